@@ -12,4 +12,11 @@ echo -e "\n\nAnalyzing the note..."
 curl -X POST http://localhost:8080/notes/1/analyze \
   -H "Content-Type: application/json" | jq '.analysis' | sed 's/\\n/\n/g'
 
+echo -e "\n\nCategorizing the note..."
+curl -X POST http://localhost:8080/notes/1/categorize \
+  -H "Content-Type: application/json" | jq '.categories'
+
+echo -e "\n\nFetching the stored categories..."
+curl http://localhost:8080/notes/1/categories | jq
+
 echo -e "\n\nDone."
\ No newline at end of file
@@ -18,6 +18,10 @@ CREATE TABLE IF NOT EXISTS notes (
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     analysis TEXT,
+    -- SHA-256 of the content, and of the content the analysis was generated
+    -- from. The analysis is stale when they differ.
+    content_hash TEXT,
+    analysis_hash TEXT,
     FOREIGN KEY (category_id) REFERENCES category_descriptions(id)
 );
 
@@ -26,6 +30,7 @@ CREATE TABLE IF NOT EXISTS llm_categories (
     id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
     note_id INTEGER NOT NULL,
     category_id INTEGER NOT NULL,
+    explanation TEXT NOT NULL DEFAULT '',
     created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (note_id) REFERENCES notes(id),
     FOREIGN KEY (category_id) REFERENCES category_descriptions(id)
@@ -50,4 +55,194 @@ INSERT OR IGNORE INTO category_descriptions (category, explanation) VALUES
 ('goal', 'Notes about personal or professional objectives, progress towards these goals, and strategies for achievement. Use this to stay focused and motivated.'),
 ('memory', 'Significant moments, experiences, or insights you want to remember. This category helps preserve important memories and the emotions associated with them.'),
 ('reflection', 'Deep thoughts, philosophical musings, or general reflections on life, society, or your place in the world. This category encourages deeper self-awareness and mindfulness.'),
-('unspecified', 'Entries that don''t clearly fit into other categories or span multiple areas. This category ensures all your thoughts have a place, even if they''re not easily categorized.');
\ No newline at end of file
+('unspecified', 'Entries that don''t clearly fit into other categories or span multiple areas. This category ensures all your thoughts have a place, even if they''re not easily categorized.');
+
+-- Create the llm_runs table if it doesn't exist
+CREATE TABLE IF NOT EXISTS llm_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    note_id INTEGER,
+    kind TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_version TEXT,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    total_duration_ms INTEGER NOT NULL DEFAULT 0,
+    load_duration_ms INTEGER NOT NULL DEFAULT 0,
+    prompt_eval_duration_ms INTEGER NOT NULL DEFAULT 0,
+    eval_duration_ms INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL,
+    error TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id),
+    CHECK (status IN ('success', 'error', 'cancelled'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_llm_runs_note_id ON llm_runs(note_id);
+CREATE INDEX IF NOT EXISTS idx_llm_runs_created_at ON llm_runs(created_at);
+
+-- Create the jobs table if it doesn't exist
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    kind TEXT NOT NULL,
+    note_id INTEGER NOT NULL,
+    params TEXT NOT NULL DEFAULT '{}',
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    result TEXT,
+    error TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    started_at DATETIME,
+    finished_at DATETIME
+);
+
+CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status);
+
+-- Create the note_embeddings table if it doesn't exist
+CREATE TABLE IF NOT EXISTS note_embeddings (
+    note_id INTEGER PRIMARY KEY NOT NULL,
+    model TEXT NOT NULL,
+    dimensions INTEGER NOT NULL,
+    embedding BLOB NOT NULL,
+    content_hash TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+-- Create the digests table if it doesn't exist
+CREATE TABLE IF NOT EXISTS digests (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    period TEXT NOT NULL,
+    period_start DATETIME NOT NULL,
+    period_end DATETIME NOT NULL,
+    content TEXT NOT NULL,
+    note_count INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    prompt_version TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (period, period_start)
+);
+
+-- Create the note_moods table if it doesn't exist
+CREATE TABLE IF NOT EXISTS note_moods (
+    note_id INTEGER PRIMARY KEY NOT NULL,
+    valence REAL NOT NULL,
+    arousal REAL NOT NULL,
+    emotions TEXT NOT NULL DEFAULT '[]',
+    source TEXT NOT NULL,
+    model TEXT,
+    prompt_version TEXT,
+    content_hash TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+-- Create the entities table if it doesn't exist
+CREATE TABLE IF NOT EXISTS entities (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create the entity_aliases table if it doesn't exist, the normalized names an
+-- entity is known by, e.g. "mother" for "Mom"
+CREATE TABLE IF NOT EXISTS entity_aliases (
+    kind TEXT NOT NULL,
+    alias TEXT NOT NULL,
+    entity_id INTEGER NOT NULL,
+    PRIMARY KEY (kind, alias),
+    FOREIGN KEY (entity_id) REFERENCES entities(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_entity_aliases_entity_id ON entity_aliases(entity_id);
+
+-- Create the note_entities table if it doesn't exist
+CREATE TABLE IF NOT EXISTS note_entities (
+    note_id INTEGER NOT NULL,
+    entity_id INTEGER NOT NULL,
+    mention TEXT NOT NULL,
+    PRIMARY KEY (note_id, entity_id),
+    FOREIGN KEY (note_id) REFERENCES notes(id),
+    FOREIGN KEY (entity_id) REFERENCES entities(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_note_entities_entity_id ON note_entities(entity_id);
+
+-- Create the entity_extractions table if it doesn't exist
+CREATE TABLE IF NOT EXISTS entity_extractions (
+    note_id INTEGER PRIMARY KEY NOT NULL,
+    content_hash TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_version TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+-- Create the tasks table if it doesn't exist, note_id is NULL for tasks
+-- entered by hand and for tasks whose note was deleted
+CREATE TABLE IF NOT EXISTS tasks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    note_id INTEGER,
+    title TEXT NOT NULL,
+    details TEXT,
+    status TEXT NOT NULL DEFAULT 'open',
+    due_date DATE,
+    source TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at DATETIME,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_tasks_note_id ON tasks(note_id);
+CREATE INDEX IF NOT EXISTS idx_tasks_status ON tasks(status);
+
+-- Create the task_extractions table if it doesn't exist
+CREATE TABLE IF NOT EXISTS task_extractions (
+    note_id INTEGER PRIMARY KEY NOT NULL,
+    content_hash TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_version TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+-- Create the analyses table if it doesn't exist, every analysis generated
+-- for a note. The current one is stored on the note as well.
+CREATE TABLE IF NOT EXISTS analyses (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    note_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    complete BOOLEAN NOT NULL,
+    is_current BOOLEAN NOT NULL DEFAULT 0,
+    model TEXT NOT NULL,
+    prompt_version TEXT NOT NULL,
+    prompt_hash TEXT NOT NULL,
+    content_hash TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (note_id) REFERENCES notes(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_analyses_note_id ON analyses(note_id);
+
+-- Create the prompts table if it doesn't exist, the versions of the prompt
+-- templates replacing the configured prompt of a task. The latest version
+-- of a name is the one in use.
+CREATE TABLE IF NOT EXISTS prompts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    template TEXT NOT NULL,
+    description TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (name, version)
+);
+
+-- Create the user_profile table if it doesn't exist, a single row with what
+-- the writer tells about themselves for the {user_profile} prompt variable
+CREATE TABLE IF NOT EXISTS user_profile (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    content TEXT NOT NULL,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,459 @@
+//! History of the analyses of each note. Every generated analysis is kept
+//! with the model and prompt that produced it, `notes.analysis` holds the
+//! current one. An older analysis can be pinned as the current one again and
+//! two analyses can be compared line by line.
+
+use crate::models::AppState;
+use crate::notes::{content_hash, Note, NOTE_COLUMNS};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, SqliteConnection};
+use tracing::{error, info};
+
+const ANALYSIS_COLUMNS: &str = "id, note_id, content, complete, is_current, model, \
+                                prompt_version, prompt_hash, content_hash, created_at";
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Analysis {
+    pub id: i64,
+    pub note_id: i64,
+    pub content: String,
+    /// False for analyses cut short by a disconnect or a failing backend.
+    pub complete: bool,
+    /// Whether this is the analysis stored on the note.
+    pub is_current: bool,
+    pub model: String,
+    /// Version of the prompt template, see `config::prompt_version`.
+    pub prompt_version: String,
+    /// SHA-256 of the prompt sent to the model, the template filled in.
+    pub prompt_hash: String,
+    /// SHA-256 of the note content the analysis was generated from.
+    pub content_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Where an analysis comes from.
+pub struct AnalysisSource {
+    pub model: String,
+    pub prompt_version: String,
+    pub prompt_hash: String,
+    pub content_hash: String,
+}
+
+impl AnalysisSource {
+    pub fn new(model: &str, prompt_version: &str, prompt: &str, content: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            prompt_version: prompt_version.to_string(),
+            prompt_hash: content_hash(prompt),
+            content_hash: content_hash(content),
+        }
+    }
+}
+
+/// Adds `content` to the history of note `id` as its current analysis.
+pub async fn insert(
+    conn: &mut SqliteConnection,
+    id: i64,
+    content: &str,
+    complete: bool,
+    source: &AnalysisSource,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE analyses SET is_current = 0 WHERE note_id = ?")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        "INSERT INTO analyses (
+            note_id, content, complete, is_current, model, prompt_version, prompt_hash,
+            content_hash, created_at
+         )
+         VALUES (?, ?, ?, 1, ?, ?, ?, ?, ?)",
+    )
+    .bind(id)
+    .bind(content)
+    .bind(complete)
+    .bind(&source.model)
+    .bind(&source.prompt_version)
+    .bind(&source.prompt_hash)
+    .bind(&source.content_hash)
+    .bind(Utc::now())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_analysis(
+    pool: &SqlitePool,
+    note_id: i64,
+    id: i64,
+) -> Result<Option<Analysis>, sqlx::Error> {
+    sqlx::query_as::<_, Analysis>(&format!(
+        "SELECT {} FROM analyses WHERE note_id = ? AND id = ?",
+        ANALYSIS_COLUMNS
+    ))
+    .bind(note_id)
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// The analyses of a note, newest first.
+pub async fn list_analyses(
+    Path(note_id): Path<i64>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let exists = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?)")
+        .bind(note_id)
+        .fetch_one(&*state.pool)
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "Note not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch note: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+                .into_response();
+        }
+    }
+    match sqlx::query_as::<_, Analysis>(&format!(
+        "SELECT {} FROM analyses WHERE note_id = ? ORDER BY id DESC",
+        ANALYSIS_COLUMNS
+    ))
+    .bind(note_id)
+    .fetch_all(&*state.pool)
+    .await
+    {
+        Ok(analyses) => (StatusCode::OK, Json(analyses)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch analyses of note {}: {}", note_id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch analyses: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn get_analysis(
+    Path((note_id, id)): Path<(i64, i64)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match fetch_analysis(&state.pool, note_id, id).await {
+        Ok(Some(analysis)) => (StatusCode::OK, Json(analysis)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Analysis not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch analysis {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch analysis: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Makes analysis `id` the current analysis of the note again.
+pub async fn pin_analysis(
+    Path((note_id, id)): Path<(i64, i64)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let pinned = async {
+        let mut tx = state.pool.begin().await?;
+        let analysis: Option<(String, bool, Option<String>)> = sqlx::query_as(
+            "SELECT content, complete, content_hash FROM analyses WHERE note_id = ? AND id = ?",
+        )
+        .bind(note_id)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((content, complete, content_hash)) = analysis else {
+            return Ok(None);
+        };
+        sqlx::query("UPDATE analyses SET is_current = (id = ?) WHERE note_id = ?")
+            .bind(id)
+            .bind(note_id)
+            .execute(&mut *tx)
+            .await?;
+        let note = sqlx::query_as::<_, Note>(&format!(
+            "UPDATE notes
+             SET analyzed = ?, analysis = ?, analysis_hash = ?, updated_at = ?
+             WHERE id = ?
+             RETURNING {}",
+            NOTE_COLUMNS
+        ))
+        .bind(complete)
+        .bind(content)
+        .bind(content_hash)
+        .bind(Utc::now())
+        .bind(note_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Some(note))
+    }
+    .await;
+
+    match pinned {
+        Ok(Some(note)) => {
+            info!("Pinned analysis {} of note {}", id, note_id);
+            (StatusCode::OK, Json(note)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Analysis not found").into_response(),
+        Err(e) => {
+            error!("Failed to pin analysis {} of note {}: {}", id, note_id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to pin analysis: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffParams {
+    pub from: i64,
+    /// The current analysis if not given.
+    pub to: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnalysisDiff {
+    pub from: i64,
+    pub to: i64,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+/// Line diff of `from` to `to` along their longest common subsequence.
+fn diff_lines(from: &str, to: &str) -> Vec<DiffLine> {
+    let a: Vec<&str> = from.lines().collect();
+    let b: Vec<&str> = to.lines().collect();
+    // common[i][j]: length of the longest common subsequence of a[i..] and b[j..]
+    let mut common = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let line = |op: DiffOp, text: &str| DiffLine {
+        op,
+        text: text.to_string(),
+    };
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            lines.push(line(DiffOp::Equal, a[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            lines.push(line(DiffOp::Delete, a[i]));
+            i += 1;
+        } else {
+            lines.push(line(DiffOp::Insert, b[j]));
+            j += 1;
+        }
+    }
+    lines.extend(a[i..].iter().map(|&text| line(DiffOp::Delete, text)));
+    lines.extend(b[j..].iter().map(|&text| line(DiffOp::Insert, text)));
+    lines
+}
+
+/// Compares two analyses of a note line by line.
+pub async fn diff_analyses(
+    Path(note_id): Path<i64>,
+    Query(params): Query<DiffParams>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let analyses = async {
+        let from = fetch_analysis(&state.pool, note_id, params.from).await?;
+        let to = match params.to {
+            Some(to) => fetch_analysis(&state.pool, note_id, to).await?,
+            None => {
+                sqlx::query_as::<_, Analysis>(&format!(
+                    "SELECT {} FROM analyses WHERE note_id = ? AND is_current = 1",
+                    ANALYSIS_COLUMNS
+                ))
+                .bind(note_id)
+                .fetch_optional(&*state.pool)
+                .await?
+            }
+        };
+        Ok::<_, sqlx::Error>(from.zip(to))
+    }
+    .await;
+    let (from, to) = match analyses {
+        Ok(Some(analyses)) => analyses,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Analysis not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch analyses: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch analyses: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    let lines = diff_lines(&from.content, &to.content);
+    let count = |op| lines.iter().filter(|line| line.op == op).count();
+    let diff = AnalysisDiff {
+        from: from.id,
+        to: to.id,
+        insertions: count(DiffOp::Insert),
+        deletions: count(DiffOp::Delete),
+        lines,
+    };
+    (StatusCode::OK, Json(diff)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::body::to_bytes;
+    use serde_json::Value;
+
+    fn ops(lines: &[DiffLine]) -> Vec<(DiffOp, &str)> {
+        lines
+            .iter()
+            .map(|line| (line.op, line.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_unchanged_lines() {
+        let text = "Calm week.\nSlept well.";
+        assert_eq!(
+            ops(&diff_lines(text, text)),
+            [
+                (DiffOp::Equal, "Calm week."),
+                (DiffOp::Equal, "Slept well.")
+            ]
+        );
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn diff_inserted_and_deleted_lines() {
+        let diff = diff_lines("A\nB\nC\nD", "A\nC\nE\nD\nF");
+        assert_eq!(
+            ops(&diff),
+            [
+                (DiffOp::Equal, "A"),
+                (DiffOp::Delete, "B"),
+                (DiffOp::Equal, "C"),
+                (DiffOp::Insert, "E"),
+                (DiffOp::Equal, "D"),
+                (DiffOp::Insert, "F"),
+            ]
+        );
+        assert_eq!(
+            ops(&diff_lines("", "A\nB")),
+            [(DiffOp::Insert, "A"), (DiffOp::Insert, "B")]
+        );
+        assert_eq!(ops(&diff_lines("A", "")), [(DiffOp::Delete, "A")]);
+        // A changed line is deleted, then inserted
+        assert_eq!(
+            ops(&diff_lines("Sad.", "Happy.")),
+            [(DiffOp::Delete, "Sad."), (DiffOp::Insert, "Happy.")]
+        );
+    }
+
+    async fn body(response: impl IntoResponse) -> (StatusCode, Value) {
+        let response = response.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn pin_an_older_analysis_and_back() {
+        let state = testing::state(["unused"]).await;
+        let note_id = testing::insert_note(&state.pool, "A long day.", "personal").await;
+        let mut conn = state.pool.acquire().await.unwrap();
+        for (content, complete) in [("First.", true), ("Second, cut", false)] {
+            let source = AnalysisSource::new("mock", "1", "prompt", "A long day.");
+            insert(&mut conn, note_id, content, complete, &source)
+                .await
+                .unwrap();
+        }
+        drop(conn);
+
+        let (status, analyses) =
+            body(list_analyses(Path(note_id), State(state.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let current: Vec<(i64, bool)> = analyses
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| {
+                (
+                    a["id"].as_i64().unwrap(),
+                    a["is_current"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        let (second, first) = (current[0].0, current[1].0);
+        assert_eq!(current, [(second, true), (first, false)]);
+
+        let pin = |id| pin_analysis(Path((note_id, id)), State(state.clone()));
+        let (status, note) = body(pin(first).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(note["analysis"], "First.");
+        assert_eq!(note["analyzed"], true);
+        let analysis = fetch_analysis(&state.pool, note_id, second).await.unwrap();
+        assert!(!analysis.unwrap().is_current);
+
+        // Pinning the newer one again restores the cut-off analysis
+        let (_, note) = body(pin(second).await).await;
+        assert_eq!(note["analysis"], "Second, cut");
+        assert_eq!(note["analyzed"], false);
+        let analysis = fetch_analysis(&state.pool, note_id, first).await.unwrap();
+        assert!(!analysis.unwrap().is_current);
+
+        let (status, _) = body(pin(999).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn analyses_of_a_missing_note_are_not_found() {
+        let state = testing::state(["unused"]).await;
+        let response = list_analyses(Path(999), State(state)).await.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"Note not found");
+    }
+}
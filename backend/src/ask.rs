@@ -0,0 +1,204 @@
+//! "Ask my diary": answers questions about the notes. The notes relevant to
+//! the question are retrieved by keywords and by embedding similarity and
+//! handed to the model, which answers citing them.
+
+use crate::generate;
+use crate::models::{AppState, LlmParams};
+use crate::notes::NoteWithCategory;
+use crate::prompts::{self, PromptName, Variable};
+use crate::runs::RunKind;
+use crate::search::{self, NoteFilter};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, convert::Infallible};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::{error, info, warn};
+
+static DEFAULT_LIMIT: usize = 8;
+static MAX_LIMIT: usize = 20;
+/// Notes are cut off after this many characters in the prompt.
+static MAX_NOTE_CHARS: usize = 2000;
+/// Dampens the weight of the top ranks when merging the rankings, the usual
+/// choice for reciprocal rank fusion.
+const RANK_OFFSET: f32 = 60.0;
+
+#[derive(Debug, Deserialize)]
+pub struct AskParams {
+    pub question: String,
+    /// Number of notes handed to the model.
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub filter: NoteFilter,
+    #[serde(flatten)]
+    pub llm: LlmParams,
+}
+
+/// Answers `question` as Server-Sent Events: a `notes` event with the
+/// retrieved notes, which the answer cites by id and date, followed by the
+/// events of `/generate`.
+pub async fn ask(
+    State(state): State<AppState>,
+    Json(params): Json<AskParams>,
+) -> impl IntoResponse {
+    let question = params.question.trim();
+    if question.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Question must not be empty").into_response();
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let ask_prompt = match prompts::prompt(&state, PromptName::Ask).await {
+        Ok(prompt) => prompt,
+        Err(response) => return response.into_response(),
+    };
+
+    let notes = match retrieve(&state, question, &params.filter, limit).await {
+        Ok(notes) => notes,
+        Err(response) => return response.into_response(),
+    };
+    info!(
+        "Answering question with {} notes: {:?}",
+        notes.len(),
+        notes.iter().map(|note| note.id).collect::<Vec<_>>()
+    );
+
+    let (tx, rx) = mpsc::channel::<Event>(64);
+    let _ = tx.try_send(notes_event(&notes));
+
+    let prompt = ask_prompt.render_with(&[
+        (Variable::Notes, &format_notes(&notes)),
+        (Variable::Question, question),
+    ]);
+    let request = state.ask_defaults.request(prompt, Some(params.llm));
+    tokio::spawn(generate::stream_generation(
+        state,
+        request,
+        RunKind::Ask,
+        Some(ask_prompt.version),
+        tx,
+    ));
+
+    Sse::new(ReceiverStream::new(rx).map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// The `limit` notes most relevant to `question`. Keyword and embedding
+/// matches are merged by reciprocal rank fusion, so notes found both ways
+/// come first. Without embeddings only the keywords are used.
+async fn retrieve(
+    state: &AppState,
+    question: &str,
+    filter: &NoteFilter,
+    limit: usize,
+) -> Result<Vec<NoteWithCategory>, (StatusCode, String)> {
+    // Each ranking contributes more candidates than needed, notes ranked low
+    // by one ranking can still make it by the other
+    let candidates = limit * 2;
+    let by_keywords = search::keyword_ranking(&state.pool, question, filter, candidates)
+        .await
+        .map_err(|e| {
+            error!("Failed to search notes: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to search notes: {}", e),
+            )
+        })?;
+    let by_embedding = match search::semantic_ranking(state, question, filter, candidates).await {
+        Ok(ranking) => ranking,
+        Err((_, message)) => {
+            warn!("Answering from keyword matches only: {}", message);
+            Vec::new()
+        }
+    };
+
+    let mut scores: HashMap<i64, f32> = HashMap::new();
+    for ranking in [by_keywords, by_embedding] {
+        for (rank, (id, _)) in ranking.into_iter().enumerate() {
+            *scores.entry(id).or_default() += 1.0 / (RANK_OFFSET + rank as f32 + 1.0);
+        }
+    }
+    let mut ranked: Vec<(i64, f32)> = scores.into_iter().collect();
+    ranked.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    let ids: Vec<i64> = ranked.into_iter().take(limit).map(|(id, _)| id).collect();
+
+    search::fetch_notes(&state.pool, &ids).await.map_err(|e| {
+        error!("Failed to fetch notes: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch notes: {}", e),
+        )
+    })
+}
+
+/// The notes as they appear in the prompt, oldest first so the model can
+/// follow the course of time.
+fn format_notes(notes: &[NoteWithCategory]) -> String {
+    if notes.is_empty() {
+        return "(No diary entries match the question.)".to_string();
+    }
+    let mut notes: Vec<&NoteWithCategory> = notes.iter().collect();
+    notes.sort_by_key(|note| note.created_at);
+    notes
+        .iter()
+        .map(|note| {
+            let mut content: String = note.content.chars().take(MAX_NOTE_CHARS).collect();
+            if content.len() < note.content.len() {
+                content.push_str(" [...]");
+            }
+            format!(
+                "### Note {} ({}, {})\n{}",
+                note.id,
+                note.created_at.format("%Y-%m-%d, %A"),
+                note.category,
+                content.trim()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn notes_event(notes: &[NoteWithCategory]) -> Event {
+    Event::default()
+        .event("notes")
+        .data(serde_json::to_string(notes).unwrap_or_else(|_| "[]".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn notes_mentioning_placeholders_are_kept_as_written() {
+        let state = testing::state(["unused"]).await;
+        let note = NoteWithCategory {
+            id: 1,
+            content: "Wrote a prompt with {question} and {notes} in it.".to_string(),
+            analyzed: false,
+            category: "work".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            analysis: None,
+            stale: false,
+        };
+        let prompt = prompts::prompt(&state, PromptName::Ask)
+            .await
+            .unwrap()
+            .render_with(&[
+                (Variable::Notes, &format_notes(&[note])),
+                (Variable::Question, "What did I write?"),
+            ]);
+        assert!(prompt.contains("Wrote a prompt with {question} and {notes} in it."));
+        assert!(prompt.contains("## Question:\nWhat did I write?\n"));
+        assert_eq!(prompt.matches("What did I write?").count(), 1);
+    }
+}
@@ -0,0 +1,472 @@
+//! Analysis of notes too long for the context window of the model. Ollama
+//! silently cuts prompts exceeding `num_ctx`, so the token count of the
+//! prompt is estimated before sending. Notes that do not fit are split into
+//! parts, each part is analyzed on its own and the analyses of the parts are
+//! combined into one in a final synthesis pass.
+
+use crate::config::TaskDefaults;
+use crate::llm::GenerateRequest;
+use crate::models::{AppState, LlmParams};
+use crate::prompts::{self, PromptName, Variable};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use axum::{http::StatusCode, response::sse::Event};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+/// Tokens kept free in the context window for the answer of the model.
+const RESPONSE_TOKENS: usize = 1024;
+/// Smallest budget for the note in a prompt, however small the context
+/// window is.
+const MIN_PART_TOKENS: usize = 256;
+
+/// Rough token count of `text`. The exact count depends on the tokenizer of
+/// the model, English text averages about four characters or three quarters
+/// of a word per token, the larger of both is used to be on the safe side.
+pub fn estimate_tokens(text: &str) -> usize {
+    let chars = text.chars().count();
+    let words = text.split_whitespace().count();
+    chars.div_ceil(4).max((words * 4).div_ceil(3))
+}
+
+/// Context window of a model call with the task defaults `defaults`
+/// overridden by `params`.
+pub fn context_window(
+    state: &AppState,
+    defaults: &TaskDefaults,
+    params: &Option<LlmParams>,
+) -> usize {
+    defaults
+        .request(String::new(), params.clone())
+        .options
+        .num_ctx
+        .map_or(state.context_window, |num_ctx| num_ctx as usize)
+}
+
+/// Tokens left for the content filled into `template` in a context window of
+/// `context_window` tokens, keeping room for the answer.
+pub fn prompt_budget(context_window: usize, template: &str) -> usize {
+    context_window
+        .saturating_sub(estimate_tokens(template) + RESPONSE_TOKENS)
+        .max(MIN_PART_TOKENS)
+}
+
+/// Cuts `text` off at about `max_tokens`.
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> String {
+    if estimate_tokens(text) <= max_tokens {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut tokens = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        tokens += estimate_tokens(word).max(1);
+        if tokens > max_tokens {
+            break;
+        }
+        truncated.push_str(word);
+    }
+    format!("{} [...]", truncated.trim_end())
+}
+
+/// The final model call of an analysis, over the note itself or over the
+/// analyses of its parts.
+pub struct AnalysisPlan {
+    pub request: GenerateRequest,
+    pub prompt_version: String,
+}
+
+/// Sent while the parts of a long note are analyzed.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "stage", rename_all = "lowercase")]
+pub enum Progress {
+    /// Analyzing part `part` of `parts`, 1 is the first.
+    Part { part: usize, parts: usize },
+    /// Combining the analyses of `analyses` parts because they do not fit
+    /// into a single synthesis prompt.
+    Combine { analyses: usize },
+}
+
+impl Progress {
+    pub fn event(&self) -> Event {
+        Event::default()
+            .event("progress")
+            .data(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Analysis of the parts `first` to `last` of a note.
+struct PartAnalysis {
+    first: usize,
+    last: usize,
+    text: String,
+}
+
+impl PartAnalysis {
+    fn format(&self) -> String {
+        if self.first == self.last {
+            format!("### Part {}\n{}", self.first, self.text.trim())
+        } else {
+            format!(
+                "### Parts {} to {}\n{}",
+                self.first,
+                self.last,
+                self.text.trim()
+            )
+        }
+    }
+}
+
+fn join_analyses(analyses: &[PartAnalysis]) -> String {
+    analyses
+        .iter()
+        .map(PartAnalysis::format)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Prepares the analysis of note `id`. A note fitting into the context
+/// window is analyzed with the analysis prompt as is. Longer notes
+/// are analyzed part by part right away, reporting to `progress`, and the
+/// returned request combines the analyses of the parts.
+pub async fn plan_analysis(
+    state: &AppState,
+    id: i64,
+    content: &str,
+    params: Option<LlmParams>,
+    priority: Priority,
+    progress: &(dyn Fn(Progress) + Sync),
+) -> Result<AnalysisPlan, (StatusCode, String)> {
+    let context_window = context_window(state, &state.analysis_defaults, &params);
+    let budget = |template: &str| prompt_budget(context_window, template);
+
+    let prompt = prompts::note_prompt(state, PromptName::Analysis, id).await?;
+    let note_tokens = estimate_tokens(content);
+    if note_tokens <= budget(&prompt.render("")) {
+        return Ok(AnalysisPlan {
+            request: state
+                .analysis_defaults
+                .request(prompt.render(content), params),
+            prompt_version: prompt.version,
+        });
+    }
+
+    // Both prompts are prepared before the first part is sent, so a template
+    // that cannot be filled in fails right away
+    let chunk_prompt = prompts::note_prompt(state, PromptName::ChunkAnalysis, id).await?;
+    let synthesis_prompt = prompts::note_prompt(state, PromptName::AnalysisSynthesis, id).await?;
+    let parts = split_into_parts(content, budget(&chunk_prompt.render("")));
+    info!(
+        "Note {} has about {} tokens, more than fit into {}, analyzing it in {} parts",
+        id,
+        note_tokens,
+        context_window,
+        parts.len()
+    );
+    let mut analyses = Vec::with_capacity(parts.len());
+    for (index, part) in parts.iter().enumerate() {
+        progress(Progress::Part {
+            part: index + 1,
+            parts: parts.len(),
+        });
+        let prompt = chunk_prompt.render_with(&[
+            (Variable::Part, &(index + 1).to_string()),
+            (Variable::Parts, &parts.len().to_string()),
+            (Variable::NoteContent, part),
+        ]);
+        let text =
+            generate_part(state, id, prompt, &chunk_prompt.version, &params, priority).await?;
+        analyses.push(PartAnalysis {
+            first: index + 1,
+            last: index + 1,
+            text,
+        });
+    }
+
+    // The analyses of many parts may not fit into one prompt either, they are
+    // combined in groups first
+    let synthesis_budget = budget(&synthesis_prompt.render_with(&[]));
+    while analyses.len() > 1 && estimate_tokens(&join_analyses(&analyses)) > synthesis_budget {
+        let groups = group_analyses(analyses, synthesis_budget);
+        if groups.iter().all(|group| group.len() == 1) {
+            warn!(
+                "Analyses of the parts of note {} do not fit into the context window",
+                id
+            );
+            analyses = groups.into_iter().flatten().collect();
+            break;
+        }
+
+        progress(Progress::Combine {
+            analyses: groups.iter().map(Vec::len).sum(),
+        });
+        analyses = Vec::with_capacity(groups.len());
+        for mut group in groups {
+            if group.len() == 1 {
+                analyses.extend(group.pop());
+                continue;
+            }
+            let prompt =
+                synthesis_prompt.render_with(&[(Variable::PartAnalyses, &join_analyses(&group))]);
+            let text = generate_part(
+                state,
+                id,
+                prompt,
+                &synthesis_prompt.version,
+                &params,
+                priority,
+            )
+            .await?;
+            analyses.push(PartAnalysis {
+                first: group[0].first,
+                last: group[group.len() - 1].last,
+                text,
+            });
+        }
+    }
+
+    let prompt =
+        synthesis_prompt.render_with(&[(Variable::PartAnalyses, &join_analyses(&analyses))]);
+    Ok(AnalysisPlan {
+        request: state.analysis_defaults.request(prompt, params),
+        prompt_version: synthesis_prompt.version,
+    })
+}
+
+/// Runs one intermediate model call of a chunked analysis.
+async fn generate_part(
+    state: &AppState,
+    id: i64,
+    prompt: String,
+    prompt_version: &str,
+    params: &Option<LlmParams>,
+    priority: Priority,
+) -> Result<String, (StatusCode, String)> {
+    let request = state.analysis_defaults.request(prompt, params.clone());
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let _ticket = state.scheduler.acquire(priority).await;
+    match state.llm.generate(request).await {
+        Ok(generation) => {
+            let run = NewRun::success(RunKind::Analyze, Some(id), &generation);
+            record_run(&state.pool, run.prompt_version(prompt_version)).await;
+            Ok(generation.text)
+        }
+        Err(e) => {
+            error!("Failed to analyze part of note {}: {}", id, e);
+            let run = NewRun::failure(RunKind::Analyze, Some(id), &model, &e);
+            record_run(&state.pool, run.prompt_version(prompt_version)).await;
+            Err((
+                e.status_code(),
+                format!("Failed to generate analysis: {}", e),
+            ))
+        }
+    }
+}
+
+/// Splits `text` into parts of at most `max_tokens`, at paragraph breaks
+/// where possible, otherwise at the end of sentences or between words. The
+/// parts are about the same size, so the last one is not a short leftover.
+fn split_into_parts(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut remaining = estimate_tokens(text);
+    let mut parts_left = remaining.div_ceil(max_tokens).max(1);
+    let mut target = remaining.div_ceil(parts_left);
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+    for piece in pieces(text, max_tokens) {
+        let tokens = estimate_tokens(piece);
+        // Close the part if the piece does not fit, or if taking it would
+        // overshoot the target by more than leaving it out falls short
+        let overshoot = (current_tokens + tokens).saturating_sub(target);
+        let shortfall = target.saturating_sub(current_tokens);
+        if current_tokens > 0 && (current_tokens + tokens > max_tokens || overshoot > shortfall) {
+            parts.push(std::mem::take(&mut current));
+            remaining = remaining.saturating_sub(current_tokens);
+            parts_left = parts_left.saturating_sub(1).max(1);
+            target = remaining.div_ceil(parts_left);
+            current_tokens = 0;
+        }
+        current.push_str(piece);
+        current_tokens += tokens;
+    }
+    parts.push(current);
+
+    parts
+        .into_iter()
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+/// The paragraphs of `text`, paragraphs longer than `max_tokens` broken up
+/// into sentences and sentences longer than that into words. Concatenated
+/// they are `text` again.
+fn pieces(text: &str, max_tokens: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    for paragraph in text.split_inclusive("\n\n") {
+        if estimate_tokens(paragraph) <= max_tokens {
+            pieces.push(paragraph);
+            continue;
+        }
+        for sentence in paragraph.split_inclusive(['.', '!', '?']) {
+            if estimate_tokens(sentence) <= max_tokens {
+                pieces.push(sentence);
+            } else {
+                pieces.extend(sentence.split_inclusive(char::is_whitespace));
+            }
+        }
+    }
+    pieces
+}
+
+/// Groups consecutive analyses so that every group fits into `max_tokens`.
+fn group_analyses(analyses: Vec<PartAnalysis>, max_tokens: usize) -> Vec<Vec<PartAnalysis>> {
+    let mut groups: Vec<Vec<PartAnalysis>> = Vec::new();
+    let mut group_tokens = 0;
+    for analysis in analyses {
+        let tokens = estimate_tokens(&analysis.format());
+        match groups.last_mut() {
+            Some(group) if group_tokens + tokens <= max_tokens => {
+                group.push(analysis);
+                group_tokens += tokens;
+            }
+            _ => {
+                groups.push(vec![analysis]);
+                group_tokens = tokens;
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, testing};
+
+    fn words(text: &str) -> Vec<&str> {
+        text.split_whitespace().collect()
+    }
+
+    fn analysis(part: usize, text: &str) -> PartAnalysis {
+        PartAnalysis {
+            first: part,
+            last: part,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn oversized_paragraph_is_split_into_sentences() {
+        let sentence = "I went for a long walk along the river today. ";
+        let text = format!("Short intro.\n\n{}\n\nShort outro.", sentence.repeat(10));
+        let max_tokens = estimate_tokens(sentence) * 3;
+
+        let pieces = pieces(&text, max_tokens);
+        assert_eq!(pieces.concat(), text);
+        assert_eq!(pieces[0], "Short intro.\n\n");
+        assert!(pieces.len() > 10);
+        assert!(pieces
+            .iter()
+            .all(|piece| estimate_tokens(piece) <= max_tokens));
+    }
+
+    #[test]
+    fn oversized_sentence_is_split_into_words() {
+        let text = "and then ".repeat(100);
+        let pieces = pieces(&text, 20);
+        assert_eq!(pieces.concat(), text);
+        assert_eq!(pieces[0], "and ");
+        assert_eq!(pieces.len(), 200);
+    }
+
+    #[test]
+    fn parts_are_of_similar_size() {
+        let paragraph = "Today was a day like any other, with work and a walk. ";
+        let text = (0..10)
+            .map(|_| paragraph.repeat(3))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let paragraph_tokens = estimate_tokens(&paragraph.repeat(3));
+        let max_tokens = paragraph_tokens * 4;
+
+        let parts = split_into_parts(&text, max_tokens);
+        assert_eq!(parts.len(), 3);
+        let sizes: Vec<usize> = parts.iter().map(|part| estimate_tokens(part)).collect();
+        assert!(sizes.iter().all(|&size| size <= max_tokens), "{:?}", sizes);
+        let (min, max) = (sizes.iter().min().unwrap(), sizes.iter().max().unwrap());
+        assert!(max - min <= paragraph_tokens + 1, "{:?}", sizes);
+    }
+
+    #[test]
+    fn parts_cover_the_text() {
+        let text = "First paragraph. It has two sentences.\n\n\
+                    A second, much longer paragraph that goes on and on about the day. "
+            .repeat(20);
+        for max_tokens in [16, 50, 200, 10_000] {
+            let parts = split_into_parts(&text, max_tokens);
+            assert!(parts.iter().all(|part| !part.is_empty()));
+            assert_eq!(words(&parts.join(" ")), words(&text), "{}", max_tokens);
+        }
+    }
+
+    #[test]
+    fn short_text_is_one_part() {
+        assert_eq!(split_into_parts("A short note.", 100), ["A short note."]);
+    }
+
+    #[test]
+    fn analyses_are_grouped_in_order() {
+        let text = "word ".repeat(20);
+        let analyses: Vec<PartAnalysis> = (1..=5).map(|part| analysis(part, &text)).collect();
+        let tokens = estimate_tokens(&analyses[0].format());
+
+        let groups = group_analyses(analyses, tokens * 2);
+        let firsts: Vec<Vec<usize>> = groups
+            .iter()
+            .map(|group| group.iter().map(|analysis| analysis.first).collect())
+            .collect();
+        assert_eq!(firsts, [vec![1, 2], vec![3, 4], vec![5]]);
+    }
+
+    #[test]
+    fn oversized_analyses_end_up_alone() {
+        let analyses = (1..=3).map(|part| analysis(part, &"word ".repeat(100)));
+        let groups = group_analyses(analyses.collect(), 10);
+        assert_eq!(groups.len(), 3);
+        assert!(groups.iter().all(|group| group.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn synthesis_stops_when_analyses_cannot_be_combined() {
+        // Every part analysis is too long to be grouped with another one
+        let long_analysis = "insight ".repeat(2000);
+        let mut state = testing::state([long_analysis]).await;
+        state.context_window = 2048;
+        let content = "A sentence about the day. ".repeat(1500);
+        let id = testing::insert_note(&state.pool, &content, "personal").await;
+
+        let plan = plan_analysis(&state, id, &content, None, Priority::Interactive, &|_| {})
+            .await
+            .unwrap();
+        let parts = split_into_parts(
+            &content,
+            prompt_budget(
+                2048,
+                &config::CHUNK_ANALYSIS_PROMPT.replace("{note_content}", ""),
+            ),
+        );
+        assert!(parts.len() > 1);
+        for part in 1..=parts.len() {
+            assert!(plan
+                .request
+                .prompt
+                .contains(&format!("### Part {}\n", part)));
+        }
+        let runs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM llm_runs")
+            .fetch_one(&*state.pool)
+            .await
+            .unwrap();
+        assert_eq!(runs as usize, parts.len());
+    }
+}
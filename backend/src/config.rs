@@ -1,8 +1,21 @@
-use std::env;
+use crate::digests::DigestPeriod;
+use crate::llm::{GenerateRequest, GenerationOptions};
+use crate::models::LlmParams;
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::{env, str::FromStr, time::Duration};
 
 static DEFAULT_MODEL: &str = "llama3.2:3b";
+static EMBEDDING_MODEL: &str = "nomic-embed-text";
 static OLLAMA_URL: &str = "http://localhost:11434";
+static OPENAI_URL: &str = "http://localhost:8000/v1";
 static LISTEN_ADDR: &str = "127.0.0.1:8080";
+static LLM_CONCURRENCY: usize = 1;
+static JOB_WORKERS: usize = 1;
+/// Context window of the model server when a request does not set `num_ctx`,
+/// the default of Ollama.
+static CONTEXT_WINDOW: usize = 4096;
+static LLM_BACKEND: &str = "ollama";
 pub static DETAILED_DIARY_ANALYSIS_PROMPT: &str = r#"# Detailed Diary Entry Analysis Prompt
 
 You are an AI assistant specialized in analyzing personal diary entries. Your task is to provide a detailed, insightful analysis of the given diary entry. Focus on understanding the writer's emotions, experiences, and thought processes, and offer meaningful observations.
@@ -134,24 +147,350 @@ IMPORTANT: Your output must be in valid JSON format. Do not include any text out
 Now, please categorize the following diary entry and provide your categorization in the required JSON format:
 
 {note_content}"#;
+
+pub static CHUNK_ANALYSIS_PROMPT: &str = r#"# Diary Entry Part Analysis Prompt
+
+You are an AI assistant specialized in analyzing personal diary entries. The diary entry below is too long to be read at once and has been split into {parts} parts. You are given part {part}.
+
+## Instructions:
+1. Carefully read this part of the entry.
+2. Write concise notes covering:
+   - Main events or topics discussed
+   - Emotional state of the writer and how it changes
+   - Any challenges or achievements mentioned
+   - Reflections or insights expressed by the writer
+   - People, places and recurring themes
+3. Stick to what this part says. The notes on all parts will be combined into a single analysis later, so do not write an introduction or a conclusion.
+
+## Part {part} of {parts}:
+
+{note_content}
+
+Notes:"#;
+
+pub static ANALYSIS_SYNTHESIS_PROMPT: &str = r#"# Diary Entry Synthesis Prompt
+
+You are an AI assistant specialized in analyzing personal diary entries. A long diary entry was split into parts, and each part has been analyzed separately. Combine the analyses of the parts below into one detailed, insightful analysis of the whole entry.
+
+## Instructions:
+1. Read the analyses of all parts in order.
+2. Write a single analysis of the entry that includes:
+   - Main events or topics discussed
+   - Emotional state of the writer and how it develops over the entry
+   - Any challenges or achievements mentioned
+   - Reflections or insights expressed by the writer
+   - Patterns or recurring themes across the parts
+3. Do not refer to the parts, write as if you had read the entry in one go.
+4. If relevant, suggest potential areas for personal growth or reflection for the writer.
+
+## Output Format:
+Provide your analysis in a clear, well-structured text format. Use paragraphs to separate different aspects of your analysis.
+
+## Analyses of the parts:
+
+{part_analyses}
+
+Analysis:"#;
+
+pub static DIGEST_PROMPT: &str = r#"# Diary Reflection Digest Prompt
+
+You are an AI assistant helping the writer reflect on their diary. Below are all diary entries of the {period} from {start} to {end}, each with its date, category and, where available, an earlier analysis of the entry.
+
+## Instructions:
+1. Read all entries and their analyses in order.
+2. Write a reflection digest of the {period} that includes:
+   - The main events and topics of the {period}
+   - How the writer's mood and energy developed over the {period}
+   - Challenges faced and achievements reached
+   - Recurring themes, people or patterns across the entries
+   - Insights the writer expressed
+3. Close with two or three gentle suggestions or questions for the writer to reflect on in the next {period}.
+4. Address the writer directly ("you") and keep a warm, supportive tone. Do not make up anything the entries do not say.
+
+## Output Format:
+Provide the digest in a clear, well-structured text format. Use paragraphs to separate different aspects.
+
+## Diary Entries:
+
+{notes}
+
+Digest:"#;
+
+pub static MOOD_PROMPT: &str = r#"# Diary Entry Mood Scoring Prompt
+
+You are an AI assistant specialized in recognizing emotions in personal diary entries. Score the mood the writer expresses in the diary entry below.
+
+## Instructions:
+1. Carefully read the entire diary entry.
+2. Rate the valence of the writer's mood from -1 (very unpleasant, e.g. despair, grief) through 0 (neutral) to 1 (very pleasant, e.g. joy, contentment).
+3. Rate the arousal from -1 (very calm, low energy, e.g. relaxed, tired) through 0 to 1 (very activated, high energy, e.g. excited, panicked).
+4. List up to five emotions the writer expresses, each with an intensity from 0 (barely present) to 1 (dominant). Use only these emotions: joy, gratitude, calm, hope, love, pride, sadness, loneliness, anger, frustration, fear, anxiety, shame, surprise.
+5. Score the mood of the writer, not of other people mentioned in the entry.
+
+## Output Format:
+Reply with a JSON object only, for example:
+
+```json
+{
+  "valence": -0.4,
+  "arousal": 0.6,
+  "emotions": [
+    {"name": "anxiety", "intensity": 0.8},
+    {"name": "hope", "intensity": 0.3}
+  ]
+}
+```
+
+Now, please score the following diary entry:
+
+{note_content}"#;
+
+pub static ENTITY_PROMPT: &str = r#"# Diary Entry Entity Extraction Prompt
+
+You are an AI assistant extracting the people, places and organizations mentioned in personal diary entries.
+
+## Instructions:
+1. Carefully read the entire diary entry.
+2. List every person, place and organization the entry mentions, each once, with its kind: person, place or organization.
+3. Use the name as the writer uses it. For family members and others the writer refers to by their relation, use the relation, e.g. "Mom", "my brother" becomes "Brother".
+4. Under aliases, list the other ways the entry refers to the same entity, e.g. "Anna" and "Anna Smith", or "Mom" and "mother". Leave the list empty if there are none.
+5. Do not list the writer themselves, pronouns, or generic words like "someone", "work" or "home".
+
+## Output Format:
+Reply with a JSON object only, for example:
+
+```json
+{
+  "entities": [
+    {"name": "Mom", "kind": "person", "aliases": ["mother"]},
+    {"name": "Anna", "kind": "person", "aliases": []},
+    {"name": "Berlin", "kind": "place", "aliases": []},
+    {"name": "Acme Corp", "kind": "organization", "aliases": ["Acme"]}
+  ]
+}
+```
+
+Now, please extract the entities of the following diary entry:
+
+{note_content}"#;
+
+pub static TASK_PROMPT: &str = r#"# Diary Entry Action Item Extraction Prompt
+
+You are an AI assistant helping the writer of a personal diary follow through on their intentions. Extract the action items from the diary entry below.
+
+## Instructions:
+1. Carefully read the entire diary entry.
+2. List the concrete things the writer intends, plans or needs to do, e.g. "I should push for that promotion" or "need to call the dentist". Skip things already done, wishes without an action and tasks of other people.
+3. Phrase each action item as a short imperative, e.g. "Ask for the promotion", and add the details the entry gives under details, or leave them out.
+4. If the entry names a deadline, give it as due_date in the form YYYY-MM-DD. The entry was written on {note_date}, resolve relative dates like "tomorrow" or "next Friday" from that day. Leave due_date out otherwise.
+5. If the entry contains no action items, return an empty list.
+
+## Output Format:
+Reply with a JSON object only, for example:
+
+```json
+{
+  "tasks": [
+    {"title": "Ask the boss about the promotion", "details": "After the successful presentation", "due_date": "2024-03-08"},
+    {"title": "Call the dentist"}
+  ]
+}
+```
+
+Now, please extract the action items of the following diary entry:
+
+{note_content}"#;
+
+pub static ASK_DIARY_PROMPT: &str = r#"# Diary Question Answering Prompt
+
+You are an AI assistant answering questions about the writer's own diary. Below are the diary entries most relevant to the question, each introduced by its note id, date and category.
+
+## Instructions:
+1. Answer the question using only the diary entries below. Do not make up events, feelings or dates.
+2. Cite every entry you rely on by its note id and date, in the form [note 12, 2024-03-05].
+3. When the question asks about time ("when did I last..."), compare the dates and name the relevant entries in order.
+4. If the entries do not contain the answer, say so plainly instead of guessing.
+5. Address the writer directly ("you") and keep a warm, supportive tone.
+
+## Diary Entries:
+
+{notes}
+
+## Question:
+{question}
+
+Answer:"#;
 pub struct Config {
+    pub llm_backend: String,
+    pub mock_llm_script: Option<String>,
     pub ollama_url: String,
+    pub openai_url: String,
+    pub openai_api_key: Option<String>,
     pub listen_addr: String,
     pub default_model: String,
+    /// Model computing the note embeddings.
+    pub embedding_model: String,
     pub detailed_diary_analysis_prompt: String,
     pub diary_categorization_prompt: String,
+    pub ask_diary_prompt: String,
+    pub chunk_analysis_prompt: String,
+    pub analysis_synthesis_prompt: String,
+    pub digest_prompt: String,
+    pub mood_prompt: String,
+    pub entity_prompt: String,
+    pub task_prompt: String,
+    pub analysis_defaults: TaskDefaults,
+    pub categorization_defaults: TaskDefaults,
+    pub ask_defaults: TaskDefaults,
+    pub digest_defaults: TaskDefaults,
+    pub mood_defaults: TaskDefaults,
+    pub entity_defaults: TaskDefaults,
+    pub task_defaults: TaskDefaults,
+    /// Digests generated automatically once their period is over.
+    pub digest_schedule: Vec<DigestPeriod>,
+    pub resilience: ResilienceSettings,
+    /// Number of model calls running at the same time, the rest is queued.
+    pub llm_concurrency: usize,
+    /// Number of background jobs processed at the same time.
+    pub job_workers: usize,
+    /// Context window in tokens assumed when a request does not set
+    /// `num_ctx`, notes not fitting into it are analyzed in parts.
+    pub context_window: usize,
+}
+
+/// Model and options used for a task unless a request overrides them.
+#[derive(Debug, Clone, Default)]
+pub struct TaskDefaults {
+    pub model: Option<String>,
+    pub options: GenerationOptions,
+}
+
+impl TaskDefaults {
+    /// Reads `<PREFIX>_MODEL`, `<PREFIX>_TEMPERATURE`, `<PREFIX>_TOP_P`,
+    /// `<PREFIX>_NUM_CTX`, `<PREFIX>_SEED`, `<PREFIX>_STOP` (comma separated)
+    /// and `<PREFIX>_KEEP_ALIVE`.
+    fn from_env(prefix: &str) -> anyhow::Result<Self> {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name)).ok();
+        Ok(Self {
+            model: var("MODEL"),
+            options: GenerationOptions {
+                temperature: parse_var(prefix, "TEMPERATURE")?,
+                top_p: parse_var(prefix, "TOP_P")?,
+                num_ctx: parse_var(prefix, "NUM_CTX")?,
+                seed: parse_var(prefix, "SEED")?,
+                stop: var("STOP").map(|stop| stop.split(',').map(str::to_string).collect()),
+                keep_alive: var("KEEP_ALIVE"),
+            },
+        })
+    }
+
+    /// Builds the request for `prompt`, the values in `params` taking
+    /// precedence over the defaults.
+    pub fn request(&self, prompt: String, params: Option<LlmParams>) -> GenerateRequest {
+        let params = params.unwrap_or_default();
+        GenerateRequest::new(prompt)
+            .with_model(params.model.or_else(|| self.model.clone()))
+            .with_options(params.options.or(&self.options))
+    }
+}
+
+/// Retries and circuit breaker around the LLM backend.
+#[derive(Debug, Clone)]
+pub struct ResilienceSettings {
+    /// Retries after a connection error or a 5xx response.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub retry_delay: Duration,
+    /// Consecutive failures after which calls fail fast.
+    pub breaker_threshold: u32,
+    /// How long calls fail fast before the backend is tried again.
+    pub breaker_cooldown: Duration,
+}
+
+impl ResilienceSettings {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_retries: parse_var("LLM", "MAX_RETRIES")?.unwrap_or(3),
+            retry_delay: Duration::from_millis(parse_var("LLM", "RETRY_DELAY_MS")?.unwrap_or(500)),
+            breaker_threshold: parse_var("LLM", "BREAKER_THRESHOLD")?.unwrap_or(5),
+            breaker_cooldown: Duration::from_secs(
+                parse_var("LLM", "BREAKER_COOLDOWN_SECS")?.unwrap_or(30),
+            ),
+        })
+    }
+}
+
+fn parse_var<T>(prefix: &str, name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let name = format!("{}_{}", prefix, name);
+    env::var(&name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("Invalid value for {}", name))
+        })
+        .transpose()
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, env::VarError> {
+    pub fn from_env() -> anyhow::Result<Self> {
         Ok(Self {
+            llm_backend: env::var("LLM_BACKEND").unwrap_or_else(|_| LLM_BACKEND.to_string()),
+            mock_llm_script: env::var("MOCK_LLM_SCRIPT").ok(),
             ollama_url: env::var("OLLAMA_URL").unwrap_or_else(|_| OLLAMA_URL.to_string()),
+            openai_url: env::var("OPENAI_URL").unwrap_or_else(|_| OPENAI_URL.to_string()),
+            openai_api_key: env::var("OPENAI_API_KEY").ok(),
             listen_addr: env::var("LISTEN_ADDR").unwrap_or_else(|_| LISTEN_ADDR.to_string()),
             default_model: env::var("DEFAULT_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string()),
+            embedding_model: env::var("EMBEDDING_MODEL")
+                .unwrap_or_else(|_| EMBEDDING_MODEL.to_string()),
             detailed_diary_analysis_prompt: env::var("DETAILED_DIARY_ANALYSIS_PROMPT")
                 .unwrap_or_else(|_| DETAILED_DIARY_ANALYSIS_PROMPT.to_string()),
             diary_categorization_prompt: env::var("DIARY_CATEGORIZATION_PROMPT")
                 .unwrap_or_else(|_| DIARY_CATEGORIZATION_PROMPT.to_string()),
+            ask_diary_prompt: env::var("ASK_DIARY_PROMPT")
+                .unwrap_or_else(|_| ASK_DIARY_PROMPT.to_string()),
+            chunk_analysis_prompt: env::var("CHUNK_ANALYSIS_PROMPT")
+                .unwrap_or_else(|_| CHUNK_ANALYSIS_PROMPT.to_string()),
+            analysis_synthesis_prompt: env::var("ANALYSIS_SYNTHESIS_PROMPT")
+                .unwrap_or_else(|_| ANALYSIS_SYNTHESIS_PROMPT.to_string()),
+            digest_prompt: env::var("DIGEST_PROMPT").unwrap_or_else(|_| DIGEST_PROMPT.to_string()),
+            mood_prompt: env::var("MOOD_PROMPT").unwrap_or_else(|_| MOOD_PROMPT.to_string()),
+            entity_prompt: env::var("ENTITY_PROMPT").unwrap_or_else(|_| ENTITY_PROMPT.to_string()),
+            task_prompt: env::var("TASK_PROMPT").unwrap_or_else(|_| TASK_PROMPT.to_string()),
+            analysis_defaults: TaskDefaults::from_env("ANALYSIS")?,
+            categorization_defaults: TaskDefaults::from_env("CATEGORIZATION")?,
+            ask_defaults: TaskDefaults::from_env("ASK")?,
+            digest_defaults: TaskDefaults::from_env("DIGEST")?,
+            mood_defaults: TaskDefaults::from_env("MOOD")?,
+            entity_defaults: TaskDefaults::from_env("ENTITY")?,
+            task_defaults: TaskDefaults::from_env("TASK")?,
+            digest_schedule: env::var("DIGEST_SCHEDULE")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|period| !period.is_empty())
+                .map(|period| {
+                    period
+                        .parse()
+                        .with_context(|| format!("Invalid value for DIGEST_SCHEDULE: {}", period))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            resilience: ResilienceSettings::from_env()?,
+            llm_concurrency: parse_var("LLM", "CONCURRENCY")?.unwrap_or(LLM_CONCURRENCY),
+            job_workers: parse_var("JOB", "WORKERS")?.unwrap_or(JOB_WORKERS),
+            context_window: parse_var("LLM", "CONTEXT_WINDOW")?.unwrap_or(CONTEXT_WINDOW),
         })
     }
 }
+
+/// Short hash identifying a prompt template, so stored results can be traced
+/// back to the exact prompt text that produced them.
+pub fn prompt_version(template: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(template.as_bytes()));
+    digest[..12].to_string()
+}
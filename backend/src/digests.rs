@@ -0,0 +1,548 @@
+//! Weekly and monthly reflection digests, summarizing the notes of a period
+//! and their analyses. Digests are generated on request and, if configured,
+//! automatically once their period is over.
+
+use crate::chunking;
+use crate::models::{AppState, LlmParams};
+use crate::notes::{NoteWithCategory, STALE_COLUMN};
+use crate::prompts::{self, PromptName, Variable};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, QueryBuilder, Sqlite};
+use std::{str::FromStr, time::Duration};
+use tracing::{error, info};
+
+const DIGEST_COLUMNS: &str = "id, period, period_start, period_end, content, note_count, \
+                              model, prompt_version, created_at, updated_at";
+
+static DEFAULT_LIMIT: i64 = 20;
+static MAX_LIMIT: i64 = 200;
+/// How often the schedule checks for periods without a digest.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum DigestPeriod {
+    /// Monday to Sunday.
+    Week,
+    Month,
+}
+
+impl FromStr for DigestPeriod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "week" | "weekly" => Ok(DigestPeriod::Week),
+            "month" | "monthly" => Ok(DigestPeriod::Month),
+            _ => anyhow::bail!("unknown digest period {}, expected week or month", s),
+        }
+    }
+}
+
+impl DigestPeriod {
+    fn name(&self) -> &'static str {
+        match self {
+            DigestPeriod::Week => "week",
+            DigestPeriod::Month => "month",
+        }
+    }
+
+    /// First day of the period containing `date`.
+    fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            DigestPeriod::Week => date - Days::new(date.weekday().num_days_from_monday().into()),
+            DigestPeriod::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    /// First day after the period starting at `start`.
+    fn end_of(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            DigestPeriod::Week => start + Days::new(7),
+            DigestPeriod::Month => start + Months::new(1),
+        }
+    }
+
+    /// First day of the last period that is over on `today`.
+    fn last_complete(&self, today: NaiveDate) -> NaiveDate {
+        self.start_of(self.start_of(today) - Days::new(1))
+    }
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN).and_utc()
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Digest {
+    pub id: i64,
+    pub period: DigestPeriod,
+    pub period_start: DateTime<Utc>,
+    /// Start of the next period, the notes covered were written before.
+    pub period_end: DateTime<Utc>,
+    pub content: String,
+    pub note_count: i64,
+    pub model: String,
+    pub prompt_version: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+async fn fetch_digest(pool: &SqlitePool, id: i64) -> Result<Option<Digest>, sqlx::Error> {
+    sqlx::query_as::<_, Digest>(&format!(
+        "SELECT {} FROM digests WHERE id = ?",
+        DIGEST_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn fetch_period_notes(
+    pool: &SqlitePool,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<NoteWithCategory>, sqlx::Error> {
+    sqlx::query_as::<_, NoteWithCategory>(&format!(
+        "SELECT n.id, n.content, n.analyzed, cd.category, n.created_at, n.updated_at, n.analysis, {}
+         FROM notes n
+         JOIN category_descriptions cd ON n.category_id = cd.id
+         WHERE n.created_at >= ? AND n.created_at < ?
+         ORDER BY n.created_at",
+        STALE_COLUMN
+    ))
+    .bind(start)
+    .bind(end)
+    .fetch_all(pool)
+    .await
+}
+
+/// The notes as they appear in the prompt. Every note gets an equal share of
+/// `budget` tokens, split between the note and its analysis.
+fn format_notes(notes: &[NoteWithCategory], budget: usize) -> String {
+    let share = budget / notes.len().max(1);
+    notes
+        .iter()
+        .map(|note| {
+            let header = format!(
+                "### Note {} ({}, {})",
+                note.id,
+                note.created_at.format("%Y-%m-%d, %A"),
+                note.category
+            );
+            match note.analysis.as_deref().map(str::trim) {
+                Some(analysis) if note.analyzed && !note.stale && !analysis.is_empty() => format!(
+                    "{}\n{}\n\nAnalysis:\n{}",
+                    header,
+                    chunking::truncate_to_tokens(note.content.trim(), share / 2),
+                    chunking::truncate_to_tokens(analysis, share / 2)
+                ),
+                _ => format!(
+                    "{}\n{}",
+                    header,
+                    chunking::truncate_to_tokens(note.content.trim(), share)
+                ),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Generates the digest of the `period` starting at `start` and stores it,
+/// replacing an earlier digest of the same period.
+pub async fn generate_digest(
+    state: &AppState,
+    period: DigestPeriod,
+    start: NaiveDate,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<Digest, (StatusCode, String)> {
+    let start = period.start_of(start);
+    let end = period.end_of(start);
+    let notes = fetch_period_notes(&state.pool, start_of_day(start), start_of_day(end))
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch notes for digest: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch notes: {}", e),
+            )
+        })?;
+    if notes.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!(
+                "No notes in the {} from {} to {}",
+                period.name(),
+                start,
+                end - Days::new(1)
+            ),
+        ));
+    }
+
+    let context_window = chunking::context_window(state, &state.digest_defaults, &params);
+    let digest_prompt = prompts::prompt(state, PromptName::Digest).await?;
+    let budget = chunking::prompt_budget(context_window, &digest_prompt.render_with(&[]));
+    let prompt = digest_prompt.render_with(&[
+        (Variable::Period, period.name()),
+        (Variable::Start, &start.to_string()),
+        (Variable::End, &(end - Days::new(1)).to_string()),
+        (Variable::Notes, &format_notes(&notes, budget)),
+    ]);
+    let prompt_version = digest_prompt.version;
+    let request = state.digest_defaults.request(prompt, params);
+    let model = state.llm.resolve_model(request.model.as_deref());
+
+    let ticket = state.scheduler.acquire(priority).await;
+    let generation = match state.llm.generate(request).await {
+        Ok(generation) => {
+            let run = NewRun::success(RunKind::Digest, None, &generation);
+            record_run(&state.pool, run.prompt_version(&prompt_version)).await;
+            generation
+        }
+        Err(e) => {
+            error!(
+                "Failed to generate {} digest from {}: {}",
+                period.name(),
+                start,
+                e
+            );
+            let run = NewRun::failure(RunKind::Digest, None, &model, &e);
+            record_run(&state.pool, run.prompt_version(&prompt_version)).await;
+            return Err((e.status_code(), format!("Failed to generate digest: {}", e)));
+        }
+    };
+    drop(ticket);
+
+    info!(
+        "Generated {} digest from {} over {} notes by {}",
+        period.name(),
+        start,
+        notes.len(),
+        generation.model
+    );
+
+    let now = Utc::now();
+    sqlx::query_as::<_, Digest>(&format!(
+        "INSERT INTO digests (
+            period, period_start, period_end, content, note_count,
+            model, prompt_version, created_at, updated_at
+         )
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(period, period_start) DO UPDATE SET
+            period_end = excluded.period_end,
+            content = excluded.content,
+            note_count = excluded.note_count,
+            model = excluded.model,
+            prompt_version = excluded.prompt_version,
+            updated_at = excluded.updated_at
+         RETURNING {}",
+        DIGEST_COLUMNS
+    ))
+    .bind(period)
+    .bind(start_of_day(start))
+    .bind(start_of_day(end))
+    .bind(generation.text.trim())
+    .bind(notes.len() as i64)
+    .bind(&generation.model)
+    .bind(&prompt_version)
+    .bind(now)
+    .bind(now)
+    .fetch_one(&*state.pool)
+    .await
+    .map_err(|e| {
+        error!("Failed to store digest: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store digest: {}", e),
+        )
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DigestFilter {
+    pub period: Option<DigestPeriod>,
+    pub limit: Option<i64>,
+}
+
+/// The digests, newest period first.
+pub async fn list_digests(
+    State(state): State<AppState>,
+    Query(filter): Query<DigestFilter>,
+) -> impl IntoResponse {
+    let mut query = QueryBuilder::<Sqlite>::new(format!(
+        "SELECT {} FROM digests WHERE 1 = 1",
+        DIGEST_COLUMNS
+    ));
+    if let Some(period) = filter.period {
+        query.push(" AND period = ").push_bind(period);
+    }
+    query
+        .push(" ORDER BY period_start DESC, period DESC LIMIT ")
+        .push_bind(filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT));
+
+    match query
+        .build_query_as::<Digest>()
+        .fetch_all(&*state.pool)
+        .await
+    {
+        Ok(digests) => (StatusCode::OK, Json(digests)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch digests: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch digests: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn get_digest(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    match fetch_digest(&state.pool, id).await {
+        Ok(Some(digest)) => (StatusCode::OK, Json(digest)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Digest not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch digest {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch digest: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateDigest {
+    pub period: DigestPeriod,
+    /// Any day of the period, the last complete period if not given.
+    pub date: Option<NaiveDate>,
+    #[serde(flatten)]
+    pub llm: LlmParams,
+}
+
+/// Generates the digest of a period, replacing the existing one.
+pub async fn create_digest(
+    State(state): State<AppState>,
+    Json(params): Json<CreateDigest>,
+) -> impl IntoResponse {
+    let date = params
+        .date
+        .unwrap_or_else(|| params.period.last_complete(Utc::now().date_naive()));
+    match generate_digest(
+        &state,
+        params.period,
+        date,
+        Some(params.llm),
+        Priority::Interactive,
+    )
+    .await
+    {
+        Ok(digest) => (StatusCode::OK, Json(digest)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+/// Generates digest `id` again, e.g. after notes of its period were added or
+/// edited.
+pub async fn regenerate_digest(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let digest = match fetch_digest(&state.pool, id).await {
+        Ok(Some(digest)) => digest,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Digest not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch digest {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch digest: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    match generate_digest(
+        &state,
+        digest.period,
+        digest.period_start.date_naive(),
+        params.map(|Json(params)| params),
+        Priority::Interactive,
+    )
+    .await
+    {
+        Ok(digest) => (StatusCode::OK, Json(digest)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+/// Generates the digest of every period in `periods` once it is over,
+/// checking every hour. Periods without notes are skipped.
+pub fn start_schedule(state: AppState, periods: Vec<DigestPeriod>) {
+    if periods.is_empty() {
+        return;
+    }
+    info!("Generating {:?} digests on a schedule", periods);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        loop {
+            interval.tick().await;
+            for period in &periods {
+                generate_due_digest(&state, *period).await;
+            }
+        }
+    });
+}
+
+async fn generate_due_digest(state: &AppState, period: DigestPeriod) {
+    let start = period.last_complete(Utc::now().date_naive());
+    // A digest generated while the period was still running is replaced
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(
+            SELECT 1 FROM digests
+            WHERE period = ? AND period_start = ? AND updated_at >= period_end
+         )",
+    )
+    .bind(period)
+    .bind(start_of_day(start))
+    .fetch_one(&*state.pool)
+    .await;
+
+    match exists {
+        Ok(true) => {}
+        Ok(false) => match generate_digest(state, period, start, None, Priority::Batch).await {
+            Ok(digest) => info!("Scheduled {} digest {} generated", period.name(), digest.id),
+            Err((StatusCode::NOT_FOUND, _)) => {}
+            Err((_, message)) => error!("Scheduled {} digest failed: {}", period.name(), message),
+        },
+        Err(e) => error!("Failed to check for {} digest: {}", period.name(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn note(id: i64, content: &str, analysis: Option<&str>, stale: bool) -> NoteWithCategory {
+        NoteWithCategory {
+            id,
+            content: content.to_string(),
+            analyzed: analysis.is_some(),
+            category: "personal".to_string(),
+            created_at: start_of_day(date("2024-03-05")),
+            updated_at: start_of_day(date("2024-03-05")),
+            analysis: analysis.map(str::to_string),
+            stale,
+        }
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let week = DigestPeriod::Week;
+        assert_eq!(week.start_of(date("2024-03-04")), date("2024-03-04"));
+        assert_eq!(week.start_of(date("2024-03-07")), date("2024-03-04"));
+        assert_eq!(week.start_of(date("2024-03-10")), date("2024-03-04"));
+        assert_eq!(week.end_of(date("2024-03-04")), date("2024-03-11"));
+    }
+
+    #[test]
+    fn first_week_of_the_year_starts_in_december() {
+        let week = DigestPeriod::Week;
+        assert_eq!(week.start_of(date("2025-01-01")), date("2024-12-30"));
+        assert_eq!(week.end_of(date("2024-12-30")), date("2025-01-06"));
+        assert_eq!(week.last_complete(date("2025-01-06")), date("2024-12-30"));
+    }
+
+    #[test]
+    fn months_roll_over() {
+        let month = DigestPeriod::Month;
+        assert_eq!(month.start_of(date("2024-02-29")), date("2024-02-01"));
+        assert_eq!(month.end_of(date("2024-01-01")), date("2024-02-01"));
+        assert_eq!(month.end_of(date("2024-02-01")), date("2024-03-01"));
+        assert_eq!(month.end_of(date("2024-12-01")), date("2025-01-01"));
+    }
+
+    #[test]
+    fn last_complete_period() {
+        let (week, month) = (DigestPeriod::Week, DigestPeriod::Month);
+        // Sunday, the week is not over yet
+        assert_eq!(week.last_complete(date("2024-03-10")), date("2024-02-26"));
+        assert_eq!(week.last_complete(date("2024-03-11")), date("2024-03-04"));
+        assert_eq!(month.last_complete(date("2024-03-01")), date("2024-02-01"));
+        assert_eq!(month.last_complete(date("2024-01-15")), date("2023-12-01"));
+    }
+
+    #[test]
+    fn notes_share_the_budget() {
+        let long = "word ".repeat(200);
+        let notes = [
+            note(1, &long, None, false),
+            note(2, &long, Some(&long), false),
+            note(3, &long, Some("Outdated analysis"), true),
+        ];
+        let formatted = format_notes(&notes, 150);
+        let parts: Vec<&str> = formatted.split("### Note ").skip(1).collect();
+        assert_eq!(parts.len(), 3);
+        for part in &parts {
+            let tokens = chunking::estimate_tokens(part);
+            assert!(tokens <= 50 + 20, "{} tokens: {}", tokens, part);
+            assert!(part.contains("[...]"));
+        }
+        assert!(parts[0].starts_with("1 (2024-03-05, Tuesday, personal)\n"));
+        assert!(!parts[0].contains("Analysis:"));
+        assert!(parts[1].contains("Analysis:"));
+        assert!(!parts[2].contains("Analysis:"));
+    }
+
+    #[tokio::test]
+    async fn digests_of_running_periods_are_replaced() {
+        let state = testing::state(["First digest", "Second digest", "Third digest"]).await;
+        let start = DigestPeriod::Week.last_complete(Utc::now().date_naive());
+        let id = testing::insert_note(&state.pool, "A week of work.", "work").await;
+        sqlx::query("UPDATE notes SET created_at = ? WHERE id = ?")
+            .bind(start_of_day(start + Days::new(1)))
+            .bind(id)
+            .execute(&*state.pool)
+            .await
+            .unwrap();
+        let content = || async {
+            sqlx::query_scalar::<_, String>("SELECT content FROM digests")
+                .fetch_all(&*state.pool)
+                .await
+                .unwrap()
+        };
+
+        // Generated on the last day of the week
+        generate_digest(&state, DigestPeriod::Week, start, None, Priority::Batch)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE digests SET updated_at = ?")
+            .bind(start_of_day(start + Days::new(6)))
+            .execute(&*state.pool)
+            .await
+            .unwrap();
+        generate_due_digest(&state, DigestPeriod::Week).await;
+        assert_eq!(content().await, ["Second digest"]);
+
+        // Generated after the week, kept
+        generate_due_digest(&state, DigestPeriod::Week).await;
+        assert_eq!(content().await, ["Second digest"]);
+    }
+}
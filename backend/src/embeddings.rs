@@ -0,0 +1,220 @@
+//! Vector embeddings of the notes, the foundation of the semantic features.
+//! Every note has at most one embedding, computed with the configured
+//! embedding model from the note content.
+
+use crate::llm::LlmBackend;
+use crate::models::AppState;
+use crate::notes::content_hash;
+use crate::scheduler::Priority;
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{sqlite::SqlitePool, FromRow};
+use tracing::{error, info};
+
+/// Number of notes sent to the embedding model in one request while
+/// reindexing.
+const REINDEX_BATCH_SIZE: usize = 16;
+
+/// Vectors are stored as little endian `f32`s.
+fn encode(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+pub fn decode(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+async fn store(
+    pool: &SqlitePool,
+    note_id: i64,
+    model: &str,
+    hash: &str,
+    vector: &[f32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO note_embeddings (note_id, model, dimensions, embedding, content_hash, created_at)
+         VALUES (?, ?, ?, ?, ?, ?)
+         ON CONFLICT(note_id) DO UPDATE SET
+            model = excluded.model,
+            dimensions = excluded.dimensions,
+            embedding = excluded.embedding,
+            content_hash = excluded.content_hash,
+            created_at = excluded.created_at",
+    )
+    .bind(note_id)
+    .bind(model)
+    .bind(vector.len() as i64)
+    .bind(encode(vector))
+    .bind(hash)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Embeds the note unless its embedding is up to date, i.e. computed by
+/// `model` from the same content. Returns whether a new embedding was stored.
+pub async fn embed_note(
+    llm: &dyn LlmBackend,
+    pool: &SqlitePool,
+    model: &str,
+    note_id: i64,
+    content: &str,
+) -> anyhow::Result<bool> {
+    let hash = content_hash(content);
+    let up_to_date: bool = sqlx::query_scalar(
+        "SELECT EXISTS(
+            SELECT 1 FROM note_embeddings WHERE note_id = ? AND model = ? AND content_hash = ?
+         )",
+    )
+    .bind(note_id)
+    .bind(model)
+    .bind(&hash)
+    .fetch_one(pool)
+    .await?;
+    if up_to_date {
+        return Ok(false);
+    }
+
+    let vector = llm
+        .embed(model, vec![content.to_string()])
+        .await?
+        .pop()
+        .context("embedding model returned no vector")?;
+    store(pool, note_id, model, &hash, &vector).await?;
+    Ok(true)
+}
+
+/// Embeds a created or updated note in the background, so a missing
+/// embedding model never fails saving the note. `reindex` catches up on
+/// notes whose embedding failed.
+pub fn spawn_embed_note(state: &AppState, note_id: i64, content: String) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let _ticket = state.scheduler.acquire(Priority::Batch).await;
+        match embed_note(
+            state.llm.as_ref(),
+            &state.pool,
+            &state.embedding_model,
+            note_id,
+            &content,
+        )
+        .await
+        {
+            Ok(true) => {
+                info!("Embedded note {} with {}", note_id, state.embedding_model);
+                state.embedding_index.invalidate().await;
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to embed note {}: {:#}", note_id, e),
+        }
+    });
+}
+
+#[derive(FromRow)]
+struct IndexedNote {
+    id: i64,
+    content: String,
+    model: Option<String>,
+    content_hash: Option<String>,
+}
+
+/// Embeds all notes without an up to date embedding, or every note when
+/// `all` is set. Returns the number of notes embedded.
+pub async fn reindex(
+    llm: &dyn LlmBackend,
+    pool: &SqlitePool,
+    model: &str,
+    all: bool,
+) -> anyhow::Result<usize> {
+    let notes = sqlx::query_as::<_, IndexedNote>(
+        "SELECT n.id, n.content, e.model, e.content_hash
+         FROM notes n
+         LEFT JOIN note_embeddings e ON e.note_id = n.id
+         ORDER BY n.id",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let stale: Vec<(IndexedNote, String)> = notes
+        .into_iter()
+        .map(|note| {
+            let hash = content_hash(&note.content);
+            (note, hash)
+        })
+        .filter(|(note, hash)| {
+            all || note.model.as_deref() != Some(model) || note.content_hash.as_ref() != Some(hash)
+        })
+        .collect();
+    info!("Embedding {} notes with {}", stale.len(), model);
+
+    let mut embedded = 0;
+    for batch in stale.chunks(REINDEX_BATCH_SIZE) {
+        let input = batch.iter().map(|(note, _)| note.content.clone()).collect();
+        let vectors = llm.embed(model, input).await?;
+        anyhow::ensure!(
+            vectors.len() == batch.len(),
+            "embedding model returned {} vectors for {} notes",
+            vectors.len(),
+            batch.len()
+        );
+        for ((note, hash), vector) in batch.iter().zip(vectors) {
+            store(pool, note.id, model, hash, &vector).await?;
+        }
+        embedded += batch.len();
+        info!("Embedded {} of {} notes", embedded, stale.len());
+    }
+    Ok(embedded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_llm::MockBackend;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn embeddings_are_kept_until_the_content_changes() {
+        let pool = testing::pool().await;
+        let llm = MockBackend::with_replies(Vec::<String>::new());
+        let note_id = testing::insert_note(&pool, "A walk by the sea.", "personal").await;
+
+        assert!(
+            embed_note(&llm, &pool, "mock", note_id, "A walk by the sea.")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !embed_note(&llm, &pool, "mock", note_id, "A walk by the sea.")
+                .await
+                .unwrap()
+        );
+        assert!(
+            embed_note(&llm, &pool, "other", note_id, "A walk by the sea.")
+                .await
+                .unwrap()
+        );
+        assert!(
+            embed_note(&llm, &pool, "other", note_id, "A walk in the rain.")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn reindex_embeds_the_stale_notes() {
+        let pool = testing::pool().await;
+        let llm = MockBackend::with_replies(Vec::<String>::new());
+        let embedded = testing::insert_note(&pool, "A walk by the sea.", "personal").await;
+        testing::insert_note(&pool, "Work was busy.", "work").await;
+        embed_note(&llm, &pool, "mock", embedded, "A walk by the sea.")
+            .await
+            .unwrap();
+
+        assert_eq!(reindex(&llm, &pool, "mock", false).await.unwrap(), 1);
+        assert_eq!(reindex(&llm, &pool, "mock", false).await.unwrap(), 0);
+        assert_eq!(reindex(&llm, &pool, "mock", true).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,821 @@
+//! People, places and organizations mentioned in the notes, extracted by the
+//! model with structured output. The names are normalized and matched against
+//! known aliases, so "Mom" in one note and "my mother" in another end up as
+//! the same entity.
+
+use crate::llm_json;
+use crate::models::{AppState, LlmParams};
+use crate::mood::{self, MoodSummary};
+use crate::notes::{content_hash, NoteWithCategory};
+use crate::prompts::{self, PreparedPrompt, PromptName};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use crate::search;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{
+    sqlite::SqlitePool, types::Json as SqlJson, FromRow, QueryBuilder, Sqlite, SqliteConnection,
+};
+use tracing::{error, info};
+
+const ENTITY_SELECT: &str = "SELECT e.id, e.kind, e.name, e.created_at,
+        (SELECT json_group_array(a.alias) FROM entity_aliases a WHERE a.entity_id = e.id)
+            AS aliases,
+        COUNT(n.id) AS note_count,
+        MAX(n.created_at) AS last_mentioned
+     FROM entities e
+     LEFT JOIN note_entities ne ON ne.entity_id = e.id
+     LEFT JOIN notes n ON n.id = ne.note_id
+     WHERE 1 = 1";
+
+/// Relations the writer refers to in many ways, each group is one person
+/// named after the first word.
+const FAMILY_ALIASES: &[&[&str]] = &[
+    &["mother", "mom", "mum", "mommy", "mummy", "mama", "mamma"],
+    &["father", "dad", "daddy", "papa"],
+    &["grandmother", "grandma", "granny", "nana", "gran"],
+    &["grandfather", "grandpa", "granddad", "grandad", "gramps"],
+    &["brother", "bro"],
+    &["sister", "sis"],
+    &["wife", "missus"],
+    &["husband", "hubby"],
+];
+
+/// Names that do not refer to an entity of their own.
+const IGNORED_NAMES: &[&str] = &["i", "me", "myself", "we", "us", "someone", "somebody"];
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum EntityKind {
+    Person,
+    Place,
+    Organization,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Entity {
+    pub id: i64,
+    pub kind: EntityKind,
+    pub name: String,
+    /// Normalized names matched to the entity.
+    pub aliases: SqlJson<Vec<String>>,
+    pub note_count: i64,
+    pub last_mentioned: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An entity as mentioned in a note.
+#[derive(Debug, Serialize, FromRow)]
+pub struct NoteEntity {
+    pub entity_id: i64,
+    pub kind: EntityKind,
+    pub name: String,
+    /// The name as the note uses it.
+    pub mention: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct EntitiesResponse {
+    entities: Vec<EntityItem>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct EntityItem {
+    name: String,
+    // Entities of unknown kinds are dropped, the schema restricts the model
+    // to the predefined ones
+    #[schemars(with = "EntityKind")]
+    kind: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+struct ExtractedEntity {
+    kind: EntityKind,
+    /// Name of a new entity.
+    name: String,
+    /// The name as the note uses it.
+    mention: String,
+    /// Normalized names, the first one matching a known alias decides the
+    /// entity.
+    keys: Vec<String>,
+}
+
+/// Lowercases `name` and strips what varies between mentions of the same
+/// entity: surrounding punctuation, a leading "my" or "the" and a trailing
+/// possessive. Family relations are mapped to the first word of their group.
+fn normalize(name: &str) -> String {
+    let mut key = name
+        .to_lowercase()
+        .replace('\u{2019}', "'")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    key = key.trim_matches(|c: char| !c.is_alphanumeric()).to_string();
+    for prefix in ["my ", "our ", "the "] {
+        if let Some(rest) = key.strip_prefix(prefix) {
+            key = rest.to_string();
+        }
+    }
+    if let Some(rest) = key.strip_suffix("'s") {
+        key = rest.to_string();
+    }
+    match FAMILY_ALIASES
+        .iter()
+        .find(|group| group.contains(&key.as_str()))
+    {
+        Some(group) => group[0].to_string(),
+        None => key,
+    }
+}
+
+/// Display name of a new entity mentioned as `mention`.
+fn display_name(mention: &str, key: &str) -> String {
+    if FAMILY_ALIASES.iter().any(|group| group[0] == key) {
+        let mut chars = key.chars();
+        return chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default();
+    }
+    let words: Vec<&str> = mention.split_whitespace().collect();
+    match words.split_first() {
+        Some((first, rest)) if ["my", "our"].contains(&first.to_lowercase().as_str()) => {
+            rest.join(" ")
+        }
+        _ => words.join(" "),
+    }
+}
+
+impl ExtractedEntity {
+    /// `None` if the kind is unknown or no name is left after normalizing.
+    fn from_item(item: EntityItem) -> Option<Self> {
+        let kind = Value::String(item.kind.trim().to_lowercase());
+        let kind = serde_json::from_value::<EntityKind>(kind).ok()?;
+        let mention = item.name.trim().to_string();
+        let mut keys: Vec<String> = Vec::new();
+        for name in std::iter::once(&item.name).chain(&item.aliases) {
+            let key = normalize(name);
+            if !key.is_empty() && !IGNORED_NAMES.contains(&key.as_str()) && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let name = display_name(&mention, keys.first()?);
+        Some(ExtractedEntity {
+            kind,
+            name,
+            mention,
+            keys,
+        })
+    }
+}
+
+/// Asks the model for the entities of the note. Returns them and the model
+/// that extracted them.
+async fn extract_with_model(
+    state: &AppState,
+    id: i64,
+    prompt: &PreparedPrompt,
+    content: &str,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<(Vec<ExtractedEntity>, String), (StatusCode, String)> {
+    let request = state
+        .entity_defaults
+        .request(prompt.render(content), params)
+        .with_format(llm_json::schema_for::<EntitiesResponse>());
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let record = |run: NewRun| record_run(&state.pool, run.prompt_version(&prompt.version));
+
+    let _ticket = state.scheduler.acquire(priority).await;
+    let max_attempts = 2;
+    for attempt in 1..=max_attempts {
+        let generation = match state.llm.generate(request.clone()).await {
+            Ok(generation) => {
+                record(NewRun::success(RunKind::Entities, Some(id), &generation)).await;
+                generation
+            }
+            Err(e) => {
+                error!("Failed to extract entities of note {}: {}", id, e);
+                record(NewRun::failure(RunKind::Entities, Some(id), &model, &e)).await;
+                return Err((
+                    e.status_code(),
+                    format!("Failed to extract entities: {}", e),
+                ));
+            }
+        };
+
+        match llm_json::extract_or_repair::<EntitiesResponse>(
+            state.llm.as_ref(),
+            &request,
+            &generation.text,
+        )
+        .await
+        {
+            Ok((response, repair)) => {
+                if let Some(repair) = repair {
+                    info!("Repaired entities JSON for note {}", id);
+                    record(NewRun::success(RunKind::Entities, Some(id), &repair)).await;
+                }
+                let entities = response
+                    .entities
+                    .into_iter()
+                    .filter_map(ExtractedEntity::from_item)
+                    .collect();
+                return Ok((entities, generation.model));
+            }
+            Err(e) => {
+                record(NewRun::repair_failure(
+                    RunKind::Entities,
+                    Some(id),
+                    &model,
+                    &e,
+                ))
+                .await;
+                error!(
+                    "Entity extraction attempt {} for note {} returned invalid JSON: {}",
+                    attempt, id, e
+                );
+            }
+        }
+    }
+    Err((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to generate valid entities JSON".to_string(),
+    ))
+}
+
+/// Id of the entity `extracted` refers to, created if none of its names is
+/// known yet. Names not known yet become aliases of the entity.
+async fn resolve_entity(
+    conn: &mut SqliteConnection,
+    extracted: &ExtractedEntity,
+) -> Result<i64, sqlx::Error> {
+    let mut entity_id = None;
+    for key in &extracted.keys {
+        entity_id = sqlx::query_scalar::<_, i64>(
+            "SELECT entity_id FROM entity_aliases WHERE kind = ? AND alias = ?",
+        )
+        .bind(extracted.kind)
+        .bind(key)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if entity_id.is_some() {
+            break;
+        }
+    }
+    let entity_id = match entity_id {
+        Some(entity_id) => entity_id,
+        None => {
+            sqlx::query_scalar::<_, i64>(
+                "INSERT INTO entities (kind, name, created_at) VALUES (?, ?, ?) RETURNING id",
+            )
+            .bind(extracted.kind)
+            .bind(&extracted.name)
+            .bind(Utc::now())
+            .fetch_one(&mut *conn)
+            .await?
+        }
+    };
+    for key in &extracted.keys {
+        sqlx::query(
+            "INSERT OR IGNORE INTO entity_aliases (kind, alias, entity_id) VALUES (?, ?, ?)",
+        )
+        .bind(extracted.kind)
+        .bind(key)
+        .bind(entity_id)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(entity_id)
+}
+
+/// Replaces the entities of note `id` by `entities`.
+async fn store_entities(
+    pool: &SqlitePool,
+    id: i64,
+    entities: &[ExtractedEntity],
+    model: &str,
+    prompt_version: &str,
+    hash: &str,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM note_entities WHERE note_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    for extracted in entities {
+        let entity_id = resolve_entity(&mut tx, extracted).await?;
+        // Several mentions of the same entity are stored once
+        sqlx::query(
+            "INSERT OR IGNORE INTO note_entities (note_id, entity_id, mention) VALUES (?, ?, ?)",
+        )
+        .bind(id)
+        .bind(entity_id)
+        .bind(&extracted.mention)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query(
+        "INSERT INTO entity_extractions (note_id, content_hash, model, prompt_version, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(note_id) DO UPDATE SET
+            content_hash = excluded.content_hash,
+            model = excluded.model,
+            prompt_version = excluded.prompt_version,
+            created_at = excluded.created_at",
+    )
+    .bind(id)
+    .bind(hash)
+    .bind(model)
+    .bind(prompt_version)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+async fn fetch_note_entities(
+    pool: &SqlitePool,
+    note_id: i64,
+) -> Result<Vec<NoteEntity>, sqlx::Error> {
+    sqlx::query_as::<_, NoteEntity>(
+        "SELECT e.id AS entity_id, e.kind, e.name, ne.mention
+         FROM note_entities ne
+         JOIN entities e ON e.id = ne.entity_id
+         WHERE ne.note_id = ?
+         ORDER BY e.kind, e.name",
+    )
+    .bind(note_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Extracts the entities of note `id` and stores them. The stored entities
+/// are kept if the note did not change since, unless `reextract` is set.
+pub async fn run_extraction(
+    state: &AppState,
+    id: i64,
+    reextract: bool,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<Vec<NoteEntity>, (StatusCode, String)> {
+    let content: Option<String> = sqlx::query_scalar("SELECT content FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&*state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch note: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+        })?;
+    let Some(content) = content else {
+        return Err((StatusCode::NOT_FOUND, "Note not found".to_string()));
+    };
+    let hash = content_hash(&content);
+
+    let extracted_hash: Option<String> =
+        sqlx::query_scalar("SELECT content_hash FROM entity_extractions WHERE note_id = ?")
+            .bind(id)
+            .fetch_optional(&*state.pool)
+            .await
+            .map_err(|e| {
+                error!("Failed to fetch entities: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch entities: {}", e),
+                )
+            })?;
+    if reextract || extracted_hash.as_deref() != Some(hash.as_str()) {
+        let prompt = prompts::note_prompt(state, PromptName::Entities, id).await?;
+        let (entities, model) =
+            extract_with_model(state, id, &prompt, &content, params, priority).await?;
+        store_entities(&state.pool, id, &entities, &model, &prompt.version, &hash)
+            .await
+            .map_err(|e| {
+                error!("Failed to store entities: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to store entities: {}", e),
+                )
+            })?;
+        info!("Extracted {} entities of note {}", entities.len(), id);
+    }
+
+    fetch_note_entities(&state.pool, id).await.map_err(|e| {
+        error!("Failed to fetch entities: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch entities: {}", e),
+        )
+    })
+}
+
+/// Extracts the entities of a created or updated note in the background.
+pub fn spawn_extract_note(state: &AppState, note_id: i64) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err((_, message)) =
+            run_extraction(&state, note_id, false, None, Priority::Batch).await
+        {
+            error!(
+                "Failed to extract entities of note {}: {}",
+                note_id, message
+            );
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtractParams {
+    /// Ask the model again even if the stored entities are up to date.
+    pub reextract: Option<bool>,
+}
+
+pub async fn extract_note_entities(
+    Path(id): Path<i64>,
+    Query(params): Query<ExtractParams>,
+    State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let reextract = params.reextract.unwrap_or(false);
+    let llm_params = llm_params.map(|Json(params)| params);
+    match run_extraction(&state, id, reextract, llm_params, Priority::Interactive).await {
+        Ok(entities) => (StatusCode::OK, Json(entities)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+pub async fn get_note_entities(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let extracted = async {
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?)")
+            .bind(id)
+            .fetch_one(&*state.pool)
+            .await?;
+        if !exists {
+            return Ok(Err("Note not found"));
+        }
+        let extracted: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM entity_extractions WHERE note_id = ?)")
+                .bind(id)
+                .fetch_one(&*state.pool)
+                .await?;
+        if !extracted {
+            return Ok(Err("Entities not extracted"));
+        }
+        fetch_note_entities(&state.pool, id).await.map(Ok)
+    }
+    .await;
+    match extracted {
+        Ok(Ok(entities)) => (StatusCode::OK, Json(entities)).into_response(),
+        Ok(Err(message)) => (StatusCode::NOT_FOUND, message).into_response(),
+        Err(e) => {
+            error!("Failed to fetch entities of note {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch entities: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntityFilter {
+    pub kind: Option<EntityKind>,
+    /// Part of the name or of an alias.
+    pub q: Option<String>,
+}
+
+async fn fetch_entity(pool: &SqlitePool, id: i64) -> Result<Option<Entity>, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(ENTITY_SELECT);
+    query.push(" AND e.id = ").push_bind(id);
+    query.push(" GROUP BY e.id");
+    query.build_query_as::<Entity>().fetch_optional(pool).await
+}
+
+/// The entities mentioned in at least one note, the most mentioned first.
+pub async fn list_entities(
+    State(state): State<AppState>,
+    Query(filter): Query<EntityFilter>,
+) -> impl IntoResponse {
+    let mut query = QueryBuilder::<Sqlite>::new(ENTITY_SELECT);
+    if let Some(kind) = filter.kind {
+        query.push(" AND e.kind = ").push_bind(kind);
+    }
+    if let Some(q) = filter.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", q);
+        query
+            .push(" AND (e.name LIKE ")
+            .push_bind(pattern.clone())
+            .push(" OR EXISTS (SELECT 1 FROM entity_aliases a WHERE a.entity_id = e.id AND a.alias LIKE ")
+            .push_bind(pattern)
+            .push("))");
+    }
+    query.push(
+        " GROUP BY e.id
+         HAVING note_count > 0
+         ORDER BY note_count DESC, last_mentioned DESC",
+    );
+
+    match query
+        .build_query_as::<Entity>()
+        .fetch_all(&*state.pool)
+        .await
+    {
+        Ok(entities) => (StatusCode::OK, Json(entities)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch entities: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch entities: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntityNotes {
+    pub entity: Entity,
+    /// Mean mood of the notes mentioning the entity, `null` if none of them
+    /// is scored yet.
+    pub mood: Option<MoodSummary>,
+    /// Newest first.
+    pub notes: Vec<NoteWithCategory>,
+}
+
+/// The notes mentioning entity `id` with their mean mood.
+pub async fn entity_notes(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    let result = async {
+        let Some(entity) = fetch_entity(&state.pool, id).await? else {
+            return Ok(None);
+        };
+        let note_ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT ne.note_id
+             FROM note_entities ne
+             JOIN notes n ON n.id = ne.note_id
+             WHERE ne.entity_id = ?
+             ORDER BY n.created_at DESC",
+        )
+        .bind(id)
+        .fetch_all(&*state.pool)
+        .await?;
+        let mood = mood::summarize(&state.pool, &note_ids).await?;
+        let notes = search::fetch_notes(&state.pool, &note_ids).await?;
+        Ok(Some(EntityNotes {
+            entity,
+            mood,
+            notes,
+        }))
+    }
+    .await;
+
+    match result {
+        Ok(Some(entity_notes)) => (StatusCode::OK, Json(entity_notes)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Entity not found").into_response(),
+        Err::<_, sqlx::Error>(e) => {
+            error!("Failed to fetch notes of entity {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch notes: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeParams {
+    /// Entity receiving the mentions and aliases.
+    pub into: i64,
+}
+
+/// Merges entity `id` into another one, for aliases the normalization does
+/// not catch, e.g. a nickname. Later mentions under any of the names of
+/// entity `id` are matched to the other entity.
+pub async fn merge_entity(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Json(params): Json<MergeParams>,
+) -> impl IntoResponse {
+    if id == params.into {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Cannot merge an entity into itself",
+        )
+            .into_response();
+    }
+    let kinds = async {
+        let source = fetch_entity(&state.pool, id).await?;
+        let target = fetch_entity(&state.pool, params.into).await?;
+        Ok::<_, sqlx::Error>(source.zip(target).map(|(s, t)| (s.kind, t.kind)))
+    }
+    .await;
+    match kinds {
+        Ok(Some((source, target))) if source != target => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Cannot merge entities of different kinds",
+            )
+                .into_response();
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "Entity not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch entity: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch entity: {}", e),
+            )
+                .into_response();
+        }
+    }
+
+    let merged = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO note_entities (note_id, entity_id, mention)
+             SELECT note_id, ?, mention FROM note_entities WHERE entity_id = ?",
+        )
+        .bind(params.into)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM note_entities WHERE entity_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE entity_aliases SET entity_id = ? WHERE entity_id = ?")
+            .bind(params.into)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM entities WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        fetch_entity(&state.pool, params.into).await
+    }
+    .await;
+
+    match merged {
+        Ok(Some(entity)) => {
+            info!("Merged entity {} into {}", id, params.into);
+            (StatusCode::OK, Json(entity)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Entity not found").into_response(),
+        Err(e) => {
+            error!("Failed to merge entity {} into {}: {}", id, params.into, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to merge entities: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const MOTHER: &str = r#"{"entities": [{"name": "Mom", "kind": "person", "aliases": []}]}"#;
+    const MY_MOTHER: &str =
+        r#"{"entities": [{"name": "my mother's", "kind": "Person", "aliases": ["Mum", "Jane"]}]}"#;
+
+    #[test]
+    fn normalize_names() {
+        assert_eq!(normalize("  Anna "), "anna");
+        assert_eq!(normalize("the  Red Lion."), "red lion");
+        assert_eq!(normalize("Anna\u{2019}s"), "anna");
+        assert_eq!(normalize("Mom"), "mother");
+        assert_eq!(normalize("my mother's"), "mother");
+        assert_eq!(normalize("Daddy"), "father");
+        // Too ambiguous to be family
+        assert_eq!(normalize("Ma"), "ma");
+        assert_eq!(normalize("Pop"), "pop");
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(display_name("Mom", "mother"), "Mother");
+        assert_eq!(display_name("my  Aunt Jo", "aunt jo"), "Aunt Jo");
+        assert_eq!(
+            display_name("Our Lady of Lourdes", "lady of lourdes"),
+            "Lady of Lourdes"
+        );
+        assert_eq!(display_name("The Red Lion", "red lion"), "The Red Lion");
+    }
+
+    #[test]
+    fn extracted_keys() {
+        let item = |name: &str, kind: &str, aliases: &[&str]| EntityItem {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+        };
+        let entity =
+            ExtractedEntity::from_item(item("Mom", "Person", &["mother", "Jane"])).unwrap();
+        assert_eq!(entity.kind, EntityKind::Person);
+        assert_eq!(
+            (entity.name.as_str(), entity.mention.as_str()),
+            ("Mother", "Mom")
+        );
+        assert_eq!(entity.keys, ["mother", "jane"]);
+
+        assert!(ExtractedEntity::from_item(item("me", "person", &[])).is_none());
+        assert!(ExtractedEntity::from_item(item("Monday", "date", &[])).is_none());
+    }
+
+    #[tokio::test]
+    async fn aliases_resolve_to_one_entity() {
+        let state = testing::state([MOTHER, MY_MOTHER]).await;
+        let first = testing::insert_note(&state.pool, "Called Mom.", "personal").await;
+        let second = testing::insert_note(&state.pool, "At my mother's.", "personal").await;
+
+        let entities = run_extraction(&state, first, false, None, Priority::Batch)
+            .await
+            .unwrap();
+        assert_eq!(entities[0].name, "Mother");
+        let entity_id = entities[0].entity_id;
+
+        let entities = run_extraction(&state, second, false, None, Priority::Batch)
+            .await
+            .unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].entity_id, entity_id);
+        assert_eq!(entities[0].mention, "my mother's");
+
+        let entity = fetch_entity(&state.pool, entity_id).await.unwrap().unwrap();
+        assert_eq!(entity.note_count, 2);
+        assert_eq!(entity.aliases.0, ["mother", "jane"]);
+    }
+
+    async fn merge(state: &AppState, id: i64, into: i64) -> StatusCode {
+        let params = Json(MergeParams { into });
+        merge_entity(Path(id), State(state.clone()), params)
+            .await
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn merge_into_an_existing_entity() {
+        let state = testing::state([
+            r#"{"entities": [{"name": "Jo", "kind": "person", "aliases": []}]}"#,
+            r#"{"entities": [{"name": "Joanna", "kind": "person", "aliases": []},
+                             {"name": "Jo", "kind": "person", "aliases": []}]}"#,
+            r#"{"entities": [{"name": "Lisbon", "kind": "place", "aliases": []}]}"#,
+            r#"{"entities": [{"name": "Jo", "kind": "person", "aliases": []}]}"#,
+        ])
+        .await;
+        let mut notes = Vec::new();
+        for content in ["Jo.", "Joanna and Jo.", "Lisbon.", "Jo again."] {
+            notes.push(testing::insert_note(&state.pool, content, "personal").await);
+        }
+        let extract = |id| run_extraction(&state, id, false, None, Priority::Batch);
+        let jo = extract(notes[0]).await.unwrap()[0].entity_id;
+        let joanna = extract(notes[1]).await.unwrap();
+        let joanna = joanna
+            .iter()
+            .find(|e| e.name == "Joanna")
+            .unwrap()
+            .entity_id;
+        let lisbon = extract(notes[2]).await.unwrap()[0].entity_id;
+
+        assert_eq!(merge(&state, jo, jo).await, StatusCode::BAD_REQUEST);
+        assert_eq!(merge(&state, jo, lisbon).await, StatusCode::BAD_REQUEST);
+        assert_eq!(merge(&state, jo, 999).await, StatusCode::NOT_FOUND);
+        assert_eq!(merge(&state, jo, joanna).await, StatusCode::OK);
+
+        assert!(fetch_entity(&state.pool, jo).await.unwrap().is_none());
+        let entity = fetch_entity(&state.pool, joanna).await.unwrap().unwrap();
+        // The note mentioning both is counted once
+        assert_eq!(entity.note_count, 2);
+        assert_eq!(entity.aliases.0, ["jo", "joanna"]);
+
+        // Later mentions of the merged name go to the remaining entity
+        let entities = extract(notes[3]).await.unwrap();
+        assert_eq!(
+            (entities[0].entity_id, entities[0].name.as_str()),
+            (joanna, "Joanna")
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::llm::{GenerateRequest, StreamEvent, Usage};
+use crate::models::{AppState, GenerateParams};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::{Priority, Ticket, Turn};
+use axum::{
+    extract::{Query, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures_util::{stream, Stream, StreamExt};
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+pub async fn generate_handler(
+    Query(params): Query<GenerateParams>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    generate_events(state, params).await
+}
+
+pub async fn generate_post_handler(
+    State(state): State<AppState>,
+    Json(params): Json<GenerateParams>,
+) -> impl IntoResponse {
+    generate_events(state, params).await
+}
+
+/// Streams the generation as Server-Sent Events: `queued` events with the
+/// position in the queue while waiting for the model, then one `data:` frame
+/// per token, followed by a `done` event with the usage stats, or an `error`
+/// event if the backend fails.
+async fn generate_events(
+    state: AppState,
+    params: GenerateParams,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (tx, rx) = mpsc::channel::<Event>(64);
+    tokio::spawn(stream_generation(
+        state,
+        params.into_request(),
+        RunKind::Generate,
+        None,
+        tx,
+    ));
+    Sse::new(ReceiverStream::new(rx).map(Ok)).keep_alive(KeepAlive::default())
+}
+
+/// Streams `request` to `tx` once it is its turn, recording the call as a
+/// `kind` run of the prompt `prompt_version`.
+pub async fn stream_generation(
+    state: AppState,
+    request: GenerateRequest,
+    kind: RunKind,
+    prompt_version: Option<String>,
+    tx: mpsc::Sender<Event>,
+) {
+    let record = |run: NewRun| {
+        let run = match &prompt_version {
+            Some(version) => run.prompt_version(version),
+            None => run,
+        };
+        record_run(&state.pool, run)
+    };
+
+    let mut ticket = state.scheduler.join(Priority::Interactive);
+    if !wait_turn(&mut ticket, &tx).await {
+        return;
+    }
+
+    let model = state.llm.resolve_model(request.model.as_deref());
+    // Loading the model can take a while before the first byte arrives
+    let events = tokio::select! {
+        events = state.llm.stream(request) => events,
+        _ = tx.closed() => {
+            record(NewRun::cancelled(kind, None, &model)).await;
+            return;
+        }
+    };
+    let mut events = match events {
+        Ok(events) => events,
+        Err(err) => {
+            error!(%err, "request to LLM backend failed");
+            stream::once(async move { Err(err) }).boxed()
+        }
+    };
+
+    loop {
+        // Dropping the stream when the client goes away aborts the request
+        // to the model server
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = tx.closed() => {
+                record(NewRun::cancelled(kind, None, &model)).await;
+                return;
+            }
+        };
+        let Some(event) = event else {
+            return;
+        };
+        let event = match event {
+            Ok(StreamEvent::Token(token)) => token_event(&token),
+            Ok(StreamEvent::Done { model, usage }) => {
+                record(NewRun::completed(kind, None, &model, &usage)).await;
+                done_event(&model, &usage)
+            }
+            Err(err) => {
+                error!(%err, "LLM stream failed");
+                record(NewRun::failure(kind, None, &model, &err)).await;
+                error_event(&err.to_string())
+            }
+        };
+        let _ = tx.send(event).await;
+    }
+}
+
+/// Waits for the turn of `ticket`, sending a `queued` event whenever the
+/// position in the queue changes. Returns `false` if the client went away
+/// in the meantime, dropping the ticket then leaves the queue.
+pub async fn wait_turn(ticket: &mut Ticket, tx: &mpsc::Sender<Event>) -> bool {
+    loop {
+        match ticket.turn() {
+            Turn::Running => return true,
+            Turn::Waiting(position) => {
+                if tx.send(queued_event(position)).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        tokio::select! {
+            _ = ticket.changed() => {}
+            _ = tx.closed() => return false,
+        }
+    }
+}
+
+pub fn queued_event(position: usize) -> Event {
+    Event::default()
+        .event("queued")
+        .data(serde_json::json!({ "position": position }).to_string())
+}
+
+pub fn token_event(token: &str) -> Event {
+    // Line breaks are split into several `data:` lines, which clients join
+    // again, but bare carriage returns are not allowed in a frame
+    Event::default().data(token.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+pub fn done_event(model: &str, usage: &Usage) -> Event {
+    Event::default().event("done").data(
+        serde_json::json!({
+            "model": model,
+            "usage": usage,
+        })
+        .to_string(),
+    )
+}
+
+pub fn error_event(message: &str) -> Event {
+    Event::default()
+        .event("error")
+        .data(message.replace(['\r', '\n'], " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ChatRequest, Generation, LlmBackend, LlmError, TokenStream};
+    use crate::testing;
+    use async_trait::async_trait;
+    use axum::body::to_bytes;
+    use std::sync::Arc;
+
+    /// Sends one token, then fails like a dropped connection.
+    struct CutOffBackend;
+
+    #[async_trait]
+    impl LlmBackend for CutOffBackend {
+        fn name(&self) -> &'static str {
+            "cut-off"
+        }
+
+        fn default_model(&self) -> &str {
+            "cut-off"
+        }
+
+        async fn generate(&self, _request: GenerateRequest) -> Result<Generation, LlmError> {
+            unimplemented!()
+        }
+
+        async fn stream(&self, _request: GenerateRequest) -> Result<TokenStream, LlmError> {
+            let events = [
+                Ok(StreamEvent::Token("Hello".to_string())),
+                Err(LlmError::Request("connection reset".to_string())),
+            ];
+            Ok(stream::iter(events).boxed())
+        }
+
+        async fn chat(&self, _request: ChatRequest) -> Result<Generation, LlmError> {
+            unimplemented!()
+        }
+
+        async fn embed(
+            &self,
+            _model: &str,
+            _input: Vec<String>,
+        ) -> Result<Vec<Vec<f32>>, LlmError> {
+            unimplemented!()
+        }
+
+        async fn health(&self) -> Result<(), LlmError> {
+            Ok(())
+        }
+    }
+
+    /// The `(event, data)` frames streamed for `prompt`.
+    async fn generate(state: AppState, prompt: &str) -> Vec<(String, String)> {
+        let params = GenerateParams {
+            prompt: prompt.to_string(),
+            model: None,
+            temperature: None,
+            top_p: None,
+            num_ctx: None,
+            seed: None,
+            stop: None,
+            keep_alive: None,
+        };
+        let response = generate_post_handler(State(state), Json(params))
+            .await
+            .into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec())
+            .unwrap()
+            .split("\n\n")
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| {
+                let mut event = "message".to_string();
+                let mut data = Vec::new();
+                for line in frame.lines() {
+                    if let Some(name) = line.strip_prefix("event: ") {
+                        event = name.to_string();
+                    } else if let Some(line) = line.strip_prefix("data: ") {
+                        data.push(line);
+                    }
+                }
+                (event, data.join("\n"))
+            })
+            .collect()
+    }
+
+    async fn run_statuses(state: &AppState) -> Vec<String> {
+        sqlx::query_scalar("SELECT status FROM llm_runs WHERE kind = 'generate'")
+            .fetch_all(&*state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_then_done() {
+        let state = testing::state(["Hello there"]).await;
+        let frames = generate(state.clone(), "Say hello").await;
+
+        let events: Vec<&str> = frames.iter().map(|(event, _)| event.as_str()).collect();
+        assert_eq!(events, ["message", "message", "done"]);
+        assert_eq!(frames[0].1, "Hello ");
+        assert_eq!(frames[1].1, "there");
+        let done: serde_json::Value = serde_json::from_str(&frames[2].1).unwrap();
+        assert_eq!(done["model"], "mock");
+        assert_eq!(done["usage"]["completion_tokens"], 2);
+        assert_eq!(run_statuses(&state).await, ["success"]);
+    }
+
+    #[tokio::test]
+    async fn failure_ends_with_an_error_event() {
+        let mut state = testing::state(["unused"]).await;
+        state.llm = Arc::new(CutOffBackend);
+        let frames = generate(state.clone(), "Say hello").await;
+
+        let events: Vec<&str> = frames.iter().map(|(event, _)| event.as_str()).collect();
+        assert_eq!(events, ["message", "error"]);
+        assert_eq!(frames[0].1, "Hello");
+        assert!(frames[1].1.contains("connection reset"), "{}", frames[1].1);
+        assert_eq!(run_statuses(&state).await, ["error"]);
+    }
+}
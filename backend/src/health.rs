@@ -0,0 +1,96 @@
+//! Liveness of the database and the LLM backend, for monitoring and to tell
+//! a broken setup from a slow model.
+
+use crate::models::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// The LLM check gives up after this long, a hanging backend counts as down.
+const LLM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new<E: ToString>(started: Instant, result: Result<(), E>) -> Self {
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok(()) => Self {
+                status: Status::Up,
+                latency_ms,
+                error: None,
+            },
+            Err(e) => Self {
+                status: Status::Down,
+                latency_ms,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LlmCheck {
+    pub backend: &'static str,
+    #[serde(flatten)]
+    pub check: Check,
+    /// Model calls currently fail fast after repeated failures.
+    pub circuit_open: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: Status,
+    pub database: Check,
+    pub llm: LlmCheck,
+}
+
+/// Answers `200` when the database and the LLM backend are reachable and
+/// `503` otherwise, with the details of each check in the body.
+pub async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    let started = Instant::now();
+    let result = sqlx::query("SELECT 1").execute(&*state.pool).await;
+    let database = Check::new(started, result.map(|_| ()));
+
+    let started = Instant::now();
+    let result = match tokio::time::timeout(LLM_TIMEOUT, state.llm.health()).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("no answer within {:?}", LLM_TIMEOUT)),
+    };
+    let llm = LlmCheck {
+        backend: state.llm.name(),
+        check: Check::new(started, result),
+        circuit_open: state.llm.circuit_open(),
+    };
+
+    let status = if database.status == Status::Up && llm.check.status == Status::Up {
+        Status::Up
+    } else {
+        Status::Down
+    };
+    let code = match status {
+        Status::Up => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        code,
+        Json(Health {
+            status,
+            database,
+            llm,
+        }),
+    )
+}
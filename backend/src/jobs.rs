@@ -0,0 +1,583 @@
+//! Persistent background jobs, so long running model calls survive a closed
+//! tab, a proxy timeout or a restart of the backend.
+
+use crate::chunking::Progress;
+use crate::models::{AppState, LlmParams};
+use crate::notes;
+use crate::scheduler::Priority;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{sqlite::SqlitePool, types::Json as SqlJson, FromRow, SqliteConnection};
+use std::{collections::HashMap, sync::Mutex};
+use tokio::sync::{oneshot, Notify};
+use tracing::{error, info};
+
+const JOB_COLUMNS: &str = "id, kind, note_id, params, status, attempts, result, error, \
+                           created_at, started_at, finished_at";
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum JobKind {
+    Analyze,
+    Categorize,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Everything besides the note a job needs to run, stored as JSON.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct JobParams {
+    #[serde(default)]
+    pub recategorize: bool,
+    /// Regenerate an analysis that is up to date.
+    #[serde(default)]
+    pub force: bool,
+    pub llm: Option<LlmParams>,
+}
+
+#[derive(Debug)]
+pub struct NewJob {
+    pub kind: JobKind,
+    pub note_id: i64,
+    pub params: JobParams,
+}
+
+impl NewJob {
+    pub fn analyze(note_id: i64, force: bool, llm: Option<LlmParams>) -> Self {
+        Self {
+            kind: JobKind::Analyze,
+            note_id,
+            params: JobParams {
+                recategorize: false,
+                force,
+                llm,
+            },
+        }
+    }
+
+    pub fn categorize(note_id: i64, recategorize: bool, llm: Option<LlmParams>) -> Self {
+        Self {
+            kind: JobKind::Categorize,
+            note_id,
+            params: JobParams {
+                recategorize,
+                force: false,
+                llm,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Job {
+    pub id: i64,
+    pub kind: JobKind,
+    pub note_id: i64,
+    pub params: SqlJson<JobParams>,
+    pub status: JobStatus,
+    /// Number of times a worker picked the job up, more than one if the
+    /// backend was restarted while it was running.
+    pub attempts: i64,
+    /// The updated note, or the note with its categories.
+    pub result: Option<SqlJson<Value>>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobResponse {
+    #[serde(flatten)]
+    pub job: Job,
+    /// Position in the queue of a queued job, 1 is picked up next.
+    pub queue_position: Option<i64>,
+    /// How far a running analysis of a long note got.
+    pub progress: Option<Progress>,
+}
+
+/// Wakes up idle workers when jobs are added and keeps track of the running
+/// jobs.
+#[derive(Debug, Default)]
+pub struct JobQueue {
+    notify: Notify,
+    running: Mutex<HashMap<i64, RunningJob>>,
+}
+
+#[derive(Debug)]
+struct RunningJob {
+    cancel: oneshot::Sender<()>,
+    progress: Option<Progress>,
+}
+
+impl JobQueue {
+    /// Stops running job `id`, which aborts the request to the model server.
+    fn stop(&self, id: i64) {
+        if let Some(job) = self.running.lock().unwrap().remove(&id) {
+            let _ = job.cancel.send(());
+        }
+    }
+}
+
+pub async fn enqueue(state: &AppState, job: NewJob) -> Result<Job, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(&format!(
+        "INSERT INTO jobs (kind, note_id, params, status, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         RETURNING {}",
+        JOB_COLUMNS
+    ))
+    .bind(job.kind)
+    .bind(job.note_id)
+    .bind(SqlJson(&job.params))
+    .bind(JobStatus::Queued)
+    .bind(Utc::now())
+    .fetch_one(&*state.pool)
+    .await?;
+
+    info!(
+        "Queued {:?} job {} for note {}",
+        job.kind, job.id, job.note_id
+    );
+    state.jobs.notify.notify_one();
+    Ok(job)
+}
+
+/// Queues `job` and answers with `202 Accepted` and the job, whose id can be
+/// polled at `GET /jobs/:id`.
+pub async fn enqueue_response(state: &AppState, job: NewJob) -> Response {
+    match enqueue(state, job).await {
+        Ok(job) => {
+            let response = job_response(state, job).await;
+            (StatusCode::ACCEPTED, Json(response)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to queue job: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to queue job: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn job_response(state: &AppState, job: Job) -> JobResponse {
+    let queue_position = if job.status == JobStatus::Queued {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) + 1 FROM jobs WHERE status = ? AND id < ?")
+            .bind(JobStatus::Queued)
+            .bind(job.id)
+            .fetch_one(&*state.pool)
+            .await
+            .map_err(|e| {
+                error!(
+                    "Failed to determine queue position of job {}: {}",
+                    job.id, e
+                )
+            })
+            .ok()
+    } else {
+        None
+    };
+    let progress = match job.status {
+        JobStatus::Running => state
+            .jobs
+            .running
+            .lock()
+            .unwrap()
+            .get(&job.id)
+            .and_then(|running| running.progress),
+        _ => None,
+    };
+    JobResponse {
+        job,
+        queue_position,
+        progress,
+    }
+}
+
+pub async fn get_job(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    let job = sqlx::query_as::<_, Job>(&format!("SELECT {} FROM jobs WHERE id = ?", JOB_COLUMNS))
+        .bind(id)
+        .fetch_optional(&*state.pool)
+        .await;
+
+    match job {
+        Ok(Some(job)) => {
+            let response = job_response(&state, job).await;
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch job {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch job: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Cancels a queued or running job. A running job is stopped right away,
+/// which aborts the request to the model server.
+pub async fn cancel_job(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    let cancelled = sqlx::query_as::<_, Job>(&format!(
+        "UPDATE jobs SET status = ?, finished_at = ?
+         WHERE id = ? AND status IN (?, ?)
+         RETURNING {}",
+        JOB_COLUMNS
+    ))
+    .bind(JobStatus::Cancelled)
+    .bind(Utc::now())
+    .bind(id)
+    .bind(JobStatus::Queued)
+    .bind(JobStatus::Running)
+    .fetch_optional(&*state.pool)
+    .await;
+
+    match cancelled {
+        Ok(Some(job)) => {
+            state.jobs.stop(id);
+            info!("Cancelled job {}", id);
+            (
+                StatusCode::OK,
+                Json(JobResponse {
+                    job,
+                    queue_position: None,
+                    progress: None,
+                }),
+            )
+                .into_response()
+        }
+        Ok(None) => match sqlx::query_scalar::<_, i64>("SELECT id FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&*state.pool)
+            .await
+        {
+            Ok(Some(_)) => (StatusCode::CONFLICT, "Job already finished").into_response(),
+            Ok(None) => (StatusCode::NOT_FOUND, "Job not found").into_response(),
+            Err(e) => {
+                error!("Failed to fetch job {}: {}", id, e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch job: {}", e),
+                )
+                    .into_response()
+            }
+        },
+        Err(e) => {
+            error!("Failed to cancel job {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to cancel job: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Cancels the queued and running jobs of note `note_id`, e.g. as part of
+/// deleting the note. Returns their ids for `stop_jobs` once the transaction
+/// is committed.
+pub async fn cancel_note_jobs(
+    conn: &mut SqliteConnection,
+    note_id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE jobs SET status = ?, finished_at = ?
+         WHERE note_id = ? AND status IN (?, ?)
+         RETURNING id",
+    )
+    .bind(JobStatus::Cancelled)
+    .bind(Utc::now())
+    .bind(note_id)
+    .bind(JobStatus::Queued)
+    .bind(JobStatus::Running)
+    .fetch_all(conn)
+    .await
+}
+
+/// Stops the running ones of the cancelled jobs `ids`.
+pub fn stop_jobs(state: &AppState, ids: &[i64]) {
+    for &id in ids {
+        state.jobs.stop(id);
+    }
+}
+
+/// Requeues the jobs that were running when the backend stopped and starts
+/// `workers` workers processing the queue.
+pub async fn start_workers(state: AppState, workers: usize) -> anyhow::Result<()> {
+    let resumed = sqlx::query("UPDATE jobs SET status = ?, started_at = NULL WHERE status = ?")
+        .bind(JobStatus::Queued)
+        .bind(JobStatus::Running)
+        .execute(&*state.pool)
+        .await?
+        .rows_affected();
+    if resumed > 0 {
+        info!("Resuming {} interrupted jobs", resumed);
+    }
+
+    for worker in 0..workers {
+        tokio::spawn(work(state.clone(), worker));
+    }
+    info!("Started {} job workers", workers);
+    Ok(())
+}
+
+async fn work(state: AppState, worker: usize) {
+    loop {
+        match claim_next(&state.pool).await {
+            Ok(Some(job)) => run(&state, worker, job).await,
+            Ok(None) => state.jobs.notify.notified().await,
+            Err(e) => {
+                error!("Job worker {} failed to fetch the next job: {}", worker, e);
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Marks the oldest queued job as running and returns it.
+async fn claim_next(pool: &SqlitePool) -> Result<Option<Job>, sqlx::Error> {
+    sqlx::query_as::<_, Job>(&format!(
+        "UPDATE jobs
+         SET status = ?, attempts = attempts + 1, started_at = ?
+         WHERE id = (SELECT id FROM jobs WHERE status = ? ORDER BY id LIMIT 1)
+         RETURNING {}",
+        JOB_COLUMNS
+    ))
+    .bind(JobStatus::Running)
+    .bind(Utc::now())
+    .bind(JobStatus::Queued)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn run(state: &AppState, worker: usize, job: Job) {
+    info!(
+        "Worker {} running {:?} job {} for note {}",
+        worker, job.kind, job.id, job.note_id
+    );
+    let (cancel, cancelled) = oneshot::channel();
+    state.jobs.running.lock().unwrap().insert(
+        job.id,
+        RunningJob {
+            cancel,
+            progress: None,
+        },
+    );
+
+    // The job may have been cancelled before it was registered above
+    let status = sqlx::query_scalar::<_, JobStatus>("SELECT status FROM jobs WHERE id = ?")
+        .bind(job.id)
+        .fetch_one(&*state.pool)
+        .await;
+    if let Ok(JobStatus::Cancelled) = status {
+        state.jobs.running.lock().unwrap().remove(&job.id);
+        return;
+    }
+
+    let SqlJson(params) = job.params;
+    let progress = |progress| {
+        if let Some(running) = state.jobs.running.lock().unwrap().get_mut(&job.id) {
+            running.progress = Some(progress);
+        }
+    };
+    let execution = async {
+        match job.kind {
+            JobKind::Analyze => notes::run_analysis(
+                state,
+                job.note_id,
+                params.force,
+                params.llm,
+                Priority::Batch,
+                &progress,
+            )
+            .await
+            .map(|note| serde_json::to_value(note).unwrap_or_default()),
+            JobKind::Categorize => notes::run_categorization(
+                state,
+                job.note_id,
+                params.recategorize,
+                params.llm,
+                Priority::Batch,
+            )
+            .await
+            .map(|categorized| serde_json::to_value(categorized).unwrap_or_default()),
+        }
+    };
+
+    // Dropping the execution aborts the model call
+    let result = tokio::select! {
+        result = execution => result,
+        _ = cancelled => {
+            info!("Job {} was cancelled while running", job.id);
+            return;
+        }
+    };
+    state.jobs.running.lock().unwrap().remove(&job.id);
+
+    let (status, result, error) = match result {
+        Ok(result) => (JobStatus::Succeeded, Some(SqlJson(result)), None),
+        Err((_, message)) => {
+            error!("Job {} failed: {}", job.id, message);
+            (JobStatus::Failed, None, Some(message))
+        }
+    };
+    // A job cancelled in the meantime keeps its status
+    let stored = sqlx::query(
+        "UPDATE jobs SET status = ?, result = ?, error = ?, finished_at = ?
+         WHERE id = ? AND status = ?",
+    )
+    .bind(status)
+    .bind(result)
+    .bind(error)
+    .bind(Utc::now())
+    .bind(job.id)
+    .bind(JobStatus::Running)
+    .execute(&*state.pool)
+    .await;
+
+    match stored {
+        Ok(_) => info!("Job {} finished with status {:?}", job.id, status),
+        Err(e) => error!("Failed to store the outcome of job {}: {}", job.id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    async fn fetch_job(state: &AppState, id: i64) -> Job {
+        sqlx::query_as::<_, Job>(&format!("SELECT {} FROM jobs WHERE id = ?", JOB_COLUMNS))
+            .bind(id)
+            .fetch_one(&*state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn jobs_run_in_queue_order() {
+        let state = testing::state(["A calm day."]).await;
+        let note_id = testing::insert_note(&state.pool, "A long day.", "personal").await;
+        let analyze = enqueue(&state, NewJob::analyze(note_id, false, None))
+            .await
+            .unwrap();
+        let categorize = enqueue(&state, NewJob::categorize(note_id, false, None))
+            .await
+            .unwrap();
+        let categorize_id = categorize.id;
+        let response = job_response(&state, categorize).await;
+        assert_eq!(response.queue_position, Some(2));
+
+        let job = claim_next(&state.pool).await.unwrap().unwrap();
+        assert_eq!(
+            (job.id, job.status, job.attempts),
+            (analyze.id, JobStatus::Running, 1)
+        );
+        let response = job_response(&state, fetch_job(&state, job.id).await).await;
+        assert_eq!(response.queue_position, None);
+        run(&state, 0, job).await;
+        let job = fetch_job(&state, analyze.id).await;
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.result.unwrap().0["analysis"], "A calm day.");
+
+        // The model does not answer with JSON, so the categorization fails
+        let job = claim_next(&state.pool).await.unwrap().unwrap();
+        run(&state, 0, job).await;
+        let job = fetch_job(&state, categorize_id).await;
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(
+            job.error.as_deref(),
+            Some("Failed to generate valid categorization JSON")
+        );
+        assert!(claim_next(&state.pool).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn interrupted_jobs_are_resumed() {
+        let state = testing::state(["A calm day."]).await;
+        let note_id = testing::insert_note(&state.pool, "A long day.", "personal").await;
+        let queued = enqueue(&state, NewJob::analyze(note_id, false, None))
+            .await
+            .unwrap();
+        claim_next(&state.pool).await.unwrap().unwrap();
+
+        // Requeued at startup, without workers to pick it up
+        start_workers(state.clone(), 0).await.unwrap();
+        let job = claim_next(&state.pool).await.unwrap().unwrap();
+        assert_eq!((job.id, job.attempts), (queued.id, 2));
+    }
+
+    #[tokio::test]
+    async fn running_analysis_reports_progress() {
+        let state = testing::state(Vec::<String>::new()).await;
+        let note_id = testing::insert_note(&state.pool, "A long day.", "personal").await;
+        let job = enqueue(&state, NewJob::analyze(note_id, false, None))
+            .await
+            .unwrap();
+        let response = job_response(&state, job).await;
+        assert_eq!(response.queue_position, Some(1));
+        assert!(response.progress.is_none());
+
+        let job = claim_next(&state.pool).await.unwrap().unwrap();
+        let (cancel, _cancelled) = oneshot::channel();
+        state.jobs.running.lock().unwrap().insert(
+            job.id,
+            RunningJob {
+                cancel,
+                progress: Some(Progress::Part { part: 2, parts: 3 }),
+            },
+        );
+        let response = job_response(&state, job).await;
+        assert_eq!(response.queue_position, None);
+        assert!(matches!(
+            response.progress,
+            Some(Progress::Part { part: 2, parts: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn deleting_the_note_cancels_its_jobs() {
+        let state = testing::state(Vec::<String>::new()).await;
+        let note_id = testing::insert_note(&state.pool, "A long day.", "personal").await;
+        let other_id = testing::insert_note(&state.pool, "Another day.", "personal").await;
+        let queued = enqueue(&state, NewJob::categorize(note_id, false, None))
+            .await
+            .unwrap();
+        let other = enqueue(&state, NewJob::categorize(other_id, false, None))
+            .await
+            .unwrap();
+
+        let response = notes::delete_note(State(state.clone()), Path(note_id))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let status = |id| {
+            sqlx::query_scalar::<_, JobStatus>("SELECT status FROM jobs WHERE id = ?")
+                .bind(id)
+                .fetch_one(&*state.pool)
+        };
+        assert_eq!(status(queued.id).await.unwrap(), JobStatus::Cancelled);
+        assert_eq!(status(other.id).await.unwrap(), JobStatus::Queued);
+    }
+}
@@ -0,0 +1,338 @@
+//! Typed interface to the language model backends used by the handlers.
+
+use crate::config::Config;
+use crate::resilience::ResilientBackend;
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::{collections::VecDeque, fmt, sync::Arc, time::Duration};
+
+#[derive(Debug, Clone, Default)]
+pub struct GenerateRequest {
+    /// Model to use, the backend default when `None`.
+    pub model: Option<String>,
+    pub prompt: String,
+    /// JSON schema the response has to conform to.
+    pub format: Option<Value>,
+    pub options: GenerationOptions,
+}
+
+impl GenerateRequest {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_format(mut self, format: Value) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn with_model(mut self, model: Option<String>) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn with_options(mut self, options: GenerationOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Sampling and runtime options of a model call. Unset options are left to
+/// the backend, backends ignore the options their protocol has no notion of.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationOptions {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Size of the context window in tokens.
+    pub num_ctx: Option<u32>,
+    pub seed: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    /// How long the model stays loaded after the call, e.g. `"10m"`.
+    pub keep_alive: Option<String>,
+}
+
+impl GenerationOptions {
+    /// Fills the options not set in `self` from `defaults`.
+    pub fn or(self, defaults: &GenerationOptions) -> Self {
+        Self {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            num_ctx: self.num_ctx.or(defaults.num_ctx),
+            seed: self.seed.or(defaults.seed),
+            stop: self.stop.or_else(|| defaults.stop.clone()),
+            keep_alive: self.keep_alive.or_else(|| defaults.keep_alive.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: ChatRole::User,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatRequest {
+    /// Model to use, the backend default when `None`.
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    /// JSON schema the response has to conform to.
+    pub format: Option<Value>,
+    pub options: GenerationOptions,
+}
+
+/// Token counts and timings reported for a single model call.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    #[serde(rename = "total_duration_ms", serialize_with = "serialize_millis")]
+    pub total_duration: Duration,
+    #[serde(rename = "load_duration_ms", serialize_with = "serialize_millis")]
+    pub load_duration: Duration,
+    #[serde(
+        rename = "prompt_eval_duration_ms",
+        serialize_with = "serialize_millis"
+    )]
+    pub prompt_eval_duration: Duration,
+    #[serde(rename = "eval_duration_ms", serialize_with = "serialize_millis")]
+    pub eval_duration: Duration,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+/// A completed, non-streamed model response.
+#[derive(Debug, Clone, Serialize)]
+pub struct Generation {
+    pub text: String,
+    pub model: String,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Token(String),
+    /// Sent once after the last token.
+    Done {
+        model: String,
+        usage: Usage,
+    },
+}
+
+pub type TokenStream = BoxStream<'static, Result<StreamEvent, LlmError>>;
+
+#[derive(Debug)]
+pub enum LlmError {
+    /// The backend could not be reached.
+    Request(String),
+    /// The backend answered with a non-success status.
+    Status { status: u16, body: String },
+    /// The backend response could not be decoded.
+    Decode(String),
+    /// Calls fail fast as the backend failed repeatedly.
+    Unavailable(String),
+}
+
+impl LlmError {
+    /// Connection problems and server errors, which may go away when the
+    /// call is repeated. Client errors and bad responses will not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LlmError::Request(_) => true,
+            LlmError::Status { status, .. } => *status >= 500,
+            LlmError::Decode(_) | LlmError::Unavailable(_) => false,
+        }
+    }
+
+    /// Status to answer with when a handler cannot recover from the error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LlmError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Request(message) => write!(f, "request to LLM backend failed: {}", message),
+            LlmError::Status { status, body } => {
+                write!(f, "LLM backend returned status {}: {}", status, body)
+            }
+            LlmError::Decode(message) => {
+                write!(f, "failed to decode LLM backend response: {}", message)
+            }
+            LlmError::Unavailable(message) => write!(f, "LLM backend unavailable: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            LlmError::Decode(err.to_string())
+        } else {
+            LlmError::Request(err.to_string())
+        }
+    }
+}
+
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// Short identifier used in logs.
+    fn name(&self) -> &'static str;
+
+    fn default_model(&self) -> &str;
+
+    /// Name of the model a request for `model` ends up using.
+    fn resolve_model(&self, model: Option<&str>) -> String {
+        model.unwrap_or(self.default_model()).to_string()
+    }
+
+    async fn generate(&self, request: GenerateRequest) -> Result<Generation, LlmError>;
+
+    async fn stream(&self, request: GenerateRequest) -> Result<TokenStream, LlmError>;
+
+    async fn chat(&self, request: ChatRequest) -> Result<Generation, LlmError>;
+
+    /// One vector per input, computed with the embedding model `model`.
+    async fn embed(&self, model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError>;
+
+    /// Cheap request checking that the backend is reachable.
+    async fn health(&self) -> Result<(), LlmError>;
+
+    /// Whether calls currently fail fast, see `ResilientBackend`.
+    fn circuit_open(&self) -> bool {
+        false
+    }
+}
+
+/// Builds the backend selected by `LLM_BACKEND`, wrapped with retries and
+/// a circuit breaker.
+pub fn backend_from_config(config: &Config) -> anyhow::Result<Arc<dyn LlmBackend>> {
+    let backend = select_backend(config)?;
+    Ok(Arc::new(ResilientBackend::new(
+        backend,
+        config.resilience.clone(),
+    )))
+}
+
+fn select_backend(config: &Config) -> anyhow::Result<Arc<dyn LlmBackend>> {
+    match config.llm_backend.as_str() {
+        "ollama" => Ok(Arc::new(crate::ollama::OllamaBackend::new(
+            reqwest::Client::new(),
+            &config.ollama_url,
+            &config.default_model,
+        ))),
+        "openai" => Ok(Arc::new(crate::openai::OpenAiBackend::new(
+            reqwest::Client::new(),
+            &config.openai_url,
+            config.openai_api_key.clone(),
+            &config.default_model,
+        ))),
+        "mock" => {
+            let backend = match &config.mock_llm_script {
+                Some(path) => crate::mock_llm::MockBackend::from_script_file(path)?,
+                None => crate::mock_llm::MockBackend::default(),
+            };
+            Ok(Arc::new(backend))
+        }
+        other => anyhow::bail!("Unknown LLM backend: {}", other),
+    }
+}
+
+/// Turns a non-success response into `LlmError::Status`.
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, LlmError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(LlmError::Status {
+            status: status.as_u16(),
+            body,
+        });
+    }
+    Ok(response)
+}
+
+/// Splits a byte stream into lines, buffering partial lines across chunks.
+/// Empty lines are skipped.
+pub fn lines<S, E>(bytes: S) -> impl Stream<Item = Result<String, LlmError>> + Send
+where
+    S: Stream<Item = Result<axum::body::Bytes, E>> + Send + Unpin + 'static,
+    E: Into<LlmError>,
+{
+    let state = (bytes, Vec::new(), VecDeque::new(), false);
+    stream::unfold(
+        state,
+        |(mut bytes, mut buffer, mut ready, mut finished)| async move {
+            loop {
+                if let Some(line) = ready.pop_front() {
+                    return Some((Ok(line), (bytes, buffer, ready, finished)));
+                }
+                if finished {
+                    return None;
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => {
+                        buffer.extend_from_slice(&chunk);
+                        while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                            let line: Vec<u8> = buffer.drain(..=pos).collect();
+                            push_line(&mut ready, &line);
+                        }
+                    }
+                    Some(Err(err)) => {
+                        finished = true;
+                        return Some((Err(err.into()), (bytes, buffer, ready, finished)));
+                    }
+                    None => {
+                        finished = true;
+                        let rest = std::mem::take(&mut buffer);
+                        push_line(&mut ready, &rest);
+                    }
+                }
+            }
+        },
+    )
+}
+
+fn push_line(ready: &mut VecDeque<String>, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    if !line.is_empty() {
+        ready.push_back(line.to_string());
+    }
+}
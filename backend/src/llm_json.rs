@@ -0,0 +1,340 @@
+//! Extraction of JSON values from free-form model output. Small local models
+//! tend to wrap JSON in code fences, add a preamble or emit trailing commas,
+//! so the output is cleaned up before it is validated against the target type.
+
+use crate::llm::{GenerateRequest, Generation, LlmBackend, LlmError};
+use schemars::{generate::SchemaSettings, JsonSchema};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+
+const JSON_REPAIR_PROMPT: &str = r#"The following text was supposed to be a single valid JSON object but it could not be parsed.
+
+Error: {error}
+
+Text:
+{json}
+
+Reply with the corrected JSON object only, without any explanation or code fences."#;
+
+#[derive(Debug)]
+pub struct ExtractError(String);
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+#[derive(Debug)]
+pub enum RepairError {
+    /// The repair request itself failed.
+    Llm(LlmError),
+    /// The repaired output still does not match the target type. Carries the
+    /// repair generation so callers can account for it.
+    Invalid(ExtractError, Generation),
+}
+
+impl fmt::Display for RepairError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepairError::Llm(err) => write!(f, "JSON repair request failed: {}", err),
+            RepairError::Invalid(err, _) => {
+                write!(f, "repaired JSON is still invalid: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RepairError {}
+
+/// JSON schema of `T`, sent along with structured output requests to
+/// constrain the model output. Subschemas are inlined as not every backend
+/// resolves `$ref`s.
+pub fn schema_for<T: JsonSchema>() -> Value {
+    SchemaSettings::draft07()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<T>()
+        .to_value()
+}
+
+/// Parses `raw` into `T`, stripping code fences and surrounding prose and
+/// fixing common syntax slips first.
+pub fn extract<T: DeserializeOwned>(raw: &str) -> Result<T, ExtractError> {
+    let trimmed = raw.trim();
+    if let Ok(value) = serde_json::from_str(trimmed) {
+        return Ok(value);
+    }
+
+    let unfenced = strip_code_fence(trimmed);
+    let candidate = outermost_object(unfenced)
+        .ok_or_else(|| ExtractError("no JSON object found in model output".to_string()))?;
+    let repaired = lenient_fixes(candidate);
+
+    // Parse into a `Value` first to tell syntax errors from schema mismatches
+    let value: Value = serde_json::from_str(&repaired)
+        .map_err(|e| ExtractError(format!("invalid JSON: {}", e)))?;
+    serde_json::from_value(value)
+        .map_err(|e| ExtractError(format!("JSON does not match the expected shape: {}", e)))
+}
+
+/// Like [`extract`], but asks the model to fix its output with a short
+/// follow-up prompt when the output cannot be salvaged locally. The repair
+/// uses the model, options and format of the original `request`. Returns the
+/// repair generation alongside the value, or inside the error if the repair
+/// did not help, so callers can account for it.
+pub async fn extract_or_repair<T: DeserializeOwned>(
+    llm: &dyn LlmBackend,
+    request: &GenerateRequest,
+    raw: &str,
+) -> Result<(T, Option<Generation>), RepairError> {
+    let error = match extract(raw) {
+        Ok(value) => return Ok((value, None)),
+        Err(error) => error,
+    };
+
+    let prompt = JSON_REPAIR_PROMPT
+        .replace("{error}", &error.to_string())
+        .replace("{json}", raw.trim());
+    let repair = GenerateRequest {
+        prompt,
+        ..request.clone()
+    };
+    let generation = llm.generate(repair).await.map_err(RepairError::Llm)?;
+    match extract(&generation.text) {
+        Ok(value) => Ok((value, Some(generation))),
+        Err(error) => Err(RepairError::Invalid(error, generation)),
+    }
+}
+
+/// Returns the contents of the first fenced code block, or `text` if there is
+/// none. An unterminated fence runs to the end of the text.
+fn strip_code_fence(text: &str) -> &str {
+    let Some(start) = text.find("```") else {
+        return text;
+    };
+    let after_fence = &text[start + 3..];
+    // Skip the language tag, e.g. ```json
+    let body = match after_fence.find('\n') {
+        Some(newline) if !after_fence[..newline].contains(['{', '[']) => {
+            &after_fence[newline + 1..]
+        }
+        _ => after_fence,
+    };
+    match body.find("```") {
+        Some(end) => &body[..end],
+        None => body,
+    }
+}
+
+/// Finds the first `{` and returns the text up to its matching `}`, taking
+/// string literals into account. Truncated output is returned to the end.
+fn outermost_object(text: &str) -> Option<&str> {
+    let start = text.find('{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (offset, c) in text[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return Some(&text[start..=start + offset]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(&text[start..])
+}
+
+/// Replaces typographic quotes, drops trailing commas and closes brackets
+/// left open by truncated output.
+fn lenient_fixes(text: &str) -> String {
+    let text = text.replace(['\u{201c}', '\u{201d}'], "\"");
+    let mut output = String::with_capacity(text.len());
+    let mut open = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            output.push(c);
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                open.pop();
+                drop_trailing_comma(&mut output);
+            }
+            _ => {}
+        }
+        output.push(c);
+    }
+
+    if in_string {
+        output.push('"');
+    }
+    while let Some(close) = open.pop() {
+        drop_trailing_comma(&mut output);
+        output.push(close);
+    }
+    output
+}
+
+fn drop_trailing_comma(output: &mut String) {
+    let content_len = output.trim_end().len();
+    if output[..content_len].ends_with(',') {
+        output.truncate(content_len - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_llm::MockBackend;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Reply {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn reply(name: &str, tags: &[&str]) -> Reply {
+        Reply {
+            name: name.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn plain_json() {
+        let parsed: Reply = extract(r#" {"name": "a", "tags": ["x"]} "#).unwrap();
+        assert_eq!(parsed, reply("a", &["x"]));
+    }
+
+    #[test]
+    fn fenced_with_language_tag() {
+        let raw = "```json\n{\"name\": \"a\", \"tags\": []}\n```";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &[]));
+    }
+
+    #[test]
+    fn fenced_without_language_tag() {
+        let raw = "```\n{\"name\": \"a\", \"tags\": []}\n```";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &[]));
+        let inline = "```{\"name\": \"a\", \"tags\": []}```";
+        assert_eq!(extract::<Reply>(inline).unwrap(), reply("a", &[]));
+    }
+
+    #[test]
+    fn prose_around_the_json() {
+        let raw = "Here is the result you asked for:\n{\"name\": \"a\", \"tags\": [\"x\"]}\n\
+                   Let me know if you need anything else.";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &["x"]));
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let raw = "{\"name\": \"a\", \"tags\": [\"x\", \"y\",],\n}";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &["x", "y"]));
+    }
+
+    #[test]
+    fn truncated_object() {
+        let raw = "{\"name\": \"a\", \"tags\": [\"x\", \"y";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &["x", "y"]));
+        let raw = "{\"name\": \"a\", \"tags\": [\"x\",";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &["x"]));
+    }
+
+    #[test]
+    fn braces_inside_strings() {
+        let raw = r#"Result: {"name": "a } b { \" c", "tags": ["{x}"]} trailing }"#;
+        assert_eq!(
+            extract::<Reply>(raw).unwrap(),
+            reply("a } b { \" c", &["{x}"])
+        );
+    }
+
+    #[test]
+    fn typographic_quotes() {
+        let raw = "{\u{201c}name\u{201d}: \u{201c}a\u{201d}, \u{201c}tags\u{201d}: []}";
+        assert_eq!(extract::<Reply>(raw).unwrap(), reply("a", &[]));
+    }
+
+    #[test]
+    fn no_object() {
+        let error = extract::<Reply>("I cannot answer that.").unwrap_err();
+        assert_eq!(error.to_string(), "no JSON object found in model output");
+    }
+
+    #[test]
+    fn shape_mismatch() {
+        let error = extract::<Reply>(r#"{"name": 1, "tags": []}"#).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("JSON does not match the expected shape"));
+    }
+
+    #[tokio::test]
+    async fn repair_is_returned() {
+        let llm = MockBackend::with_replies([r#"{"name": "a", "tags": []}"#]);
+        let request = GenerateRequest::new("prompt");
+        let (parsed, repair) = extract_or_repair::<Reply>(&llm, &request, "broken")
+            .await
+            .unwrap();
+        assert_eq!(parsed, reply("a", &[]));
+        assert!(repair.is_some());
+
+        let (_, repair) =
+            extract_or_repair::<Reply>(&llm, &request, r#"{"name": "b", "tags": []}"#)
+                .await
+                .unwrap();
+        assert!(repair.is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_repair_keeps_the_generation() {
+        let llm = MockBackend::with_replies(["still broken"]);
+        let request = GenerateRequest::new("prompt");
+        match extract_or_repair::<Reply>(&llm, &request, "broken").await {
+            Err(RepairError::Invalid(_, generation)) => assert_eq!(generation.text, "still broken"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn code_fence_helpers() {
+        assert_eq!(strip_code_fence("no fence"), "no fence");
+        assert_eq!(strip_code_fence("```json\n{}\n```"), "{}\n");
+        assert_eq!(strip_code_fence("```\n{}"), "{}");
+        assert_eq!(outermost_object("a {\"b\": {}} c"), Some("{\"b\": {}}"));
+        assert_eq!(outermost_object("no object"), None);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::generate::error_event;
+use crate::llm::{self, LlmError};
+use crate::models::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use tracing::{error, info, warn};
+
+/// Manages the models installed on the Ollama server (`/api/tags`,
+/// `/api/show`, `/api/pull` and `/api/delete`). This is independent of the
+/// selected `LLM_BACKEND`, only Ollama offers these endpoints.
+pub struct ModelRegistry {
+    client: Client,
+    base_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub digest: String,
+    #[serde(default)]
+    pub modified_at: Option<String>,
+    #[serde(default)]
+    pub details: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+    pub default_model: String,
+    pub models: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullParams {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+}
+
+/// One line of the `/api/pull` progress stream.
+#[derive(Debug, Serialize, Deserialize)]
+struct PullProgress {
+    #[serde(default)]
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ModelRegistry {
+    pub fn new(client: Client, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn send<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<reqwest::Response, LlmError> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        llm::check_status(request.send().await?).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<ModelInfo>, LlmError> {
+        let response = self.send::<()>(Method::GET, "/api/tags", None).await?;
+        let tags: TagsResponse = response.json().await?;
+        Ok(tags.models)
+    }
+
+    pub async fn show(&self, name: &str) -> Result<Value, LlmError> {
+        let body = ModelRequest {
+            model: name,
+            stream: None,
+        };
+        let response = self.send(Method::POST, "/api/show", Some(&body)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), LlmError> {
+        let body = ModelRequest {
+            model: name,
+            stream: None,
+        };
+        self.send(Method::DELETE, "/api/delete", Some(&body))
+            .await?;
+        Ok(())
+    }
+
+    async fn pull(
+        &self,
+        name: &str,
+    ) -> Result<impl Stream<Item = Result<PullProgress, LlmError>>, LlmError> {
+        let body = ModelRequest {
+            model: name,
+            stream: Some(true),
+        };
+        let response = self.send(Method::POST, "/api/pull", Some(&body)).await?;
+        Ok(llm::lines(response.bytes_stream()).map(|line| {
+            serde_json::from_str::<PullProgress>(&line?)
+                .map_err(|e| LlmError::Decode(e.to_string()))
+        }))
+    }
+
+    /// Warns about configured models that are not installed, so a typo in
+    /// `DEFAULT_MODEL` shows up at startup instead of on the first request.
+    pub async fn check_installed(&self, models: &[&str]) {
+        let installed = match self.list().await {
+            Ok(installed) => installed,
+            Err(err) => {
+                warn!(%err, "could not list the installed models");
+                return;
+            }
+        };
+        for model in models {
+            if installed.iter().any(|info| same_model(&info.name, model)) {
+                info!("model {} is installed", model);
+            } else {
+                warn!(
+                    "model {} is not installed, pull it with `POST /models` or `ollama pull {}`",
+                    model, model
+                );
+            }
+        }
+    }
+}
+
+/// Ollama treats a name without tag as `:latest`.
+fn same_model(a: &str, b: &str) -> bool {
+    fn with_tag(name: &str) -> String {
+        if name.contains(':') {
+            name.to_string()
+        } else {
+            format!("{}:latest", name)
+        }
+    }
+    with_tag(a) == with_tag(b)
+}
+
+fn error_response(err: LlmError) -> (StatusCode, String) {
+    match err {
+        LlmError::Status { status: 404, body } => (StatusCode::NOT_FOUND, body),
+        err => (StatusCode::BAD_GATEWAY, err.to_string()),
+    }
+}
+
+pub async fn list_models(State(state): State<AppState>) -> impl IntoResponse {
+    match state.model_registry.list().await {
+        Ok(models) => (
+            StatusCode::OK,
+            Json(ModelList {
+                default_model: state.llm.default_model().to_string(),
+                models,
+            }),
+        )
+            .into_response(),
+        Err(err) => {
+            error!(%err, "Failed to list models");
+            error_response(err).into_response()
+        }
+    }
+}
+
+pub async fn show_model(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.model_registry.show(&name).await {
+        Ok(details) => (StatusCode::OK, Json(details)).into_response(),
+        Err(err) => {
+            error!(%err, "Failed to show model {}", name);
+            error_response(err).into_response()
+        }
+    }
+}
+
+pub async fn delete_model(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.model_registry.delete(&name).await {
+        Ok(()) => {
+            info!("Deleted model {}", name);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(err) => {
+            error!(%err, "Failed to delete model {}", name);
+            error_response(err).into_response()
+        }
+    }
+}
+
+/// Pulls a model and streams the download progress as Server-Sent Events:
+/// a `progress` event per status update from Ollama, followed by a `done`
+/// event, or an `error` event if the pull fails.
+pub async fn pull_model(
+    State(state): State<AppState>,
+    Json(params): Json<PullParams>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    info!("Pulling model {}", params.name);
+    let progress = match state.model_registry.pull(&params.name).await {
+        Ok(progress) => progress.boxed(),
+        Err(err) => stream::once(async move { Err(err) }).boxed(),
+    };
+
+    let name = params.name;
+    let events = progress.map(move |progress| {
+        let event = match progress {
+            Ok(PullProgress {
+                error: Some(message),
+                ..
+            }) => {
+                error!("Failed to pull model {}: {}", name, message);
+                error_event(&message)
+            }
+            Ok(progress) if progress.status == "success" => {
+                info!("Pulled model {}", name);
+                Event::default()
+                    .event("done")
+                    .data(serde_json::json!({ "model": name }).to_string())
+            }
+            Ok(progress) => Event::default()
+                .event("progress")
+                .data(serde_json::to_string(&progress).unwrap_or_default()),
+            Err(err) => {
+                error!(%err, "Failed to pull model {}", name);
+                error_event(&err.to_string())
+            }
+        };
+        Ok(event)
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
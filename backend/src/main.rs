@@ -1,7 +1,30 @@
+mod analyses;
+mod ask;
+mod chunking;
 mod config;
+mod digests;
+mod embeddings;
+mod entities;
+mod generate;
+mod health;
+mod jobs;
+mod llm;
+mod llm_json;
+mod llm_models;
+mod mock_llm;
 mod models;
+mod mood;
 mod notes;
 mod ollama;
+mod openai;
+mod prompts;
+mod resilience;
+mod runs;
+mod scheduler;
+mod search;
+mod tasks;
+#[cfg(test)]
+mod testing;
 
 use anyhow::{Context, Result};
 use axum::{
@@ -10,6 +33,7 @@ use axum::{
     routing::{delete, get, post, put},
     Router,
 };
+use clap::{Parser, Subcommand};
 use config::Config;
 use models::AppState;
 use sqlx::sqlite::SqlitePoolOptions;
@@ -30,12 +54,71 @@ async fn initialize_database(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<()> {
                 .with_context(|| format!("Failed to execute SQL statement: {}", statement))?;
         }
     }
+    apply_column_migrations(pool).await?;
+    notes::backfill_content_hashes(pool)
+        .await
+        .context("Failed to hash the note contents")?;
     info!("Database schema initialized successfully");
     Ok(())
 }
 
+// Columns added to tables after their first release. `CREATE TABLE IF NOT EXISTS`
+// leaves existing databases untouched, so these are added explicitly when missing.
+const COLUMN_MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("llm_categories", "explanation", "TEXT NOT NULL DEFAULT ''"),
+    ("notes", "content_hash", "TEXT"),
+    ("notes", "analysis_hash", "TEXT"),
+    ("analyses", "content_hash", "TEXT"),
+];
+
+async fn apply_column_migrations(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<()> {
+    for (table, column, definition) in COLUMN_MIGRATIONS {
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM pragma_table_info(?) WHERE name = ?)")
+                .bind(table)
+                .bind(column)
+                .fetch_one(pool)
+                .await
+                .with_context(|| format!("Failed to inspect table {}", table))?;
+
+        if !exists {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, definition
+            ))
+            .execute(pool)
+            .await
+            .with_context(|| format!("Failed to add column {}.{}", table, column))?;
+            info!("Added column {}.{}", table, column);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Parser)]
+#[command(about = "Backend of the mindfulnotes diary")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API (the default)
+    Serve,
+    /// Embed the notes whose embedding is missing or outdated, e.g. after
+    /// changing EMBEDDING_MODEL
+    Reindex {
+        /// Embed every note, even if its embedding is up to date
+        #[arg(long)]
+        all: bool,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Load .env file
     dotenv::dotenv().ok();
 
@@ -87,24 +170,156 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to initialize database schema")?;
 
+    let llm = llm::backend_from_config(&config).context("Failed to set up LLM backend")?;
+    info!(
+        "LLM backend {} with default model {}",
+        llm.name(),
+        llm.default_model()
+    );
+
+    if let Some(Command::Reindex { all }) = cli.command {
+        let embedded = embeddings::reindex(llm.as_ref(), &pool, &config.embedding_model, all)
+            .await
+            .context("Failed to reindex the note embeddings")?;
+        info!("Reindexed {} notes", embedded);
+        return Ok(());
+    }
+
+    let model_registry = Arc::new(llm_models::ModelRegistry::new(
+        reqwest::Client::new(),
+        &config.ollama_url,
+    ));
+    if config.llm_backend == "ollama" {
+        let mut models = vec![
+            config.default_model.as_str(),
+            config.embedding_model.as_str(),
+        ];
+        for defaults in [
+            &config.analysis_defaults,
+            &config.categorization_defaults,
+            &config.ask_defaults,
+            &config.digest_defaults,
+            &config.mood_defaults,
+            &config.entity_defaults,
+            &config.task_defaults,
+        ] {
+            if let Some(model) = defaults.model.as_deref() {
+                if !models.contains(&model) {
+                    models.push(model);
+                }
+            }
+        }
+        model_registry.check_installed(&models).await;
+    }
+
     let state = AppState {
-        client: Arc::new(reqwest::Client::new()),
-        ollama_url: config.ollama_url.clone(),
-        default_model: config.default_model.clone(),
+        llm,
+        model_registry,
+        jobs: Arc::default(),
+        scheduler: Arc::new(scheduler::Scheduler::new(config.llm_concurrency)),
         pool: Arc::new(pool),
         detailed_diary_analysis_prompt: config.detailed_diary_analysis_prompt.clone(),
         diary_categorization_prompt: config.diary_categorization_prompt.clone(),
+        ask_diary_prompt: config.ask_diary_prompt.clone(),
+        chunk_analysis_prompt: config.chunk_analysis_prompt.clone(),
+        analysis_synthesis_prompt: config.analysis_synthesis_prompt.clone(),
+        digest_prompt: config.digest_prompt.clone(),
+        mood_prompt: config.mood_prompt.clone(),
+        entity_prompt: config.entity_prompt.clone(),
+        task_prompt: config.task_prompt.clone(),
+        analysis_defaults: config.analysis_defaults.clone(),
+        categorization_defaults: config.categorization_defaults.clone(),
+        ask_defaults: config.ask_defaults.clone(),
+        digest_defaults: config.digest_defaults.clone(),
+        mood_defaults: config.mood_defaults.clone(),
+        entity_defaults: config.entity_defaults.clone(),
+        task_defaults: config.task_defaults.clone(),
+        embedding_model: config.embedding_model.clone(),
+        embedding_index: Arc::default(),
+        context_window: config.context_window,
     };
+    prompts::check_configured(&state).context("Failed to load configuration")?;
+
+    jobs::start_workers(state.clone(), config.job_workers)
+        .await
+        .context("Failed to start job workers")?;
+    digests::start_schedule(state.clone(), config.digest_schedule.clone());
 
     let app = Router::new()
-        .route("/generate", get(ollama::generate_handler))
+        .route("/health", get(health::health))
+        .route("/generate", get(generate::generate_handler))
+        .route("/generate", post(generate::generate_post_handler))
         .route("/notes", post(notes::create_note))
         .route("/notes", get(notes::list_notes))
         .route("/notes/:id", get(notes::get_note))
         .route("/notes/:id", put(notes::update_note))
         .route("/notes/:id", delete(notes::delete_note))
         .route("/notes/:id/analyze", post(notes::analyze_note))
-        .route("/notes/:id/categoryze", post(notes::categorize_note))
+        // GET as well, so the stream can be consumed with `EventSource`
+        .route(
+            "/notes/:id/analyze/stream",
+            get(notes::analyze_note_stream).post(notes::analyze_note_stream),
+        )
+        .route("/notes/:id/analyses", get(analyses::list_analyses))
+        .route("/notes/:id/analyses/diff", get(analyses::diff_analyses))
+        .route(
+            "/notes/:id/analyses/:analysis_id",
+            get(analyses::get_analysis),
+        )
+        .route(
+            "/notes/:id/analyses/:analysis_id/pin",
+            post(analyses::pin_analysis),
+        )
+        .route("/notes/:id/categorize", post(notes::categorize_note))
+        .route("/notes/:id/categories", get(notes::get_note_llm_categories))
+        .route("/notes/:id/similar", get(search::similar_notes))
+        .route("/notes/:id/mood", get(mood::get_note_mood))
+        .route("/notes/:id/mood", post(mood::score_note))
+        .route("/notes/:id/entities", get(entities::get_note_entities))
+        .route("/notes/:id/entities", post(entities::extract_note_entities))
+        .route("/notes/:id/tasks", get(tasks::get_note_tasks))
+        .route("/notes/:id/tasks", post(tasks::extract_note_tasks))
+        .route("/mood/timeline", get(mood::timeline))
+        .route("/entities", get(entities::list_entities))
+        .route("/entities/:id/notes", get(entities::entity_notes))
+        .route("/entities/:id/merge", post(entities::merge_entity))
+        .route("/search/semantic", get(search::search_notes))
+        .route("/ask", post(ask::ask))
+        .route("/categories", get(notes::list_categories))
+        .route("/digests", get(digests::list_digests))
+        .route("/digests", post(digests::create_digest))
+        .route("/digests/:id", get(digests::get_digest))
+        .route("/digests/:id/regenerate", post(digests::regenerate_digest))
+        .route("/tasks", get(tasks::list_tasks))
+        .route("/tasks", post(tasks::create_task))
+        .route("/tasks/:id", get(tasks::get_task))
+        .route("/tasks/:id", put(tasks::update_task))
+        .route("/tasks/:id", delete(tasks::delete_task))
+        .route("/prompts", get(prompts::list_prompts))
+        .route("/prompts/:name", get(prompts::get_prompt))
+        .route("/prompts/:name", put(prompts::update_prompt))
+        .route("/prompts/:name", delete(prompts::delete_prompt))
+        .route("/prompts/:name/versions", get(prompts::list_versions))
+        .route(
+            "/prompts/:name/versions/:version",
+            get(prompts::get_version),
+        )
+        .route(
+            "/prompts/:name/versions/:version",
+            delete(prompts::delete_version),
+        )
+        .route("/prompts/:name/render", post(prompts::render_prompt))
+        .route("/profile", get(prompts::get_profile))
+        .route("/profile", put(prompts::update_profile))
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/jobs/:id", delete(jobs::cancel_job))
+        .route("/llm/runs", get(runs::list_runs))
+        .route("/llm/queue", get(scheduler::queue_status))
+        .route("/models", get(llm_models::list_models))
+        .route("/models", post(llm_models::pull_model))
+        // model names may contain a namespace, e.g. `library/llama3.2:3b`
+        .route("/models/*name", get(llm_models::show_model))
+        .route("/models/*name", delete(llm_models::delete_model))
         .layer(TraceLayer::new_for_http().on_body_chunk(
             |chunk: &axum::body::Bytes, _latency: std::time::Duration, _span: &Span| {
                 debug!("streaming {} bytes", chunk.len());
@@ -115,8 +330,6 @@ async fn main() -> Result<()> {
 
     let listener = tokio::net::TcpListener::bind(&config.listen_addr).await?;
     info!("listening on {}", config.listen_addr);
-    info!("ollama URL {}", config.ollama_url);
-    info!("default model {}", config.default_model);
 
     axum::serve(listener, app).await?;
     Ok(())
@@ -0,0 +1,146 @@
+//! In-memory LLM backend that plays back scripted replies, so the handlers can
+//! be exercised without a model server (`LLM_BACKEND=mock`).
+
+use crate::llm::{
+    ChatRequest, GenerateRequest, Generation, LlmBackend, LlmError, StreamEvent, TokenStream, Usage,
+};
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+};
+use tracing::debug;
+
+const MOCK_MODEL: &str = "mock";
+const DEFAULT_REPLY: &str = "This is a mock response.";
+const MOCK_DIMENSIONS: usize = 64;
+
+/// Replies are consumed in order, one per call. Once the script is exhausted
+/// the last reply is repeated.
+pub struct MockBackend {
+    replies: Mutex<VecDeque<String>>,
+    last_reply: Mutex<String>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::with_replies(Vec::<String>::new())
+    }
+}
+
+impl MockBackend {
+    pub fn with_replies<I, S>(replies: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            replies: Mutex::new(replies.into_iter().map(Into::into).collect()),
+            last_reply: Mutex::new(DEFAULT_REPLY.to_string()),
+        }
+    }
+
+    /// Loads the replies from a JSON array of strings.
+    pub fn from_script_file(path: &str) -> anyhow::Result<Self> {
+        let script = std::fs::read_to_string(path)?;
+        let replies: Vec<String> = serde_json::from_str(&script)?;
+        Ok(Self::with_replies(replies))
+    }
+
+    fn next_reply(&self, prompt: &str) -> String {
+        debug!("mock LLM prompt: {}", prompt);
+        let mut last_reply = self.last_reply.lock().unwrap();
+        if let Some(reply) = self.replies.lock().unwrap().pop_front() {
+            *last_reply = reply;
+        }
+        last_reply.clone()
+    }
+
+    fn generation(&self, prompt: &str) -> Generation {
+        let text = self.next_reply(prompt);
+        let usage = Usage {
+            prompt_tokens: count_tokens(prompt),
+            completion_tokens: count_tokens(&text),
+            ..Default::default()
+        };
+        Generation {
+            text,
+            model: MOCK_MODEL.to_string(),
+            usage,
+        }
+    }
+}
+
+fn count_tokens(text: &str) -> u64 {
+    text.split_whitespace().count() as u64
+}
+
+/// Hashes the words of `text` into a normalized vector, texts sharing words
+/// end up close to each other.
+fn bag_of_words(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; MOCK_DIMENSIONS];
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        if word.is_empty() {
+            continue;
+        }
+        let mut hasher = DefaultHasher::new();
+        word.to_lowercase().hash(&mut hasher);
+        vector[hasher.finish() as usize % MOCK_DIMENSIONS] += 1.0;
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+#[async_trait]
+impl LlmBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn default_model(&self) -> &str {
+        MOCK_MODEL
+    }
+
+    async fn generate(&self, request: GenerateRequest) -> Result<Generation, LlmError> {
+        Ok(self.generation(&request.prompt))
+    }
+
+    async fn stream(&self, request: GenerateRequest) -> Result<TokenStream, LlmError> {
+        let generation = self.generation(&request.prompt);
+        let tokens: Vec<_> = generation
+            .text
+            .split_inclusive(' ')
+            .map(|token| Ok(StreamEvent::Token(token.to_string())))
+            .collect();
+        let done = Ok(StreamEvent::Done {
+            model: generation.model,
+            usage: generation.usage,
+        });
+        Ok(stream::iter(tokens)
+            .chain(stream::once(async { done }))
+            .boxed())
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<Generation, LlmError> {
+        let prompt = request
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(self.generation(&prompt))
+    }
+
+    async fn embed(&self, _model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        Ok(input.iter().map(|text| bag_of_words(text)).collect())
+    }
+
+    async fn health(&self) -> Result<(), LlmError> {
+        Ok(())
+    }
+}
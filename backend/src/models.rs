@@ -1,20 +1,95 @@
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use crate::config::TaskDefaults;
+use crate::jobs::JobQueue;
+use crate::llm::{GenerateRequest, GenerationOptions, LlmBackend};
+use crate::llm_models::ModelRegistry;
+use crate::scheduler::Scheduler;
+use crate::search::EmbeddingIndex;
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::SqlitePool;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub client: Arc<Client>,
-    pub ollama_url: String,
-    pub default_model: String,
+    pub llm: Arc<dyn LlmBackend>,
+    pub model_registry: Arc<ModelRegistry>,
+    pub jobs: Arc<JobQueue>,
+    pub scheduler: Arc<Scheduler>,
     pub pool: Arc<SqlitePool>,
     pub detailed_diary_analysis_prompt: String,
     pub diary_categorization_prompt: String,
+    pub ask_diary_prompt: String,
+    pub chunk_analysis_prompt: String,
+    pub analysis_synthesis_prompt: String,
+    pub digest_prompt: String,
+    pub mood_prompt: String,
+    pub entity_prompt: String,
+    pub task_prompt: String,
+    pub analysis_defaults: TaskDefaults,
+    pub categorization_defaults: TaskDefaults,
+    pub ask_defaults: TaskDefaults,
+    pub digest_defaults: TaskDefaults,
+    pub mood_defaults: TaskDefaults,
+    pub entity_defaults: TaskDefaults,
+    pub task_defaults: TaskDefaults,
+    pub embedding_model: String,
+    pub embedding_index: Arc<EmbeddingIndex>,
+    pub context_window: usize,
 }
 
+// The options are listed explicitly instead of flattening `GenerationOptions`
+// because flattened numbers cannot be parsed from a query string.
 #[derive(Deserialize, Debug, Serialize, Clone)]
 pub struct GenerateParams {
     pub prompt: String,
     pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub num_ctx: Option<u32>,
+    pub seed: Option<i64>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub stop: Option<Vec<String>>,
+    pub keep_alive: Option<String>,
+}
+
+impl GenerateParams {
+    pub fn into_request(self) -> GenerateRequest {
+        let options = GenerationOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            num_ctx: self.num_ctx,
+            seed: self.seed,
+            stop: self.stop,
+            keep_alive: self.keep_alive,
+        };
+        GenerateRequest::new(self.prompt)
+            .with_model(self.model)
+            .with_options(options)
+    }
+}
+
+/// Accepts a single string as well as a list, so `?stop=x` works in a query.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(value)) => Some(vec![value]),
+        Some(OneOrMany::Many(values)) => Some(values),
+        None => None,
+    })
+}
+
+/// Optional body of the endpoints running a model over a note, overriding
+/// the configured task defaults.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct LlmParams {
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub options: GenerationOptions,
 }
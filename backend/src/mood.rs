@@ -0,0 +1,857 @@
+//! Mood of the notes: valence, arousal and the emotions expressed, scored by
+//! the model with structured output or, when no model is available, by a
+//! small built-in lexicon. The scores feed the mood timeline.
+
+use crate::llm_json;
+use crate::models::{AppState, LlmParams};
+use crate::notes::content_hash;
+use crate::prompts::{self, PreparedPrompt, PromptName};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{sqlite::SqlitePool, types::Json as SqlJson, FromRow, QueryBuilder, Sqlite};
+use std::collections::BTreeMap;
+use tracing::{error, info, warn};
+
+const MOOD_COLUMNS: &str = "note_id, valence, arousal, emotions, source, model, prompt_version, \
+                            content_hash, created_at";
+
+/// Emotions listed per note at most.
+const MAX_EMOTIONS: usize = 5;
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Emotion {
+    Joy,
+    Gratitude,
+    Calm,
+    Hope,
+    Love,
+    Pride,
+    Sadness,
+    Loneliness,
+    Anger,
+    Frustration,
+    Fear,
+    Anxiety,
+    Shame,
+    Surprise,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmotionScore {
+    pub name: Emotion,
+    /// From 0, barely present, to 1, dominant.
+    pub intensity: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum MoodSource {
+    Llm,
+    Lexicon,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct NoteMood {
+    pub note_id: i64,
+    /// From -1, very unpleasant, to 1, very pleasant.
+    pub valence: f32,
+    /// From -1, very calm, to 1, very activated.
+    pub arousal: f32,
+    pub emotions: SqlJson<Vec<EmotionScore>>,
+    pub source: MoodSource,
+    pub model: Option<String>,
+    pub prompt_version: Option<String>,
+    /// Hash of the content the mood was scored from.
+    pub content_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+struct Mood {
+    valence: f32,
+    arousal: f32,
+    emotions: Vec<EmotionScore>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct MoodResponse {
+    #[schemars(range(min = -1, max = 1))]
+    valence: f32,
+    #[schemars(range(min = -1, max = 1))]
+    arousal: f32,
+    #[schemars(length(max = 5))]
+    emotions: Vec<EmotionItem>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct EmotionItem {
+    // Unknown emotions are dropped, the schema restricts the model to the
+    // predefined ones
+    #[schemars(with = "Emotion")]
+    name: String,
+    #[schemars(range(min = 0, max = 1))]
+    intensity: f32,
+}
+
+impl From<MoodResponse> for Mood {
+    fn from(response: MoodResponse) -> Self {
+        let mut emotions: Vec<EmotionScore> = Vec::new();
+        for item in response.emotions {
+            let name = Value::String(item.name.trim().to_lowercase());
+            let Ok(name) = serde_json::from_value::<Emotion>(name) else {
+                continue;
+            };
+            if !emotions.iter().any(|emotion| emotion.name == name) {
+                emotions.push(EmotionScore {
+                    name,
+                    intensity: item.intensity.clamp(0.0, 1.0),
+                });
+            }
+        }
+        emotions.sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+        emotions.truncate(MAX_EMOTIONS);
+        Mood {
+            valence: response.valence.clamp(-1.0, 1.0),
+            arousal: response.arousal.clamp(-1.0, 1.0),
+            emotions,
+        }
+    }
+}
+
+/// Words with their valence, arousal and emotion. A trailing `*` matches any
+/// word starting with the stem, the longest match wins.
+const LEXICON: &[(&str, f32, f32, Option<Emotion>)] = &[
+    ("happy", 0.8, 0.4, Some(Emotion::Joy)),
+    ("happi*", 0.8, 0.4, Some(Emotion::Joy)),
+    ("joy*", 0.9, 0.5, Some(Emotion::Joy)),
+    ("glad", 0.7, 0.3, Some(Emotion::Joy)),
+    ("wonderful", 0.8, 0.5, Some(Emotion::Joy)),
+    ("excit*", 0.7, 0.9, Some(Emotion::Joy)),
+    ("fun", 0.7, 0.6, Some(Emotion::Joy)),
+    ("laugh*", 0.7, 0.6, Some(Emotion::Joy)),
+    ("enjoy*", 0.7, 0.4, Some(Emotion::Joy)),
+    ("grateful", 0.8, 0.1, Some(Emotion::Gratitude)),
+    ("gratitude", 0.8, 0.1, Some(Emotion::Gratitude)),
+    ("thank*", 0.6, 0.1, Some(Emotion::Gratitude)),
+    ("calm*", 0.5, -0.7, Some(Emotion::Calm)),
+    ("relax*", 0.6, -0.7, Some(Emotion::Calm)),
+    ("peace*", 0.7, -0.6, Some(Emotion::Calm)),
+    // Not "content", which is far more often the noun
+    ("contented", 0.6, -0.4, Some(Emotion::Calm)),
+    ("contentment", 0.6, -0.4, Some(Emotion::Calm)),
+    ("hope*", 0.5, 0.2, Some(Emotion::Hope)),
+    ("hopeless*", -0.8, -0.5, Some(Emotion::Sadness)),
+    ("optimis*", 0.6, 0.3, Some(Emotion::Hope)),
+    ("love*", 0.8, 0.5, Some(Emotion::Love)),
+    ("loving", 0.8, 0.4, Some(Emotion::Love)),
+    ("proud", 0.7, 0.5, Some(Emotion::Pride)),
+    ("accomplish*", 0.7, 0.4, Some(Emotion::Pride)),
+    ("success*", 0.7, 0.5, Some(Emotion::Pride)),
+    ("sad", -0.7, -0.4, Some(Emotion::Sadness)),
+    ("sadly", -0.6, -0.4, Some(Emotion::Sadness)),
+    ("sadness", -0.7, -0.4, Some(Emotion::Sadness)),
+    ("cry", -0.6, 0.3, Some(Emotion::Sadness)),
+    ("crying", -0.6, 0.3, Some(Emotion::Sadness)),
+    ("cried", -0.6, 0.3, Some(Emotion::Sadness)),
+    ("grie*", -0.8, -0.2, Some(Emotion::Sadness)),
+    ("depress*", -0.8, -0.6, Some(Emotion::Sadness)),
+    ("lonel*", -0.7, -0.3, Some(Emotion::Loneliness)),
+    ("alone", -0.4, -0.3, Some(Emotion::Loneliness)),
+    ("isolat*", -0.6, -0.3, Some(Emotion::Loneliness)),
+    ("angry", -0.7, 0.8, Some(Emotion::Anger)),
+    ("anger*", -0.7, 0.8, Some(Emotion::Anger)),
+    ("furious", -0.8, 0.9, Some(Emotion::Anger)),
+    ("mad", -0.6, 0.7, Some(Emotion::Anger)),
+    ("frustrat*", -0.6, 0.6, Some(Emotion::Frustration)),
+    ("annoy*", -0.5, 0.5, Some(Emotion::Frustration)),
+    ("irritat*", -0.5, 0.5, Some(Emotion::Frustration)),
+    ("anxi*", -0.7, 0.7, Some(Emotion::Anxiety)),
+    ("stress*", -0.6, 0.7, Some(Emotion::Anxiety)),
+    ("worr*", -0.6, 0.6, Some(Emotion::Anxiety)),
+    ("nervous*", -0.5, 0.7, Some(Emotion::Anxiety)),
+    ("panic*", -0.8, 0.9, Some(Emotion::Anxiety)),
+    ("overwhelm*", -0.6, 0.7, Some(Emotion::Anxiety)),
+    ("afraid", -0.7, 0.6, Some(Emotion::Fear)),
+    ("scared", -0.7, 0.7, Some(Emotion::Fear)),
+    // Not "fear*", which would match "fearless"
+    ("fear", -0.7, 0.6, Some(Emotion::Fear)),
+    ("fears", -0.7, 0.6, Some(Emotion::Fear)),
+    ("feared", -0.7, 0.6, Some(Emotion::Fear)),
+    ("fearful*", -0.7, 0.6, Some(Emotion::Fear)),
+    ("terrif*", -0.8, 0.8, Some(Emotion::Fear)),
+    ("asham*", -0.7, 0.3, Some(Emotion::Shame)),
+    ("embarrass*", -0.6, 0.5, Some(Emotion::Shame)),
+    ("guilt*", -0.6, 0.3, Some(Emotion::Shame)),
+    ("surpris*", 0.2, 0.7, Some(Emotion::Surprise)),
+    ("shock*", -0.3, 0.8, Some(Emotion::Surprise)),
+    ("tired", -0.4, -0.7, None),
+    ("exhaust*", -0.5, -0.6, None),
+    ("bored*", -0.4, -0.7, None),
+    ("sick", -0.5, -0.2, None),
+    ("bad", -0.5, 0.2, None),
+    ("awful", -0.7, 0.4, None),
+    ("terrible", -0.8, 0.5, None),
+    ("good", 0.5, 0.2, None),
+    ("great", 0.6, 0.4, None),
+    ("nice", 0.5, 0.1, None),
+    ("energ*", 0.4, 0.7, None),
+];
+
+/// Words flipping the meaning of the next few words, e.g. "not happy". "No"
+/// is left out, it rarely negates a feeling ("no doubt", "no wonder").
+const NEGATIONS: &[&str] = &[
+    "not", "never", "hardly", "without", "don't", "didn't", "isn't", "wasn't", "can't", "couldn't",
+    "won't",
+];
+
+fn lookup(word: &str) -> Option<(f32, f32, Option<Emotion>)> {
+    LEXICON
+        .iter()
+        .filter(|(entry, ..)| match entry.strip_suffix('*') {
+            Some(stem) => word.starts_with(stem),
+            None => word == *entry,
+        })
+        .max_by_key(|(entry, ..)| entry.len())
+        .map(|(_, valence, arousal, emotion)| (*valence, *arousal, *emotion))
+}
+
+/// Scores `text` by averaging the lexicon words it contains. Negated words
+/// count half with the opposite valence and without their emotion.
+fn score_with_lexicon(text: &str) -> Mood {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '\u{2019}')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase().replace('\u{2019}', "'"))
+        .collect();
+
+    let mut matched = 0;
+    let mut valence = 0.0;
+    let mut arousal = 0.0;
+    let mut counts: BTreeMap<Emotion, usize> = BTreeMap::new();
+    for (index, word) in words.iter().enumerate() {
+        let Some((word_valence, word_arousal, emotion)) = lookup(word) else {
+            continue;
+        };
+        let negated = words[index.saturating_sub(3)..index]
+            .iter()
+            .any(|previous| NEGATIONS.contains(&previous.as_str()));
+        matched += 1;
+        arousal += word_arousal;
+        if negated {
+            valence -= word_valence / 2.0;
+        } else {
+            valence += word_valence;
+            if let Some(emotion) = emotion {
+                *counts.entry(emotion).or_default() += 1;
+            }
+        }
+    }
+    if matched == 0 {
+        return Mood {
+            valence: 0.0,
+            arousal: 0.0,
+            emotions: Vec::new(),
+        };
+    }
+
+    let mut emotions: Vec<EmotionScore> = counts
+        .into_iter()
+        .map(|(name, count)| EmotionScore {
+            name,
+            intensity: (count as f32 / matched as f32).min(1.0),
+        })
+        .collect();
+    emotions.sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+    emotions.truncate(MAX_EMOTIONS);
+    Mood {
+        valence: (valence / matched as f32).clamp(-1.0, 1.0),
+        arousal: (arousal / matched as f32).clamp(-1.0, 1.0),
+        emotions,
+    }
+}
+
+/// Asks the model for the mood of the note. Returns the mood and the model
+/// that scored it, or why the model could not be used.
+async fn score_with_model(
+    state: &AppState,
+    id: i64,
+    prompt: &PreparedPrompt,
+    content: &str,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<(Mood, String), String> {
+    let request = state
+        .mood_defaults
+        .request(prompt.render(content), params)
+        .with_format(llm_json::schema_for::<MoodResponse>());
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let record = |run: NewRun| record_run(&state.pool, run.prompt_version(&prompt.version));
+
+    let _ticket = state.scheduler.acquire(priority).await;
+    let max_attempts = 2;
+    for attempt in 1..=max_attempts {
+        let generation = match state.llm.generate(request.clone()).await {
+            Ok(generation) => {
+                record(NewRun::success(RunKind::Mood, Some(id), &generation)).await;
+                generation
+            }
+            Err(e) => {
+                record(NewRun::failure(RunKind::Mood, Some(id), &model, &e)).await;
+                return Err(e.to_string());
+            }
+        };
+
+        match llm_json::extract_or_repair::<MoodResponse>(
+            state.llm.as_ref(),
+            &request,
+            &generation.text,
+        )
+        .await
+        {
+            Ok((response, repair)) => {
+                if let Some(repair) = repair {
+                    info!("Repaired mood JSON for note {}", id);
+                    record(NewRun::success(RunKind::Mood, Some(id), &repair)).await;
+                }
+                return Ok((response.into(), generation.model));
+            }
+            Err(e) => {
+                record(NewRun::repair_failure(RunKind::Mood, Some(id), &model, &e)).await;
+                error!(
+                    "Mood scoring attempt {} for note {} returned invalid JSON: {}",
+                    attempt, id, e
+                );
+            }
+        }
+    }
+    Err("no valid mood JSON".to_string())
+}
+
+async fn fetch_mood(pool: &SqlitePool, note_id: i64) -> Result<Option<NoteMood>, sqlx::Error> {
+    sqlx::query_as::<_, NoteMood>(&format!(
+        "SELECT {} FROM note_moods WHERE note_id = ?",
+        MOOD_COLUMNS
+    ))
+    .bind(note_id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn store_mood(
+    pool: &SqlitePool,
+    note_id: i64,
+    mood: Mood,
+    source: MoodSource,
+    model: Option<String>,
+    prompt_version: Option<String>,
+    hash: String,
+) -> Result<NoteMood, sqlx::Error> {
+    sqlx::query_as::<_, NoteMood>(&format!(
+        "INSERT INTO note_moods (
+            note_id, valence, arousal, emotions, source, model, prompt_version,
+            content_hash, created_at
+         )
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(note_id) DO UPDATE SET
+            valence = excluded.valence,
+            arousal = excluded.arousal,
+            emotions = excluded.emotions,
+            source = excluded.source,
+            model = excluded.model,
+            prompt_version = excluded.prompt_version,
+            content_hash = excluded.content_hash,
+            created_at = excluded.created_at
+         RETURNING {}",
+        MOOD_COLUMNS
+    ))
+    .bind(note_id)
+    .bind(mood.valence)
+    .bind(mood.arousal)
+    .bind(SqlJson(&mood.emotions))
+    .bind(source)
+    .bind(model)
+    .bind(prompt_version)
+    .bind(hash)
+    .bind(Utc::now())
+    .fetch_one(pool)
+    .await
+}
+
+/// Scores the mood of note `id` and stores it. A stored score is kept if it
+/// came from the model and the note did not change since, unless `rescore`
+/// is set. Lexicon scores are replaced as soon as the model is back.
+pub async fn run_scoring(
+    state: &AppState,
+    id: i64,
+    rescore: bool,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<NoteMood, (StatusCode, String)> {
+    let content: Option<String> = sqlx::query_scalar("SELECT content FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&*state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch note {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+        })?;
+    let Some(content) = content else {
+        return Err((StatusCode::NOT_FOUND, "Note not found".to_string()));
+    };
+    let hash = content_hash(&content);
+
+    if !rescore {
+        match fetch_mood(&state.pool, id).await {
+            Ok(Some(mood)) if mood.source == MoodSource::Llm && mood.content_hash == hash => {
+                return Ok(mood);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to fetch mood of note {}: {}", id, e),
+        }
+    }
+
+    // A prompt that cannot be filled in is an error to fix, not a reason
+    // to fall back to the lexicon
+    let prompt = prompts::note_prompt(state, PromptName::Mood, id).await?;
+    let stored = match score_with_model(state, id, &prompt, &content, params, priority).await {
+        Ok((mood, model)) => {
+            store_mood(
+                &state.pool,
+                id,
+                mood,
+                MoodSource::Llm,
+                Some(model),
+                Some(prompt.version),
+                hash,
+            )
+            .await
+        }
+        Err(reason) => {
+            warn!(
+                "Scoring mood of note {} with the lexicon, the model failed: {}",
+                id, reason
+            );
+            let mood = score_with_lexicon(&content);
+            store_mood(&state.pool, id, mood, MoodSource::Lexicon, None, None, hash).await
+        }
+    };
+    stored.map_err(|e| {
+        error!("Failed to store mood of note {}: {}", id, e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store mood: {}", e),
+        )
+    })
+}
+
+/// Scores a created or updated note in the background.
+pub fn spawn_score_note(state: &AppState, note_id: i64) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err((_, message)) = run_scoring(&state, note_id, false, None, Priority::Batch).await
+        {
+            error!("Failed to score mood of note {}: {}", note_id, message);
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScoreParams {
+    /// Ask the model again even if the stored score is up to date.
+    pub rescore: Option<bool>,
+}
+
+pub async fn score_note(
+    Path(id): Path<i64>,
+    Query(params): Query<ScoreParams>,
+    State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let rescore = params.rescore.unwrap_or(false);
+    let llm_params = llm_params.map(|Json(params)| params);
+    match run_scoring(&state, id, rescore, llm_params, Priority::Interactive).await {
+        Ok(mood) => (StatusCode::OK, Json(mood)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+pub async fn get_note_mood(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let exists = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?)")
+        .bind(id)
+        .fetch_one(&*state.pool)
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "Note not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch note {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+                .into_response();
+        }
+    }
+    match fetch_mood(&state.pool, id).await {
+        Ok(Some(mood)) => (StatusCode::OK, Json(mood)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Mood not scored").into_response(),
+        Err(e) => {
+            error!("Failed to fetch mood of note {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch mood: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    /// Monday to Sunday.
+    Week,
+}
+
+impl Bucket {
+    fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => date,
+            Bucket::Week => date - Days::new(date.weekday().num_days_from_monday().into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimelineParams {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub bucket: Option<Bucket>,
+}
+
+#[derive(FromRow)]
+struct ScoredNote {
+    created_at: DateTime<Utc>,
+    valence: f32,
+    arousal: f32,
+    emotions: SqlJson<Vec<EmotionScore>>,
+}
+
+/// Mean mood over a set of notes.
+#[derive(Debug, Serialize)]
+pub struct MoodSummary {
+    /// Number of scored notes the means are taken over.
+    pub notes: usize,
+    pub valence: f32,
+    pub arousal: f32,
+    /// Mean intensity of each emotion, notes without the emotion counting
+    /// as 0.
+    pub emotions: BTreeMap<Emotion, f32>,
+}
+
+impl MoodSummary {
+    /// `None` if there are no scored notes.
+    fn of<'a>(scored: impl IntoIterator<Item = &'a ScoredNote>) -> Option<Self> {
+        let mut summary = MoodSummary {
+            notes: 0,
+            valence: 0.0,
+            arousal: 0.0,
+            emotions: BTreeMap::new(),
+        };
+        for note in scored {
+            summary.notes += 1;
+            summary.valence += note.valence;
+            summary.arousal += note.arousal;
+            for emotion in note.emotions.iter() {
+                *summary.emotions.entry(emotion.name).or_default() += emotion.intensity;
+            }
+        }
+        if summary.notes == 0 {
+            return None;
+        }
+        let notes = summary.notes as f32;
+        summary.valence /= notes;
+        summary.arousal /= notes;
+        summary.emotions.values_mut().for_each(|sum| *sum /= notes);
+        Some(summary)
+    }
+}
+
+/// Mean mood of the notes `note_ids`, `None` if none of them is scored.
+pub async fn summarize(
+    pool: &SqlitePool,
+    note_ids: &[i64],
+) -> Result<Option<MoodSummary>, sqlx::Error> {
+    if note_ids.is_empty() {
+        return Ok(None);
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT n.created_at, m.valence, m.arousal, m.emotions
+         FROM note_moods m
+         JOIN notes n ON n.id = m.note_id
+         WHERE m.note_id IN (",
+    );
+    let mut ids = query.separated(", ");
+    for id in note_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    let scored = query.build_query_as::<ScoredNote>().fetch_all(pool).await?;
+    Ok(MoodSummary::of(&scored))
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoodPoint {
+    /// First day of the bucket.
+    pub start: NaiveDate,
+    #[serde(flatten)]
+    pub mood: MoodSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoodTimeline {
+    pub bucket: Bucket,
+    /// Buckets without scored notes are left out.
+    pub points: Vec<MoodPoint>,
+    /// Notes in the range without a mood score yet.
+    pub unscored: i64,
+}
+
+fn push_range(query: &mut QueryBuilder<'_, Sqlite>, params: &TimelineParams) {
+    if let Some(from) = params.from {
+        query.push(" AND n.created_at >= ").push_bind(from);
+    }
+    if let Some(to) = params.to {
+        query.push(" AND n.created_at < ").push_bind(to);
+    }
+}
+
+async fn fetch_timeline(
+    pool: &SqlitePool,
+    params: &TimelineParams,
+) -> Result<MoodTimeline, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT n.created_at, m.valence, m.arousal, m.emotions
+         FROM note_moods m
+         JOIN notes n ON n.id = m.note_id
+         WHERE 1 = 1",
+    );
+    push_range(&mut query, params);
+    query.push(" ORDER BY n.created_at");
+    let scored = query.build_query_as::<ScoredNote>().fetch_all(pool).await?;
+
+    let mut unscored_query = QueryBuilder::<Sqlite>::new(
+        "SELECT COUNT(*)
+         FROM notes n
+         LEFT JOIN note_moods m ON m.note_id = n.id
+         WHERE m.note_id IS NULL",
+    );
+    push_range(&mut unscored_query, params);
+    let unscored = unscored_query
+        .build_query_scalar::<i64>()
+        .fetch_one(pool)
+        .await?;
+
+    let bucket = params.bucket.unwrap_or_default();
+    let points = scored
+        .chunk_by(|a, b| {
+            bucket.start_of(a.created_at.date_naive()) == bucket.start_of(b.created_at.date_naive())
+        })
+        .filter_map(|notes| {
+            Some(MoodPoint {
+                start: bucket.start_of(notes[0].created_at.date_naive()),
+                mood: MoodSummary::of(notes)?,
+            })
+        })
+        .collect();
+
+    Ok(MoodTimeline {
+        bucket,
+        points,
+        unscored,
+    })
+}
+
+/// Mean mood per day or week, `from` and `to` bounding the notes included.
+pub async fn timeline(
+    State(state): State<AppState>,
+    Query(params): Query<TimelineParams>,
+) -> impl IntoResponse {
+    match fetch_timeline(&state.pool, &params).await {
+        Ok(timeline) => (StatusCode::OK, Json(timeline)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch mood timeline: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch mood timeline: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn emotions(mood: &Mood) -> Vec<Emotion> {
+        mood.emotions.iter().map(|emotion| emotion.name).collect()
+    }
+
+    #[test]
+    fn stems_match_the_longest_entry() {
+        let emotion = |word: &str| lookup(word).and_then(|(_, _, emotion)| emotion);
+        assert_eq!(emotion("frustrating"), Some(Emotion::Frustration));
+        assert_eq!(emotion("happiness"), Some(Emotion::Joy));
+        assert_eq!(emotion("hopeful"), Some(Emotion::Hope));
+        assert_eq!(emotion("hopeless"), Some(Emotion::Sadness));
+        assert_eq!(emotion("fearful"), Some(Emotion::Fear));
+        assert_eq!(emotion("contented"), Some(Emotion::Calm));
+        assert!(lookup("fearless").is_none());
+        assert!(lookup("content").is_none());
+        // Exact entries do not match longer words
+        assert!(lookup("sadder").is_none());
+    }
+
+    #[test]
+    fn lexicon_averages_the_words() {
+        let mood = score_with_lexicon("Happy and sad, happy again.");
+        assert!((mood.valence - (0.8 - 0.7 + 0.8) / 3.0).abs() < 1e-6);
+        assert_eq!(emotions(&mood), [Emotion::Joy, Emotion::Sadness]);
+
+        let mood = score_with_lexicon("Went to the shop.");
+        assert_eq!((mood.valence, mood.arousal), (0.0, 0.0));
+        assert!(mood.emotions.is_empty());
+    }
+
+    #[test]
+    fn negation_flips_the_next_words() {
+        let mood = score_with_lexicon("I was not happy today.");
+        assert!((mood.valence + 0.4).abs() < 1e-6);
+        assert!(mood.emotions.is_empty());
+
+        let mood = score_with_lexicon("I didn\u{2019}t feel lonely.");
+        assert!(mood.valence > 0.0);
+        assert!(mood.emotions.is_empty());
+
+        // Three words later the negation no longer applies
+        let mood = score_with_lexicon("Not at all, today I was happy.");
+        assert!((mood.valence - 0.8).abs() < 1e-6);
+
+        let mood = score_with_lexicon("No doubt I was happy.");
+        assert!((mood.valence - 0.8).abs() < 1e-6);
+        assert_eq!(emotions(&mood), [Emotion::Joy]);
+    }
+
+    /// Inserts a note written at `created_at` scored with `valence`.
+    async fn insert_scored(pool: &SqlitePool, created_at: &str, valence: Option<f32>) {
+        let id = testing::insert_note(pool, "A day.", "personal").await;
+        let created_at: DateTime<Utc> = created_at.parse().unwrap();
+        sqlx::query("UPDATE notes SET created_at = ? WHERE id = ?")
+            .bind(created_at)
+            .bind(id)
+            .execute(pool)
+            .await
+            .unwrap();
+        if let Some(valence) = valence {
+            let mood = Mood {
+                valence,
+                arousal: 0.0,
+                emotions: vec![EmotionScore {
+                    name: Emotion::Joy,
+                    intensity: valence.max(0.0),
+                }],
+            };
+            store_mood(
+                pool,
+                id,
+                mood,
+                MoodSource::Lexicon,
+                None,
+                None,
+                String::new(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    fn points(timeline: &MoodTimeline) -> Vec<(String, usize, f32)> {
+        timeline
+            .points
+            .iter()
+            .map(|point| {
+                let valence = (point.mood.valence * 100.0).round() / 100.0;
+                (point.start.to_string(), point.mood.notes, valence)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn timeline_buckets() {
+        let pool = testing::pool().await;
+        // Monday and Tuesday of one week, Sunday and Monday around the next
+        insert_scored(&pool, "2024-03-04T08:00:00Z", Some(0.2)).await;
+        insert_scored(&pool, "2024-03-04T20:00:00Z", Some(0.6)).await;
+        insert_scored(&pool, "2024-03-05T12:00:00Z", Some(-0.2)).await;
+        insert_scored(&pool, "2024-03-10T12:00:00Z", Some(1.0)).await;
+        insert_scored(&pool, "2024-03-11T12:00:00Z", Some(-1.0)).await;
+        insert_scored(&pool, "2024-03-06T12:00:00Z", None).await;
+
+        let mut params = TimelineParams {
+            from: None,
+            to: None,
+            bucket: None,
+        };
+        let timeline = fetch_timeline(&pool, &params).await.unwrap();
+        assert_eq!(
+            points(&timeline),
+            [
+                ("2024-03-04".to_string(), 2, 0.4),
+                ("2024-03-05".to_string(), 1, -0.2),
+                ("2024-03-10".to_string(), 1, 1.0),
+                ("2024-03-11".to_string(), 1, -1.0),
+            ]
+        );
+        assert_eq!(timeline.unscored, 1);
+        assert!((timeline.points[0].mood.emotions[&Emotion::Joy] - 0.4).abs() < 1e-6);
+
+        params.bucket = Some(Bucket::Week);
+        let timeline = fetch_timeline(&pool, &params).await.unwrap();
+        assert_eq!(
+            points(&timeline),
+            [
+                ("2024-03-04".to_string(), 4, 0.4),
+                ("2024-03-11".to_string(), 1, -1.0),
+            ]
+        );
+
+        params.from = Some("2024-03-05T00:00:00Z".parse().unwrap());
+        params.to = Some("2024-03-11T00:00:00Z".parse().unwrap());
+        let timeline = fetch_timeline(&pool, &params).await.unwrap();
+        assert_eq!(points(&timeline), [("2024-03-04".to_string(), 2, 0.4)]);
+        assert_eq!(timeline.unscored, 1);
+    }
+}
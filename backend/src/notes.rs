@@ -1,19 +1,37 @@
-use crate::models::AppState;
+use crate::analyses::{self, AnalysisSource};
+use crate::chunking::{self, AnalysisPlan, Progress};
+use crate::embeddings;
+use crate::entities;
+use crate::generate;
+use crate::jobs::{self, NewJob};
+use crate::llm::{LlmError, StreamEvent, Usage};
+use crate::llm_json;
+use crate::models::{AppState, LlmParams};
+use crate::mood;
+use crate::prompts::{self, PromptName};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use crate::tasks;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     Json,
 };
 use chrono::{DateTime, Utc};
-use futures_util::StreamExt;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use sha2::{Digest, Sha256};
 use sqlx::{query_as, sqlite::SqlitePool, FromRow};
-use std::str::FromStr;
+use std::{convert::Infallible, str::FromStr};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{error, info};
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone)]
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, JsonSchema)]
 #[sqlx(rename_all = "lowercase")]
 pub enum Category {
     Personal,
@@ -99,8 +117,15 @@ pub struct Note {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub analysis: Option<String>,
+    /// The content changed since the analysis was generated.
+    pub stale: bool,
 }
 
+/// Columns of `Note`, `stale` computed from the content hashes.
+pub const NOTE_COLUMNS: &str = "id, content, analyzed, category_id, created_at, updated_at, \
+                                analysis, (analysis_hash IS NOT NULL AND analysis_hash != \
+                                content_hash) AS stale";
+
 #[derive(Debug, Deserialize)]
 pub struct CreateNoteRequest {
     pub content: String,
@@ -116,6 +141,8 @@ pub async fn create_note(
     let now = Utc::now();
     let analyzed = note.analyzed.unwrap_or(false);
     let analysis = note.analysis.unwrap_or_default();
+    let hash = content_hash(&note.content);
+    let analysis_hash = (!analysis.is_empty()).then_some(&hash);
 
     // First, get the category_id
     let category_id = match get_category_id(&state.pool, &note.category).await {
@@ -132,9 +159,11 @@ pub async fn create_note(
             category_id, 
             created_at, 
             updated_at, 
-            analysis
+            analysis,
+            content_hash,
+            analysis_hash
         ) 
-        VALUES (?, ?, ?, ?, ?, ?) 
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?) 
         RETURNING 
             id, 
             content, 
@@ -142,19 +171,28 @@ pub async fn create_note(
             category_id, 
             created_at as "created_at: DateTime<Utc>", 
             updated_at as "updated_at: DateTime<Utc>", 
-            analysis
+            analysis,
+            (analysis_hash IS NOT NULL AND analysis_hash != content_hash) as "stale!: bool"
         "#,
         note.content,
         analyzed,
         category_id,
         now,
         now,
-        analysis
+        analysis,
+        hash,
+        analysis_hash
     )
     .fetch_one(&*state.pool)
     .await
     {
-        Ok(created_note) => (StatusCode::CREATED, Json(created_note)).into_response(),
+        Ok(created_note) => {
+            embeddings::spawn_embed_note(&state, created_note.id, created_note.content.clone());
+            mood::spawn_score_note(&state, created_note.id);
+            entities::spawn_extract_note(&state, created_note.id);
+            tasks::spawn_extract_note(&state, created_note.id, &note.category);
+            (StatusCode::CREATED, Json(created_note)).into_response()
+        }
         Err(e) => {
             error!("Failed to create note: {}", e);
             (
@@ -166,7 +204,7 @@ pub async fn create_note(
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct NoteWithCategory {
     pub id: i64,
     pub content: String,
@@ -175,8 +213,15 @@ pub struct NoteWithCategory {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub analysis: Option<String>,
+    /// The content changed since the analysis was generated.
+    pub stale: bool,
 }
 
+/// Whether the analysis of the note `n` is stale, as a column of
+/// `NoteWithCategory`.
+pub const STALE_COLUMN: &str =
+    "(n.analysis_hash IS NOT NULL AND n.analysis_hash != n.content_hash) AS stale";
+
 pub async fn list_notes(State(state): State<AppState>) -> impl IntoResponse {
     match sqlx::query_as!(
         NoteWithCategory,
@@ -188,7 +233,8 @@ pub async fn list_notes(State(state): State<AppState>) -> impl IntoResponse {
             cd.category as "category!",
             n.created_at as "created_at: DateTime<Utc>",
             n.updated_at as "updated_at: DateTime<Utc>",
-            n.analysis
+            n.analysis,
+            (n.analysis_hash IS NOT NULL AND n.analysis_hash != n.content_hash) as "stale!: bool"
         FROM 
             notes n
         JOIN 
@@ -226,7 +272,8 @@ pub async fn get_note(
             cd.category as "category!",
             n.created_at as "created_at: DateTime<Utc>",
             n.updated_at as "updated_at: DateTime<Utc>",
-            n.analysis
+            n.analysis,
+            (n.analysis_hash IS NOT NULL AND n.analysis_hash != n.content_hash) as "stale!: bool"
         FROM 
             notes n
         JOIN 
@@ -262,8 +309,6 @@ pub async fn update_note(
     Json(note): Json<CreateNoteRequest>,
 ) -> impl IntoResponse {
     let now = Utc::now();
-    let analyzed = note.analyzed.unwrap_or(false);
-    let analysis = note.analysis.unwrap_or_default();
 
     // Get the category_id using the helper function
     let category_id = match get_category_id(&state.pool, &note.category).await {
@@ -271,35 +316,60 @@ pub async fn update_note(
         Err(response) => return response.into_response(),
     };
 
-    // Update the note and return the updated version
-    match sqlx::query_as::<_, Note>(
-        r#"
+    // Update the note and return the updated version. The analysis is kept
+    // unless the request sets it, and becomes stale if the content changed.
+    // `analyzed` only counts together with an analysis, the server tracks
+    // whether the stored one is still up to date.
+    let hash = content_hash(&note.content);
+    let analyzed = note.analysis.as_ref().and(note.analyzed);
+    let analysis_hash = note.analysis.as_ref().map(|_| &hash);
+    let updated = async {
+        let mut tx = state.pool.begin().await?;
+        let updated_note = sqlx::query_as::<_, Note>(&format!(
+            r#"
     UPDATE notes
     SET content = $1,
-        analyzed = $2,
+        analyzed = COALESCE($2, analyzed),
         category_id = $3,
         updated_at = $4,
-        analysis = $5
-    WHERE id = $6
-    RETURNING id,
-             content,
-             analyzed,
-             category_id,
-             created_at,
-             updated_at,
-             analysis
+        analysis = COALESCE($5, analysis),
+        content_hash = $6,
+        analysis_hash = COALESCE($7, analysis_hash)
+    WHERE id = $8
+    RETURNING {}
     "#,
-    )
-    .bind(&note.content)
-    .bind(analyzed)
-    .bind(&category_id)
-    .bind(now)
-    .bind(&analysis)
-    .bind(id)
-    .fetch_optional(&*state.pool)
-    .await
-    {
-        Ok(Some(updated_note)) => (StatusCode::OK, Json(updated_note)).into_response(),
+            NOTE_COLUMNS
+        ))
+        .bind(&note.content)
+        .bind(analyzed)
+        .bind(category_id)
+        .bind(now)
+        .bind(&note.analysis)
+        .bind(&hash)
+        .bind(analysis_hash)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        // An analysis set by hand is none of the generated ones
+        if note.analysis.is_some() {
+            sqlx::query("UPDATE analyses SET is_current = 0 WHERE note_id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(updated_note)
+    }
+    .await;
+
+    match updated {
+        Ok(Some(updated_note)) => {
+            embeddings::spawn_embed_note(&state, updated_note.id, updated_note.content.clone());
+            mood::spawn_score_note(&state, updated_note.id);
+            entities::spawn_extract_note(&state, updated_note.id);
+            tasks::spawn_extract_note(&state, updated_note.id, &note.category);
+            (StatusCode::OK, Json(updated_note)).into_response()
+        }
         Ok(None) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -313,11 +383,55 @@ pub async fn delete_note(
     State(state): State<AppState>,
     Path(note_id): Path<i64>,
 ) -> impl IntoResponse {
-    match sqlx::query!("DELETE FROM notes WHERE id = ?", note_id)
-        .execute(&*state.pool)
-        .await
-    {
-        Ok(result) => {
+    // Categories, the embedding and the mood reference the note, so they are
+    // removed along with it
+    let deleted = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query!("DELETE FROM llm_categories WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM note_embeddings WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM analyses WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM note_moods WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM note_entities WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM entity_extractions WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM task_extractions WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        // Tasks stay on the list after their note is gone
+        sqlx::query!("UPDATE tasks SET note_id = NULL WHERE note_id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        // Pending jobs would only fail with "Note not found" later
+        let jobs = jobs::cancel_note_jobs(&mut tx, note_id).await?;
+        // Runs are kept for the statistics
+        sqlx::query!(
+            "UPDATE llm_runs SET note_id = NULL WHERE note_id = ?",
+            note_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query!("DELETE FROM notes WHERE id = ?", note_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>((result, jobs))
+    }
+    .await;
+
+    match deleted {
+        Ok((result, jobs)) => {
+            jobs::stop_jobs(&state, &jobs);
             if result.rows_affected() > 0 {
                 StatusCode::NO_CONTENT.into_response()
             } else {
@@ -339,22 +453,84 @@ pub async fn delete_note(
 pub struct LlmCategory {
     pub category: String,
     pub explanation: String,
+    pub created_at: DateTime<Utc>,
 }
 
-pub async fn get_note_llm_categories(
-    State(state): State<AppState>,
-    Path(id): Path<i64>,
-) -> impl IntoResponse {
-    match query_as::<_, LlmCategory>(
-        "SELECT cd.category, cd.explanation
+#[derive(Debug, Serialize)]
+pub struct CategorizedNote {
+    pub note: Note,
+    pub categories: Vec<LlmCategory>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CategorizeParams {
+    /// Discard the stored LLM categories and ask the model again.
+    pub recategorize: Option<bool>,
+    /// Queue the categorization as a background job instead of waiting for it.
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,
+}
+
+async fn fetch_note(pool: &SqlitePool, id: i64) -> Result<Option<Note>, sqlx::Error> {
+    sqlx::query_as::<_, Note>(&format!("SELECT {} FROM notes WHERE id = ?", NOTE_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+async fn fetch_llm_categories(
+    pool: &SqlitePool,
+    note_id: i64,
+) -> Result<Vec<LlmCategory>, sqlx::Error> {
+    query_as::<_, LlmCategory>(
+        "SELECT cd.category, lc.explanation, lc.created_at
          FROM llm_categories as lc
          JOIN category_descriptions as cd ON lc.category_id = cd.id
-         WHERE lc.note_id = ?",
+         WHERE lc.note_id = ?
+         ORDER BY lc.id",
+    )
+    .bind(note_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_categories(State(state): State<AppState>) -> impl IntoResponse {
+    match query_as::<_, CategoryDescription>(
+        "SELECT id, category, explanation, created_at FROM category_descriptions ORDER BY id",
     )
-    .bind(id)
     .fetch_all(&*state.pool)
     .await
     {
+        Ok(categories) => (StatusCode::OK, Json(categories)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch categories: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to fetch categories".to_string(),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn get_note_llm_categories(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match fetch_note(&state.pool, id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "Note not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch note {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+                .into_response();
+        }
+    }
+
+    match fetch_llm_categories(&state.pool, id).await {
         Ok(categories) => (StatusCode::OK, Json(categories)).into_response(),
         Err(e) => {
             error!("Failed to fetch categories for note {}: {}", id, e);
@@ -367,328 +543,697 @@ pub async fn get_note_llm_categories(
     }
 }
 
+/// SHA-256 of a note's content, to tell whether something derived from the
+/// note is still up to date.
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Hashes the content of notes stored before the hashes were tracked. Their
+/// analyses are taken as up to date, as they were before.
+pub async fn backfill_content_hashes(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let notes: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, content FROM notes WHERE content_hash IS NULL")
+            .fetch_all(pool)
+            .await?;
+    if notes.is_empty() {
+        return Ok(());
+    }
+    let mut tx = pool.begin().await?;
+    for (id, content) in &notes {
+        sqlx::query(
+            "UPDATE notes
+             SET content_hash = ?1,
+                 analysis_hash = CASE WHEN analysis <> '' THEN ?1 ELSE analysis_hash END
+             WHERE id = ?2",
+        )
+        .bind(content_hash(content))
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    info!("Hashed the content of {} notes", notes.len());
+    Ok(())
+}
+
+/// Stores `analysis` on the note and adds it to the history of its analyses.
+/// Incomplete analyses are kept with `analyzed = false` so they can be looked
+/// at but are regenerated later.
+async fn save_analysis(
+    pool: &SqlitePool,
+    id: i64,
+    analysis: &str,
+    analyzed: bool,
+    source: &AnalysisSource,
+) -> Result<Note, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    analyses::insert(&mut tx, id, analysis, analyzed, source).await?;
+    let note = sqlx::query_as::<_, Note>(&format!(
+        "UPDATE notes 
+         SET analyzed = ?, analysis = ?, analysis_hash = ?, updated_at = ? 
+         WHERE id = ? 
+         RETURNING {}",
+        NOTE_COLUMNS
+    ))
+    .bind(analyzed)
+    .bind(analysis)
+    .bind(&source.content_hash)
+    .bind(Utc::now())
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(note)
+}
+
 #[derive(Debug, Deserialize)]
-struct OllamaResponse {
-    response: String,
-    model: String,
-    created_at: String,
-    total_duration: u64,
-    load_duration: u64,
-    prompt_eval_count: u64,
-    prompt_eval_duration: u64,
-    eval_count: u64,
-    eval_duration: u64,
-}
-
-pub async fn analyze_note(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
-    // Fetch the note
-    let note = sqlx::query_as::<_, Note>(
-        "SELECT id, content, analyzed, category_id, created_at, updated_at, analysis 
-         FROM notes 
-         WHERE id = ?",
+pub struct AnalyzeParams {
+    /// Queue the analysis as a background job instead of waiting for it.
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,
+    /// Regenerate the analysis even if it is up to date.
+    pub force: Option<bool>,
+}
+
+pub async fn analyze_note(
+    Path(id): Path<i64>,
+    Query(params): Query<AnalyzeParams>,
+    State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let llm_params = llm_params.map(|Json(params)| params);
+    let force = params.force.unwrap_or(false);
+    if params.run_async.unwrap_or(false) {
+        let job = NewJob::analyze(id, force, llm_params);
+        return jobs::enqueue_response(&state, job).await;
+    }
+
+    match run_analysis(
+        &state,
+        id,
+        force,
+        llm_params,
+        Priority::Interactive,
+        &|_| {},
     )
-    .bind(id)
-    .fetch_optional(&*state.pool)
-    .await;
+    .await
+    {
+        Ok(note) => (StatusCode::OK, Json(note)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
 
-    match note {
-        Ok(Some(note)) => {
-            if note.analyzed {
-                return (StatusCode::OK, Json(note)).into_response();
-            }
+/// Generates and stores the analysis of note `id`, unless it has an analysis
+/// that is up to date and `force` is not set. Shared by the analyze endpoint
+/// and the job workers.
+pub async fn run_analysis(
+    state: &AppState,
+    id: i64,
+    force: bool,
+    params: Option<LlmParams>,
+    priority: Priority,
+    progress: &(dyn Fn(Progress) + Sync),
+) -> Result<Note, (StatusCode, String)> {
+    let note = match fetch_note(&state.pool, id).await {
+        Ok(Some(note)) => note,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Note not found".to_string())),
+        Err(e) => {
+            error!("Failed to fetch note: {}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            ));
+        }
+    };
+    if note.analyzed && !note.stale && !force {
+        return Ok(note);
+    }
 
-            // Prepare the prompt for analysis
-            let prompt = state
-                .detailed_diary_analysis_prompt
-                .replace("{note_content}", &note.content);
-
-            // Use ollama::generate_handler to get the analysis
-            let params = crate::models::GenerateParams {
-                prompt,
-                model: None, // Use default model
-            };
-            let query = axum::extract::Query(params);
-            let response = crate::ollama::generate_handler(query, State(state.clone())).await;
-
-            // Process the streaming response
-            let (parts, body) = response.into_response().into_parts();
-            let status = parts.status;
-
-            if status != StatusCode::OK {
-                return (status, "Failed to generate analysis").into_response();
-            }
+    let AnalysisPlan {
+        request,
+        prompt_version,
+    } = chunking::plan_analysis(state, id, &note.content, params, priority, progress).await?;
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let mut source = AnalysisSource::new(&model, &prompt_version, &request.prompt, &note.content);
+    let ticket = state.scheduler.acquire(priority).await;
+    let generation = match state.llm.generate(request).await {
+        Ok(generation) => {
+            let run = NewRun::success(RunKind::Analyze, Some(id), &generation);
+            record_run(&state.pool, run.prompt_version(&prompt_version)).await;
+            generation
+        }
+        Err(e) => {
+            error!("Failed to generate analysis for note {}: {}", id, e);
+            let run = NewRun::failure(RunKind::Analyze, Some(id), &model, &e);
+            record_run(&state.pool, run.prompt_version(&prompt_version)).await;
+            return Err((
+                e.status_code(),
+                format!("Failed to generate analysis: {}", e),
+            ));
+        }
+    };
+    drop(ticket);
 
-            let mut stream = body.into_data_stream();
-            let mut full_response = String::new();
-            let mut total_tokens = 0;
-
-            while let Some(chunk) = stream.next().await {
-                match chunk {
-                    Ok(chunk) => {
-                        if let Ok(text) = String::from_utf8(chunk.to_vec()) {
-                            full_response.push_str(&text);
-                            // Try to parse each line as JSON
-                            for line in text.lines() {
-                                if let Ok(json) = serde_json::from_str::<OllamaResponse>(line) {
-                                    total_tokens += json.prompt_eval_count + json.eval_count;
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Stream error: {}", e);
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Stream error: {}", e),
-                        )
-                            .into_response();
-                    }
-                }
-            }
+    info!(
+        "Analysis generated for note {} by {}. Total tokens used: {}",
+        id,
+        generation.model,
+        generation.usage.total_tokens()
+    );
 
-            // Extract the actual analysis content
-            let analysis: String = full_response
-                .lines()
-                .filter_map(|line| {
-                    serde_json::from_str::<Value>(line)
-                        .ok()
-                        .and_then(|v| v["response"].as_str().map(String::from))
-                })
-                .collect();
-
-            info!(
-                "Analysis generated for note {}. Total tokens used: {}",
-                id, total_tokens
-            );
-
-            // Update the note with the analysis
-            let result = sqlx::query_as::<_, Note>(
-                "UPDATE notes 
-                 SET analyzed = ?, analysis = ?, updated_at = ? 
-                 WHERE id = ? 
-                 RETURNING id, content, analyzed, category_id, created_at, updated_at, analysis",
+    // Update the note with the analysis
+    source.model = generation.model;
+    save_analysis(&state.pool, id, &generation.text, true, &source)
+        .await
+        .map_err(|e| {
+            error!("Failed to update note with analysis: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to update note with analysis: {}", e),
             )
-            .bind(true)
-            .bind(&analysis)
-            .bind(Utc::now())
-            .bind(id)
-            .fetch_one(&*state.pool)
-            .await;
-
-            match result {
-                Ok(updated_note) => (StatusCode::OK, Json(updated_note)).into_response(),
-                Err(e) => {
-                    error!("Failed to update note with analysis: {}", e);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to update note with analysis: {}", e),
-                    )
-                        .into_response()
-                }
-            }
-        }
-        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnalyzeStreamParams {
+    /// Regenerate the analysis even if it is up to date.
+    pub force: Option<bool>,
+}
+
+/// Streams the analysis of a note as Server-Sent Events while accumulating it
+/// server side. The finished text is stored on the note once the model is
+/// done. If the client disconnects or the backend fails midway the partial
+/// text is stored with `analyzed = false`. Notes too long for the context
+/// window send a `progress` event per part analyzed before the final pass.
+pub async fn analyze_note_stream(
+    Path(id): Path<i64>,
+    Query(params): Query<AnalyzeStreamParams>,
+    State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let note = match fetch_note(&state.pool, id).await {
+        Ok(Some(note)) => note,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Note not found").into_response(),
         Err(e) => {
             error!("Failed to fetch note: {}", e);
-            (
+            return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Failed to fetch note: {}", e),
             )
-                .into_response()
+                .into_response();
         }
+    };
+
+    let (tx, rx) = mpsc::channel::<Event>(64);
+
+    if note.analyzed && !note.stale && !params.force.unwrap_or(false) {
+        // Nothing to generate, replay the stored analysis
+        let analysis = note.analysis.unwrap_or_default();
+        let _ = tx.try_send(generate::token_event(&analysis));
+        let _ = tx.try_send(generate::done_event("", &Usage::default()));
+    } else {
+        let llm_params = llm_params.map(|Json(params)| params);
+        tokio::spawn(stream_analysis(state, id, note.content, llm_params, tx));
     }
+
+    Sse::new(ReceiverStream::new(rx).map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::default())
+        .into_response()
 }
 
-#[derive(Debug, Deserialize)]
+async fn stream_analysis(
+    state: AppState,
+    id: i64,
+    content: String,
+    params: Option<LlmParams>,
+    tx: mpsc::Sender<Event>,
+) {
+    // Long notes are analyzed part by part before the final pass is streamed
+    let progress = |progress: Progress| {
+        let _ = tx.try_send(progress.event());
+    };
+    let plan = tokio::select! {
+        plan = chunking::plan_analysis(&state, id, &content, params, Priority::Interactive, &progress) => plan,
+        _ = tx.closed() => {
+            info!("Client disconnected during analysis of note {}", id);
+            return;
+        }
+    };
+    let AnalysisPlan {
+        request,
+        prompt_version,
+    } = match plan {
+        Ok(plan) => plan,
+        Err((_, message)) => {
+            let _ = tx.send(generate::error_event(&message)).await;
+            return;
+        }
+    };
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let mut source = AnalysisSource::new(&model, &prompt_version, &request.prompt, &content);
+    let record = |run: NewRun| record_run(&state.pool, run.prompt_version(&prompt_version));
+
+    let mut ticket = state.scheduler.join(Priority::Interactive);
+    if !generate::wait_turn(&mut ticket, &tx).await {
+        info!(
+            "Client disconnected while analysis of note {} was queued",
+            id
+        );
+        return;
+    }
+
+    // Loading the model can take a while before the first byte arrives
+    let events = tokio::select! {
+        events = state.llm.stream(request) => events,
+        _ = tx.closed() => {
+            info!("Client disconnected during analysis of note {}", id);
+            record(NewRun::cancelled(RunKind::Analyze, Some(id), &model)).await;
+            return;
+        }
+    };
+    let mut events = match events {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to generate analysis for note {}: {}", id, e);
+            record(NewRun::failure(RunKind::Analyze, Some(id), &model, &e)).await;
+            let _ = tx.send(generate::error_event(&e.to_string())).await;
+            return;
+        }
+    };
+
+    let mut analysis = String::new();
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = tx.closed() => {
+                info!("Client disconnected during analysis of note {}", id);
+                record(NewRun::cancelled(RunKind::Analyze, Some(id), &model)).await;
+                persist_partial_analysis(&state.pool, id, &analysis, &source).await;
+                return;
+            }
+        };
+
+        match event {
+            Some(Ok(StreamEvent::Token(token))) => {
+                analysis.push_str(&token);
+                let _ = tx.send(generate::token_event(&token)).await;
+            }
+            Some(Ok(StreamEvent::Done { model, usage })) => {
+                info!(
+                    "Analysis generated for note {} by {}. Total tokens used: {}",
+                    id,
+                    model,
+                    usage.total_tokens()
+                );
+                record(NewRun::completed(
+                    RunKind::Analyze,
+                    Some(id),
+                    &model,
+                    &usage,
+                ))
+                .await;
+                source.model = model.clone();
+                match save_analysis(&state.pool, id, &analysis, true, &source).await {
+                    Ok(_) => {
+                        let _ = tx.send(generate::done_event(&model, &usage)).await;
+                    }
+                    Err(e) => {
+                        error!("Failed to update note with analysis: {}", e);
+                        let message = format!("Failed to update note with analysis: {}", e);
+                        let _ = tx.send(generate::error_event(&message)).await;
+                    }
+                }
+                return;
+            }
+            Some(Err(e)) => {
+                error!("Analysis stream for note {} failed: {}", id, e);
+                record(NewRun::failure(RunKind::Analyze, Some(id), &model, &e)).await;
+                persist_partial_analysis(&state.pool, id, &analysis, &source).await;
+                let _ = tx.send(generate::error_event(&e.to_string())).await;
+                return;
+            }
+            None => {
+                // The backend closed the stream without a final event
+                let e = LlmError::Decode("stream ended unexpectedly".to_string());
+                record(NewRun::failure(RunKind::Analyze, Some(id), &model, &e)).await;
+                persist_partial_analysis(&state.pool, id, &analysis, &source).await;
+                let _ = tx.send(generate::error_event(&e.to_string())).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn persist_partial_analysis(
+    pool: &SqlitePool,
+    id: i64,
+    analysis: &str,
+    source: &AnalysisSource,
+) {
+    if analysis.is_empty() {
+        return;
+    }
+    match save_analysis(pool, id, analysis, false, source).await {
+        Ok(_) => info!("Stored partial analysis for note {}", id),
+        Err(e) => error!("Failed to store partial analysis for note {}: {}", id, e),
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct CategoryResponse {
+    #[schemars(length(min = 1, max = 3))]
     categories: Vec<CategoryItem>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct CategoryItem {
+    // Parsed leniently via `Category::from_str`, but the schema restricts the
+    // model to the predefined category names.
+    #[schemars(with = "Category")]
     name: String,
     explanation: String,
 }
 
 pub async fn categorize_note(
     Path(id): Path<i64>,
+    Query(params): Query<CategorizeParams>,
     State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
 ) -> impl IntoResponse {
-    // Fetch the note
-    let note_result = sqlx::query_as::<_, Note>(
-        "SELECT id, content, analyzed, category, created_at, updated_at, analysis 
-         FROM notes 
-         WHERE id = ?",
-    )
-    .bind(id)
-    .fetch_optional(&*state.pool)
-    .await;
-
-    match note_result {
-        Ok(Some(note)) => {
-            // Check if the note already has LLM categories
-            let existing_categories = sqlx::query_as::<_, LlmCategory>(
-                "SELECT id, note_id, category, explanation, created_at 
-                 FROM llm_categories 
-                 WHERE note_id = ?",
-            )
-            .bind(note.id)
-            .fetch_all(&*state.pool)
-            .await;
-
-            match existing_categories {
-                Ok(categories) if !categories.is_empty() => {
-                    // If categories exist, return the note with its categories
-                    return (StatusCode::OK, Json((note, categories))).into_response();
-                }
-                Ok(_) | Err(_) => {
-                    // If no categories or there was an error, proceed with categorization
-                    // hmm maybe we should only categorize if there are no categories
-                    // otherwise we might overwrite existing categories
-                }
-            }
-
-            // Prepare the prompt for categorization
-            let prompt = state
-                .diary_categorization_prompt
-                .replace("{note_content}", &note.content);
-
-            // Use ollama::generate_handler to get the categorization
-            let params = crate::models::GenerateParams {
-                prompt: prompt.clone(),
-                model: None, // Use default model
-            };
+    let llm_params = llm_params.map(|Json(params)| params);
+    let recategorize = params.recategorize.unwrap_or(false);
+    if params.run_async.unwrap_or(false) {
+        let job = NewJob::categorize(id, recategorize, llm_params);
+        return jobs::enqueue_response(&state, job).await;
+    }
 
-            let query = axum::extract::Query(params);
+    match run_categorization(&state, id, recategorize, llm_params, Priority::Interactive).await {
+        Ok(categorized) => (StatusCode::OK, Json(categorized)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
 
-            let mut attempts = 0;
-            let max_attempts = 3;
-            let mut categories = Vec::new();
-            let mut total_tokens = 0;
+/// Asks the model for the categories of note `id` and stores them, unless
+/// the note has been categorized already and `recategorize` is not set.
+/// Shared by the categorize endpoint and the job workers.
+pub async fn run_categorization(
+    state: &AppState,
+    id: i64,
+    recategorize: bool,
+    llm_params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<CategorizedNote, (StatusCode, String)> {
+    let note = match fetch_note(&state.pool, id).await {
+        Ok(Some(note)) => note,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Note not found".to_string())),
+        Err(e) => {
+            error!("Failed to fetch note: {}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            ));
+        }
+    };
 
-            while attempts < max_attempts {
-                attempts += 1;
-                let response =
-                    crate::ollama::generate_handler(query.clone(), State(state.clone())).await;
+    // Categories are only generated once unless a re-categorization is requested
+    if !recategorize {
+        match fetch_llm_categories(&state.pool, id).await {
+            Ok(categories) if !categories.is_empty() => {
+                return Ok(CategorizedNote { note, categories });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to fetch categories for note {}: {}", id, e);
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch categories: {}", e),
+                ));
+            }
+        }
+    }
 
-                // Process the streaming response
-                let (parts, body) = response.into_response().into_parts();
-                let status = parts.status;
+    // Prepare the prompt for categorization
+    let prompt = prompts::note_prompt(state, PromptName::Categorization, id).await?;
+    let request = state
+        .categorization_defaults
+        .request(prompt.render(&note.content), llm_params)
+        .with_format(llm_json::schema_for::<CategoryResponse>());
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let prompt_version = prompt.version;
+    let record = |run: NewRun| record_run(&state.pool, run.prompt_version(&prompt_version));
 
-                if status != StatusCode::OK {
-                    if attempts == max_attempts {
-                        return (status, "Failed to generate categorization").into_response();
-                    }
-                    continue;
-                }
+    // One slot for all attempts, including the JSON repairs
+    let _ticket = state.scheduler.acquire(priority).await;
+    // Attempts at getting valid JSON, connection problems are already
+    // retried by the backend
+    let max_attempts = 3;
+    let mut total_tokens = 0;
+    let mut categories = None;
 
-                let mut stream = body.into_data_stream();
-                let mut full_response = String::new();
-
-                while let Some(chunk) = stream.next().await {
-                    match chunk {
-                        Ok(chunk) => {
-                            if let Ok(text) = String::from_utf8(chunk.to_vec()) {
-                                full_response.push_str(&text);
-                                // Try to parse each line as JSON
-                                for line in text.lines() {
-                                    if let Ok(json) = serde_json::from_str::<OllamaResponse>(line) {
-                                        total_tokens += json.prompt_eval_count + json.eval_count;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Stream error: {}", e);
-                            if attempts == max_attempts {
-                                return (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Stream error: {}", e),
-                                )
-                                    .into_response();
-                            }
-                            continue;
-                        }
-                    }
-                }
+    for attempt in 1..=max_attempts {
+        let generation = match state.llm.generate(request.clone()).await {
+            Ok(generation) => {
+                record(NewRun::success(RunKind::Categorize, Some(id), &generation)).await;
+                generation
+            }
+            Err(e) => {
+                error!(
+                    "Categorization attempt {} for note {} failed: {}",
+                    attempt, id, e
+                );
+                record(NewRun::failure(RunKind::Categorize, Some(id), &model, &e)).await;
+                return Err((
+                    e.status_code(),
+                    format!("Failed to generate categorization: {}", e),
+                ));
+            }
+        };
+        total_tokens += generation.usage.total_tokens();
 
-                // Extract the actual categorization content
-                let categorization: String = full_response
-                    .lines()
-                    .filter_map(|line| {
-                        serde_json::from_str::<Value>(line)
-                            .ok()
-                            .and_then(|v| v["response"].as_str().map(String::from))
-                    })
-                    .collect();
-
-                // Try to parse the categorization as JSON
-                match serde_json::from_str::<CategoryResponse>(&categorization) {
-                    Ok(category_response) => {
-                        categories = category_response.categories;
-                        break;
-                    }
-                    Err(e) => {
-                        error!("Failed to parse categorization JSON: {}", e);
-                        if attempts == max_attempts {
-                            return (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                "Failed to generate valid categorization JSON".to_string(),
-                            )
-                                .into_response();
-                        }
-                    }
+        // Salvage the output or ask for a repair before regenerating from scratch
+        match llm_json::extract_or_repair::<CategoryResponse>(
+            state.llm.as_ref(),
+            &request,
+            &generation.text,
+        )
+        .await
+        {
+            Ok((category_response, repair)) => {
+                if let Some(repair) = repair {
+                    info!("Repaired categorization JSON for note {}", id);
+                    record(NewRun::success(RunKind::Categorize, Some(id), &repair)).await;
+                    total_tokens += repair.usage.total_tokens();
                 }
+                categories = Some(category_response.categories);
+                break;
             }
-
-            info!(
-                "Categorization generated for note {}. Total tokens used: {}",
-                id, total_tokens
-            );
-
-            // Insert the new categories
-            let mut inserted_categories = Vec::new();
-            for category_item in categories {
-                let category =
-                    Category::from_str(&category_item.name).unwrap_or(Category::Unspecified);
-
-                // we need to get the category_id from the category name string
-
-                let result = sqlx::query_as::<_, LlmCategory>(
-                    "INSERT INTO llm_categories (note_id, category, explanation) 
-                     VALUES (?, ?, ?) 
-                     RETURNING id, note_id, category, explanation, created_at",
-                )
-                .bind(note.id)
-                .bind(category)
-                .bind(&category_item.explanation)
-                .fetch_one(&*state.pool)
+            Err(e) => {
+                record(NewRun::repair_failure(
+                    RunKind::Categorize,
+                    Some(id),
+                    &model,
+                    &e,
+                ))
                 .await;
-
-                match result {
-                    Ok(inserted_category) => inserted_categories.push(inserted_category),
-                    Err(e) => {
-                        error!("Failed to insert category: {}", e);
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Failed to insert category: {}", e),
-                        )
-                            .into_response();
-                    }
-                }
+                error!("Failed to parse categorization JSON: {}", e);
             }
+        }
+    }
+
+    let Some(categories) = categories else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to generate valid categorization JSON".to_string(),
+        ));
+    };
 
-            // Return the note with its new categories
-            (StatusCode::OK, Json((note, inserted_categories))).into_response()
+    info!(
+        "Categorization generated for note {}. Total tokens used: {}",
+        id, total_tokens
+    );
+
+    // Resolve the category ids up front, ignoring duplicate names from the model
+    let mut resolved: Vec<(i64, String)> = Vec::new();
+    for item in categories {
+        let category = Category::from_str(&item.name).unwrap_or(Category::Unspecified);
+        let category_id = get_category_id(&state.pool, &category).await?;
+        if !resolved
+            .iter()
+            .any(|(existing, _)| *existing == category_id)
+        {
+            resolved.push((category_id, item.explanation));
         }
-        Ok(None) => (StatusCode::NOT_FOUND, "Note not found").into_response(),
+    }
+
+    // Replace any previous categorization of the note
+    let stored = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("DELETE FROM llm_categories WHERE note_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        for (category_id, explanation) in &resolved {
+            sqlx::query(
+                "INSERT INTO llm_categories (note_id, category_id, explanation) VALUES (?, ?, ?)",
+            )
+            .bind(id)
+            .bind(category_id)
+            .bind(explanation)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+
+    if let Err(e) = stored {
+        error!("Failed to store categories for note {}: {}", id, e);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store categories: {}", e),
+        ));
+    }
+
+    match fetch_llm_categories(&state.pool, id).await {
+        Ok(categories) => Ok(CategorizedNote { note, categories }),
         Err(e) => {
-            error!("Failed to fetch note: {}", e);
-            (
+            error!("Failed to fetch categories for note {}: {}", id, e);
+            Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to fetch note: {}", e),
-            )
-                .into_response()
+                format!("Failed to fetch categories: {}", e),
+            ))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const CATEGORIES: &str = r#"{"categories": [
+        {"name": "work", "explanation": "A presentation at work."},
+        {"name": "reflection", "explanation": "Thinks about what went well."}
+    ]}"#;
+
+    async fn run_count(pool: &SqlitePool, kind: &str) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM llm_runs WHERE kind = ?")
+            .bind(kind)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn categorization_stores_valid_reply() {
+        let state = testing::state([CATEGORIES]).await;
+        let id = testing::insert_note(&state.pool, "The presentation went well.", "work").await;
+
+        let categorized = run_categorization(&state, id, false, None, Priority::Interactive)
+            .await
+            .unwrap();
+        let names: Vec<&str> = categorized
+            .categories
+            .iter()
+            .map(|category| category.category.as_str())
+            .collect();
+        assert_eq!(names, ["work", "reflection"]);
+        assert_eq!(
+            categorized.categories[0].explanation,
+            "A presentation at work."
+        );
+        assert_eq!(run_count(&state.pool, "categorize").await, 1);
+    }
+
+    #[tokio::test]
+    async fn categorization_salvages_fenced_reply() {
+        let reply = format!("Sure, here you go:\n```json\n{}\n```", CATEGORIES);
+        let state = testing::state([reply]).await;
+        let id = testing::insert_note(&state.pool, "The presentation went well.", "work").await;
+
+        let categorized = run_categorization(&state, id, false, None, Priority::Interactive)
+            .await
+            .unwrap();
+        assert_eq!(categorized.categories.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn categorization_repairs_invalid_reply() {
+        let state = testing::state(["I would say this is about work.", CATEGORIES]).await;
+        let id = testing::insert_note(&state.pool, "The presentation went well.", "work").await;
+
+        let categorized = run_categorization(&state, id, false, None, Priority::Interactive)
+            .await
+            .unwrap();
+        assert_eq!(categorized.categories.len(), 2);
+        // The original call and the repair
+        assert_eq!(run_count(&state.pool, "categorize").await, 2);
+    }
+
+    #[tokio::test]
+    async fn categorization_fails_on_invalid_replies() {
+        let state = testing::state(["I would say this is about work."]).await;
+        let id = testing::insert_note(&state.pool, "The presentation went well.", "work").await;
+
+        let (status, message) = run_categorization(&state, id, false, None, Priority::Interactive)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(message, "Failed to generate valid categorization JSON");
+        let stored = fetch_llm_categories(&state.pool, id).await.unwrap();
+        assert!(stored.is_empty());
+        // Three attempts, each with a failed repair
+        assert_eq!(run_count(&state.pool, "categorize").await, 6);
+    }
+
+    #[tokio::test]
+    async fn categorization_of_missing_note() {
+        let state = testing::state([CATEGORIES]).await;
+        let (status, _) = run_categorization(&state, 42, false, None, Priority::Interactive)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn analysis_is_stored_and_kept_in_history() {
+        let state = testing::state(["A calm and thoughtful day."]).await;
+        let id = testing::insert_note(&state.pool, "I walked along the lake.", "personal").await;
+
+        let note = run_analysis(&state, id, false, None, Priority::Interactive, &|_| {})
+            .await
+            .unwrap();
+        assert!(note.analyzed);
+        assert!(!note.stale);
+        assert_eq!(note.analysis.as_deref(), Some("A calm and thoughtful day."));
+
+        let (count, current): (i64, i64) =
+            sqlx::query_as("SELECT COUNT(*), SUM(is_current) FROM analyses WHERE note_id = ?")
+                .bind(id)
+                .fetch_one(&*state.pool)
+                .await
+                .unwrap();
+        assert_eq!((count, current), (1, 1));
+        assert_eq!(run_count(&state.pool, "analyze").await, 1);
+    }
+
+    #[tokio::test]
+    async fn analysis_up_to_date_is_not_regenerated() {
+        let state = testing::state(["First analysis.", "Second analysis."]).await;
+        let id = testing::insert_note(&state.pool, "I walked along the lake.", "personal").await;
+
+        run_analysis(&state, id, false, None, Priority::Interactive, &|_| {})
+            .await
+            .unwrap();
+        let note = run_analysis(&state, id, false, None, Priority::Interactive, &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(note.analysis.as_deref(), Some("First analysis."));
+
+        let note = run_analysis(&state, id, true, None, Priority::Interactive, &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(note.analysis.as_deref(), Some("Second analysis."));
+    }
+}
@@ -1,66 +1,257 @@
-use crate::models::{AppState, GenerateParams};
-use axum::{
-    body::Body,
-    extract::{Query, State},
-    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
-    response::IntoResponse,
+use crate::llm::{
+    self, ChatMessage, ChatRequest, GenerateRequest, Generation, GenerationOptions, LlmBackend,
+    LlmError, StreamEvent, TokenStream, Usage,
 };
-use tokio_stream::StreamExt;
-use tracing::error;
-
-pub async fn generate_handler(
-    Query(params): Query<GenerateParams>,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
-    let model = params.model.as_deref().unwrap_or(&state.default_model);
-    let ollama_response = match state
-        .client
-        .post(format!("{}/api/generate", state.ollama_url))
-        .json(&serde_json::json!({
-            "model": model,
-            "prompt": params.prompt,
-            "stream": true,
-        }))
-        .send()
-        .await
-    {
-        Ok(res) => res,
-        Err(err) => {
-            error!(%err, "request to Ollama failed");
-            return (StatusCode::BAD_GATEWAY, HeaderMap::new(), Body::empty()).into_response();
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Talks to the native Ollama API (`/api/generate` and `/api/chat`).
+pub struct OllamaBackend {
+    client: Client,
+    base_url: String,
+    default_model: String,
+}
+
+impl OllamaBackend {
+    pub fn new(client: Client, base_url: &str, default_model: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            default_model: default_model.to_string(),
         }
-    };
+    }
+
+    async fn post<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, LlmError> {
+        let response = self
+            .client
+            .post(format!("{}{}", self.base_url, path))
+            .json(body)
+            .send()
+            .await?;
+        llm::check_status(response).await
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaGenerateRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a Value>,
+    options: OllamaOptions<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+}
+
+/// The `options` object of a request, `keep_alive` is sent separately.
+#[derive(Debug, Serialize)]
+struct OllamaOptions<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a [String]>,
+}
+
+impl<'a> From<&'a GenerationOptions> for OllamaOptions<'a> {
+    fn from(options: &'a GenerationOptions) -> Self {
+        Self {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            num_ctx: options.num_ctx,
+            seed: options.seed,
+            stop: options.stop.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a Value>,
+    options: OllamaOptions<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaEmbedRequest<'a> {
+    model: &'a str,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
 
-    let status = ollama_response.status();
-    if !status.is_success() {
-        error!(status = ?status, "Ollama returned non-success status");
-        return (StatusCode::BAD_GATEWAY, HeaderMap::new(), Body::empty()).into_response();
+/// One object of an Ollama response. Streamed responses send one per line and
+/// only the final object (`done: true`) carries the counts and durations.
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    model: String,
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    total_duration: u64,
+    #[serde(default)]
+    load_duration: u64,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    prompt_eval_duration: u64,
+    #[serde(default)]
+    eval_count: u64,
+    #[serde(default)]
+    eval_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    content: String,
+}
+
+impl OllamaResponse {
+    fn usage(&self) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_eval_count,
+            completion_tokens: self.eval_count,
+            total_duration: Duration::from_nanos(self.total_duration),
+            load_duration: Duration::from_nanos(self.load_duration),
+            prompt_eval_duration: Duration::from_nanos(self.prompt_eval_duration),
+            eval_duration: Duration::from_nanos(self.eval_duration),
+        }
     }
 
-    let mut headers = HeaderMap::new();
-    // Manually convert headers from reqwest to axum
-    for (name, value) in ollama_response.headers() {
-        if let (Ok(header_name), Ok(header_value)) = (
-            HeaderName::from_bytes(name.as_ref()),
-            HeaderValue::from_bytes(value.as_bytes()),
-        ) {
-            headers.insert(header_name, header_value);
+    fn into_generation(self) -> Generation {
+        let usage = self.usage();
+        let text = match self.message {
+            Some(message) => message.content,
+            None => self.response,
+        };
+        Generation {
+            text,
+            model: self.model,
+            usage,
         }
     }
-    // Ensure we set the content type for SSE
-    headers.insert(
-        HeaderName::from_static("content-type"),
-        HeaderValue::from_static("text/event-stream"),
-    );
-
-    let stream = ollama_response
-        .bytes_stream()
-        .map(|result| result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)));
-
-    (
-        StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::OK),
-        headers,
-        Body::from_stream(stream),
-    )
-        .into_response()
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn generate(&self, request: GenerateRequest) -> Result<Generation, LlmError> {
+        let model = request.model.as_deref().unwrap_or(&self.default_model);
+        let response = self
+            .post(
+                "/api/generate",
+                &OllamaGenerateRequest {
+                    model,
+                    prompt: &request.prompt,
+                    stream: false,
+                    format: request.format.as_ref(),
+                    options: (&request.options).into(),
+                    keep_alive: request.options.keep_alive.as_deref(),
+                },
+            )
+            .await?;
+        let response: OllamaResponse = response.json().await?;
+        Ok(response.into_generation())
+    }
+
+    async fn stream(&self, request: GenerateRequest) -> Result<TokenStream, LlmError> {
+        let model = request.model.as_deref().unwrap_or(&self.default_model);
+        let response = self
+            .post(
+                "/api/generate",
+                &OllamaGenerateRequest {
+                    model,
+                    prompt: &request.prompt,
+                    stream: true,
+                    format: request.format.as_ref(),
+                    options: (&request.options).into(),
+                    keep_alive: request.options.keep_alive.as_deref(),
+                },
+            )
+            .await?;
+
+        let events = llm::lines(response.bytes_stream()).map(|line| {
+            let response: OllamaResponse =
+                serde_json::from_str(&line?).map_err(|e| LlmError::Decode(e.to_string()))?;
+            if response.done {
+                Ok(StreamEvent::Done {
+                    usage: response.usage(),
+                    model: response.model,
+                })
+            } else {
+                Ok(StreamEvent::Token(response.response))
+            }
+        });
+        Ok(events.boxed())
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<Generation, LlmError> {
+        let model = request.model.as_deref().unwrap_or(&self.default_model);
+        let response = self
+            .post(
+                "/api/chat",
+                &OllamaChatRequest {
+                    model,
+                    messages: &request.messages,
+                    stream: false,
+                    format: request.format.as_ref(),
+                    options: (&request.options).into(),
+                    keep_alive: request.options.keep_alive.as_deref(),
+                },
+            )
+            .await?;
+        let response: OllamaResponse = response.json().await?;
+        Ok(response.into_generation())
+    }
+
+    async fn embed(&self, model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        let response = self
+            .post("/api/embed", &OllamaEmbedRequest { model, input })
+            .await?;
+        let response: OllamaEmbedResponse = response.json().await?;
+        Ok(response.embeddings)
+    }
+
+    async fn health(&self) -> Result<(), LlmError> {
+        let response = self
+            .client
+            .get(format!("{}/api/version", self.base_url))
+            .send()
+            .await?;
+        llm::check_status(response).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,396 @@
+use crate::llm::{
+    self, ChatMessage, ChatRequest, GenerateRequest, Generation, LlmBackend, LlmError, StreamEvent,
+    TokenStream, Usage,
+};
+use async_trait::async_trait;
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+/// Talks to any server implementing the OpenAI `/v1/chat/completions`
+/// protocol, e.g. llama.cpp's server or vLLM.
+pub struct OpenAiBackend {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    default_model: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(
+        client: Client,
+        base_url: &str,
+        api_key: Option<String>,
+        default_model: &str,
+    ) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            default_model: default_model.to_string(),
+        }
+    }
+
+    async fn post<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, LlmError> {
+        let mut request = self
+            .client
+            .post(format!("{}{}", self.base_url, path))
+            .json(body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        llm::check_status(request.send().await?).await
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    // `num_ctx` and `keep_alive` have no equivalent in the protocol, the
+    // context size is fixed when the server is started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a [String]>,
+}
+
+/// Wraps a JSON schema in the `response_format` object of the protocol.
+fn response_format(schema: &Value) -> Value {
+    json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "response",
+            "schema": schema,
+            "strict": true,
+        },
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    model: String,
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: CompletionMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionMessage {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// One `data:` frame of a streamed completion. With `include_usage` the last
+/// frame before `[DONE]` has no choices and carries the token counts.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunk {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: CompletionMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+}
+
+impl CompletionUsage {
+    fn apply(&self, usage: &mut Usage) {
+        usage.prompt_tokens = self.prompt_tokens;
+        usage.completion_tokens = self.completion_tokens;
+    }
+}
+
+/// The payload of a `data:` line of an event stream. Comments and other
+/// fields carry none.
+fn sse_data(line: &str) -> Option<&str> {
+    line.strip_prefix("data:").map(str::trim)
+}
+
+/// Turns the lines of a streamed completion into tokens, closed by `Done`
+/// once `[DONE]` arrives. A stream ending before that was cut off, which is
+/// an error rather than a complete generation.
+fn completion_events<S>(lines: S, model: String, started: Instant) -> TokenStream
+where
+    S: Stream<Item = Result<String, LlmError>> + Send + 'static,
+{
+    stream::unfold(
+        Some((lines.boxed(), model, Usage::default())),
+        move |state| async move {
+            let (mut lines, mut model, mut usage) = state?;
+            while let Some(line) = lines.next().await {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                let Some(data) = sse_data(&line) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    usage.total_duration = started.elapsed();
+                    return Some((Ok(StreamEvent::Done { model, usage }), None));
+                }
+                let chunk: ChatCompletionChunk = match serde_json::from_str(data) {
+                    Ok(chunk) => chunk,
+                    Err(err) => return Some((Err(LlmError::Decode(err.to_string())), None)),
+                };
+                if let Some(chunk_model) = chunk.model {
+                    model = chunk_model;
+                }
+                if let Some(chunk_usage) = &chunk.usage {
+                    chunk_usage.apply(&mut usage);
+                }
+                let content = chunk
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .filter(|content| !content.is_empty());
+                if let Some(content) = content {
+                    return Some((Ok(StreamEvent::Token(content)), Some((lines, model, usage))));
+                }
+            }
+            Some((
+                Err(LlmError::Decode(
+                    "the stream ended before [DONE]".to_string(),
+                )),
+                None,
+            ))
+        },
+    )
+    .boxed()
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn generate(&self, request: GenerateRequest) -> Result<Generation, LlmError> {
+        self.chat(ChatRequest {
+            model: request.model,
+            messages: vec![ChatMessage::user(request.prompt)],
+            format: request.format,
+            options: request.options,
+        })
+        .await
+    }
+
+    async fn stream(&self, request: GenerateRequest) -> Result<TokenStream, LlmError> {
+        let started = Instant::now();
+        let model = request.model.unwrap_or_else(|| self.default_model.clone());
+        let messages = [ChatMessage::user(request.prompt)];
+        let response = self
+            .post(
+                "/chat/completions",
+                &ChatCompletionRequest {
+                    model: &model,
+                    messages: &messages,
+                    stream: true,
+                    stream_options: Some(StreamOptions {
+                        include_usage: true,
+                    }),
+                    response_format: request.format.as_ref().map(response_format),
+                    temperature: request.options.temperature,
+                    top_p: request.options.top_p,
+                    seed: request.options.seed,
+                    stop: request.options.stop.as_deref(),
+                },
+            )
+            .await?;
+
+        Ok(completion_events(
+            llm::lines(response.bytes_stream()),
+            model,
+            started,
+        ))
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<Generation, LlmError> {
+        let started = Instant::now();
+        let model = request.model.as_deref().unwrap_or(&self.default_model);
+        let response = self
+            .post(
+                "/chat/completions",
+                &ChatCompletionRequest {
+                    model,
+                    messages: &request.messages,
+                    stream: false,
+                    stream_options: None,
+                    response_format: request.format.as_ref().map(response_format),
+                    temperature: request.options.temperature,
+                    top_p: request.options.top_p,
+                    seed: request.options.seed,
+                    stop: request.options.stop.as_deref(),
+                },
+            )
+            .await?;
+        let completion: ChatCompletion = response.json().await?;
+
+        // The protocol reports no timings, so the wall clock time is recorded
+        let mut usage = Usage {
+            total_duration: started.elapsed(),
+            ..Default::default()
+        };
+        if let Some(completion_usage) = &completion.usage {
+            completion_usage.apply(&mut usage);
+        }
+        let text = completion
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .unwrap_or_default();
+        Ok(Generation {
+            text,
+            model: completion.model,
+            usage,
+        })
+    }
+
+    async fn embed(&self, model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        let response = self
+            .post("/embeddings", &EmbeddingRequest { model, input })
+            .await?;
+        let mut response: EmbeddingResponse = response.json().await?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+
+    async fn health(&self) -> Result<(), LlmError> {
+        let mut request = self.client.get(format!("{}/models", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        llm::check_status(request.send().await?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn events(lines: &[&str]) -> Vec<Result<StreamEvent, LlmError>> {
+        let lines: Vec<Result<String, LlmError>> =
+            lines.iter().map(|line| Ok(line.to_string())).collect();
+        completion_events(stream::iter(lines), "requested".to_string(), Instant::now())
+            .collect()
+            .await
+    }
+
+    fn chunk(content: &str) -> String {
+        format!(
+            r#"data: {{"model":"served","choices":[{{"delta":{{"content":"{}"}}}}]}}"#,
+            content
+        )
+    }
+
+    #[test]
+    fn data_lines() {
+        assert_eq!(sse_data(r#"data: {"a":1}"#), Some(r#"{"a":1}"#));
+        assert_eq!(sse_data("data:[DONE]"), Some("[DONE]"));
+        assert_eq!(sse_data(": keep-alive"), None);
+        assert_eq!(sse_data("event: message"), None);
+    }
+
+    #[tokio::test]
+    async fn tokens_then_done_with_usage() {
+        let events = events(&[
+            ": OPENROUTER PROCESSING",
+            &chunk("Hello"),
+            &chunk(""),
+            &chunk(" world"),
+            r#"data: {"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":2}}"#,
+            "data: [DONE]",
+        ])
+        .await;
+        let events: Vec<StreamEvent> = events.into_iter().map(Result::unwrap).collect();
+        assert!(matches!(&events[0], StreamEvent::Token(token) if token == "Hello"));
+        assert!(matches!(&events[1], StreamEvent::Token(token) if token == " world"));
+        let StreamEvent::Done { model, usage } = &events[2] else {
+            panic!("expected done, got {:?}", events[2]);
+        };
+        assert_eq!(model, "served");
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 2));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stream_cut_off_before_done_fails() {
+        let events = events(&[&chunk("Hello")]).await;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Ok(StreamEvent::Token(_))));
+        assert!(matches!(&events[1], Err(LlmError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_chunk_fails() {
+        let events = events(&["data: {not json", "data: [DONE]"]).await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Err(LlmError::Decode(_))));
+    }
+}
@@ -0,0 +1,1241 @@
+//! Prompt templates kept in the database. A template stored under the name of
+//! a task replaces the configured prompt of that task, every change adds a new
+//! version. Templates use typed variables like `{note_date}` or
+//! `{previous_notes:3}`, which are checked against the task when a template is
+//! saved and filled in before anything is sent to the model.
+
+use crate::chunking;
+use crate::config;
+use crate::models::AppState;
+use crate::notes::Category;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow};
+use std::{fmt, str::FromStr};
+use tracing::{error, info};
+
+const PROMPT_COLUMNS: &str = "id, name, version, template, description, created_at";
+
+/// Notes filled in by `{previous_notes}` without a count.
+const DEFAULT_PREVIOUS_NOTES: usize = 3;
+const MAX_PREVIOUS_NOTES: usize = 10;
+/// Tokens each of the previous notes is cut off at.
+const PREVIOUS_NOTE_TOKENS: usize = 300;
+
+/// The tasks whose prompt can be replaced by a stored template.
+#[derive(Debug, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum PromptName {
+    Analysis,
+    Categorization,
+    Mood,
+    Entities,
+    Tasks,
+    /// Analysis of one part of a note too long for the context window.
+    ChunkAnalysis,
+    /// Combines the analyses of the parts of a long note.
+    AnalysisSynthesis,
+    Ask,
+    Digest,
+}
+
+impl PromptName {
+    const ALL: [PromptName; 9] = [
+        PromptName::Analysis,
+        PromptName::Categorization,
+        PromptName::Mood,
+        PromptName::Entities,
+        PromptName::Tasks,
+        PromptName::ChunkAnalysis,
+        PromptName::AnalysisSynthesis,
+        PromptName::Ask,
+        PromptName::Digest,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            PromptName::Analysis => "analysis",
+            PromptName::Categorization => "categorization",
+            PromptName::Mood => "mood",
+            PromptName::Entities => "entities",
+            PromptName::Tasks => "tasks",
+            PromptName::ChunkAnalysis => "chunk_analysis",
+            PromptName::AnalysisSynthesis => "analysis_synthesis",
+            PromptName::Ask => "ask",
+            PromptName::Digest => "digest",
+        }
+    }
+
+    /// The prompt used while no template is stored, see `Config`.
+    fn configured(self, state: &AppState) -> &str {
+        match self {
+            PromptName::Analysis => &state.detailed_diary_analysis_prompt,
+            PromptName::Categorization => &state.diary_categorization_prompt,
+            PromptName::Mood => &state.mood_prompt,
+            PromptName::Entities => &state.entity_prompt,
+            PromptName::Tasks => &state.task_prompt,
+            PromptName::ChunkAnalysis => &state.chunk_analysis_prompt,
+            PromptName::AnalysisSynthesis => &state.analysis_synthesis_prompt,
+            PromptName::Ask => &state.ask_diary_prompt,
+            PromptName::Digest => &state.digest_prompt,
+        }
+    }
+
+    /// Whether the prompt is about a single note, which `{note_date}` and
+    /// the other note variables are filled in from.
+    fn uses_note(self) -> bool {
+        !matches!(self, PromptName::Ask | PromptName::Digest)
+    }
+
+    /// The variables given with each model call, the ones the template must
+    /// contain and the ones it may contain.
+    fn call_variables(self) -> (&'static [Variable], &'static [Variable]) {
+        match self {
+            PromptName::ChunkAnalysis => {
+                (&[Variable::NoteContent], &[Variable::Part, Variable::Parts])
+            }
+            PromptName::AnalysisSynthesis => (&[Variable::PartAnalyses], &[]),
+            PromptName::Ask => (&[Variable::Notes, Variable::Question], &[]),
+            PromptName::Digest => (
+                &[Variable::Notes],
+                &[Variable::Period, Variable::Start, Variable::End],
+            ),
+            _ => (&[Variable::NoteContent], &[]),
+        }
+    }
+
+    fn allows(self, variable: Variable) -> bool {
+        let (required, optional) = self.call_variables();
+        match variable {
+            Variable::UserProfile => true,
+            Variable::NoteDate
+            | Variable::Category
+            | Variable::CategoryExplanation
+            | Variable::PreviousNotes(_) => self.uses_note(),
+            _ => required.contains(&variable) || optional.contains(&variable),
+        }
+    }
+}
+
+impl FromStr for PromptName {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PromptName::ALL
+            .into_iter()
+            .find(|name| name.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for PromptName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A variable of a template, written as `{name}` or `{name:argument}`.
+/// Which ones a template may use depends on its task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    /// `{note_content}`, the note, or the part of it for `chunk_analysis`.
+    NoteContent,
+    /// `{note_date}`, the day the note was written, e.g. `2024-03-01, Friday`.
+    NoteDate,
+    /// `{category}` of the note.
+    Category,
+    /// `{category_explanation}`, the description of the category of the note.
+    CategoryExplanation,
+    /// `{previous_notes:N}`, the N notes written before the note.
+    PreviousNotes(usize),
+    /// `{user_profile}`, what the writer tells about themselves.
+    UserProfile,
+    /// `{part}`, the number of the part of a long note, 1 is the first.
+    Part,
+    /// `{parts}`, the number of parts of a long note.
+    Parts,
+    /// `{part_analyses}`, the analyses of the parts of a long note.
+    PartAnalyses,
+    /// `{notes}`, the notes a question is answered from or a digest covers.
+    Notes,
+    /// `{question}` asked about the notes.
+    Question,
+    /// `{period}` of a digest, e.g. `week`.
+    Period,
+    /// `{start}`, the first day of the period of a digest.
+    Start,
+    /// `{end}`, the last day of the period of a digest.
+    End,
+}
+
+impl Variable {
+    fn parse(placeholder: &str) -> Result<Self, String> {
+        let (name, argument) = match placeholder.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (placeholder, None),
+        };
+        let variable = match name {
+            "note_content" => Variable::NoteContent,
+            "note_date" => Variable::NoteDate,
+            "category" => Variable::Category,
+            "category_explanation" => Variable::CategoryExplanation,
+            "user_profile" => Variable::UserProfile,
+            "part" => Variable::Part,
+            "parts" => Variable::Parts,
+            "part_analyses" => Variable::PartAnalyses,
+            "notes" => Variable::Notes,
+            "question" => Variable::Question,
+            "period" => Variable::Period,
+            "start" => Variable::Start,
+            "end" => Variable::End,
+            "previous_notes" => {
+                let count = match argument {
+                    Some(count) => count
+                        .parse()
+                        .ok()
+                        .filter(|count| (1..=MAX_PREVIOUS_NOTES).contains(count))
+                        .ok_or_else(|| {
+                            format!(
+                                "{{{}}} needs a number of notes from 1 to {}",
+                                placeholder, MAX_PREVIOUS_NOTES
+                            )
+                        })?,
+                    None => DEFAULT_PREVIOUS_NOTES,
+                };
+                return Ok(Variable::PreviousNotes(count));
+            }
+            _ => return Err(format!("unknown variable {{{}}}", placeholder)),
+        };
+        match argument {
+            Some(_) => Err(format!("{{{}}} takes no argument", name)),
+            None => Ok(variable),
+        }
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variable::NoteContent => f.write_str("{note_content}"),
+            Variable::NoteDate => f.write_str("{note_date}"),
+            Variable::Category => f.write_str("{category}"),
+            Variable::CategoryExplanation => f.write_str("{category_explanation}"),
+            Variable::PreviousNotes(count) => write!(f, "{{previous_notes:{}}}", count),
+            Variable::UserProfile => f.write_str("{user_profile}"),
+            Variable::Part => f.write_str("{part}"),
+            Variable::Parts => f.write_str("{parts}"),
+            Variable::PartAnalyses => f.write_str("{part_analyses}"),
+            Variable::Notes => f.write_str("{notes}"),
+            Variable::Question => f.write_str("{question}"),
+            Variable::Period => f.write_str("{period}"),
+            Variable::Start => f.write_str("{start}"),
+            Variable::End => f.write_str("{end}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Variable(Variable),
+}
+
+/// A parsed template. Braces not enclosing a lowercase name, like the ones of
+/// the JSON examples in the prompts, are kept as text.
+#[derive(Debug)]
+struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parses `template` of the prompt `name`, listing every problem found in
+    /// the error.
+    fn parse(name: PromptName, template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut errors = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let placeholder = after
+                .find('}')
+                .map(|end| &after[..end])
+                .filter(|placeholder| is_placeholder(placeholder));
+            let Some(placeholder) = placeholder else {
+                text.push('{');
+                rest = after;
+                continue;
+            };
+            match Variable::parse(placeholder) {
+                Ok(variable) if !name.allows(variable) => errors.push(format!(
+                    "{} cannot be used in the {} prompt",
+                    variable, name
+                )),
+                Ok(variable) => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Variable(variable));
+                }
+                Err(e) => errors.push(e),
+            }
+            rest = &after[placeholder.len() + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        let template = Self { segments };
+        for required in name.call_variables().0 {
+            if !template.variables().any(|variable| variable == *required) {
+                errors.push(format!("the template must contain {}", required));
+            }
+        }
+        if errors.is_empty() {
+            Ok(template)
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    fn variables(&self) -> impl Iterator<Item = Variable> + '_ {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable(variable) => Some(*variable),
+            Segment::Text(_) => None,
+        })
+    }
+}
+
+fn is_placeholder(placeholder: &str) -> bool {
+    placeholder.starts_with(|c: char| c.is_ascii_lowercase())
+        && placeholder
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == ':')
+}
+
+/// A template filled in but for the variables given with each model call,
+/// like the content of the note, which are added by `render`.
+pub struct PreparedPrompt {
+    segments: Vec<Segment>,
+    /// Version of the template, see `config::prompt_version`.
+    pub version: String,
+}
+
+impl PreparedPrompt {
+    /// The prompt for a note with `content`.
+    pub fn render(&self, content: &str) -> String {
+        self.render_with(&[(Variable::NoteContent, content)])
+    }
+
+    /// The prompt with the `values` of the variables. Variables without a
+    /// value are kept as they are written. The values are inserted as is, a
+    /// note mentioning `{question}` stays as written.
+    pub fn render_with(&self, values: &[(Variable, &str)]) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Variable(variable) => {
+                    match values.iter().find(|(name, _)| name == variable) {
+                        Some((_, value)) => rendered.push_str(value),
+                        None => rendered.push_str(&variable.to_string()),
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// Checks the configured prompts at startup, so a broken `*_PROMPT`
+/// variable fails right away instead of on the first note.
+pub fn check_configured(state: &AppState) -> anyhow::Result<()> {
+    for name in PromptName::ALL {
+        Template::parse(name, name.configured(state))
+            .map_err(|e| anyhow::anyhow!("Invalid {} prompt: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// The latest stored version of `name`.
+async fn fetch_latest(
+    pool: &SqlitePool,
+    name: PromptName,
+) -> Result<Option<StoredPrompt>, sqlx::Error> {
+    sqlx::query_as::<_, StoredPrompt>(&format!(
+        "SELECT {} FROM prompts WHERE name = ? ORDER BY version DESC LIMIT 1",
+        PROMPT_COLUMNS
+    ))
+    .bind(name)
+    .fetch_optional(pool)
+    .await
+}
+
+/// The template in use for task `name`, the latest stored one or the
+/// configured one.
+async fn template_in_use(
+    state: &AppState,
+    name: PromptName,
+) -> Result<String, (StatusCode, String)> {
+    match fetch_latest(&state.pool, name).await {
+        Ok(Some(stored)) => Ok(stored.template),
+        Ok(None) => Ok(name.configured(state).to_string()),
+        Err(e) => {
+            error!("Failed to fetch prompt: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch prompt: {}", e),
+            ))
+        }
+    }
+}
+
+/// The prompt of task `name` for note `id`. Fails if a variable cannot be
+/// filled in.
+pub async fn note_prompt(
+    state: &AppState,
+    name: PromptName,
+    id: i64,
+) -> Result<PreparedPrompt, (StatusCode, String)> {
+    let template = template_in_use(state, name).await?;
+    prepare(&state.pool, name, &template, Some(id)).await
+}
+
+/// The prompt of task `name`, which is not about a single note. Fails if a
+/// variable cannot be filled in.
+pub async fn prompt(
+    state: &AppState,
+    name: PromptName,
+) -> Result<PreparedPrompt, (StatusCode, String)> {
+    let template = template_in_use(state, name).await?;
+    prepare(&state.pool, name, &template, None).await
+}
+
+async fn prepare(
+    pool: &SqlitePool,
+    name: PromptName,
+    template: &str,
+    id: Option<i64>,
+) -> Result<PreparedPrompt, (StatusCode, String)> {
+    let parsed = Template::parse(name, template).map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid {} prompt: {}", name, e),
+        )
+    })?;
+    let segments = fill(pool, name, parsed, id).await?;
+    Ok(PreparedPrompt {
+        segments,
+        version: config::prompt_version(template),
+    })
+}
+
+/// The note the note variables are filled in from.
+#[derive(Debug, FromRow)]
+struct FilledNote {
+    id: i64,
+    created_at: DateTime<Utc>,
+    category: Category,
+    explanation: String,
+}
+
+/// Replaces the variables of `template` with the values for note `id` and
+/// the user profile, keeping the ones given with each model call.
+async fn fill(
+    pool: &SqlitePool,
+    name: PromptName,
+    template: Template,
+    id: Option<i64>,
+) -> Result<Vec<Segment>, (StatusCode, String)> {
+    let note = match id {
+        Some(id) => {
+            let note = sqlx::query_as::<_, FilledNote>(
+                "SELECT n.id, n.created_at, cd.category, cd.explanation
+                 FROM notes n
+                 JOIN category_descriptions cd ON n.category_id = cd.id
+                 WHERE n.id = ?",
+            )
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| {
+                error!("Failed to fetch note: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch note: {}", e),
+                )
+            })?;
+            Some(note.ok_or((StatusCode::NOT_FOUND, "Note not found".to_string()))?)
+        }
+        None => None,
+    };
+
+    let mut segments = Vec::with_capacity(template.segments.len());
+    for segment in template.segments {
+        let text = match (segment, &note) {
+            (Segment::Text(text), _) => text,
+            (Segment::Variable(Variable::NoteDate), Some(note)) => {
+                note.created_at.format("%Y-%m-%d, %A").to_string()
+            }
+            (Segment::Variable(Variable::Category), Some(note)) => note.category.to_string(),
+            (Segment::Variable(Variable::CategoryExplanation), Some(note)) => {
+                note.explanation.clone()
+            }
+            (Segment::Variable(Variable::PreviousNotes(count)), Some(note)) => {
+                previous_notes(pool, note.id, note.created_at, count)
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to fetch previous notes: {}", e);
+                        (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Failed to fetch previous notes: {}", e),
+                        )
+                    })?
+            }
+            (Segment::Variable(Variable::UserProfile), _) => fetch_profile(pool)
+                .await
+                .map_err(|e| {
+                    error!("Failed to fetch profile: {}", e);
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to fetch profile: {}", e),
+                    )
+                })?
+                .map(|profile| profile.content)
+                .filter(|content| !content.trim().is_empty())
+                .ok_or_else(|| {
+                    (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        format!(
+                            "The {} prompt uses {{user_profile}} but no profile is set",
+                            name
+                        ),
+                    )
+                })?,
+            (segment, _) => {
+                segments.push(segment);
+                continue;
+            }
+        };
+        segments.push(Segment::Text(text));
+    }
+    Ok(segments)
+}
+
+/// The `count` notes written before note `id`, oldest first.
+async fn previous_notes(
+    pool: &SqlitePool,
+    id: i64,
+    created_at: DateTime<Utc>,
+    count: usize,
+) -> Result<String, sqlx::Error> {
+    let mut notes: Vec<(DateTime<Utc>, Category, String)> = sqlx::query_as(
+        "SELECT n.created_at, cd.category, n.content
+         FROM notes n
+         JOIN category_descriptions cd ON n.category_id = cd.id
+         WHERE n.created_at < ? OR (n.created_at = ? AND n.id < ?)
+         ORDER BY n.created_at DESC, n.id DESC
+         LIMIT ?",
+    )
+    .bind(created_at)
+    .bind(created_at)
+    .bind(id)
+    .bind(count as i64)
+    .fetch_all(pool)
+    .await?;
+    if notes.is_empty() {
+        return Ok("(No earlier diary entries.)".to_string());
+    }
+    notes.reverse();
+    Ok(notes
+        .iter()
+        .map(|(created_at, category, content)| {
+            format!(
+                "### {} ({})\n{}",
+                created_at.format("%Y-%m-%d, %A"),
+                category,
+                chunking::truncate_to_tokens(content.trim(), PREVIOUS_NOTE_TOKENS)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+#[derive(Debug, FromRow)]
+struct StoredPrompt {
+    id: i64,
+    name: PromptName,
+    version: i64,
+    template: String,
+    description: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Prompt {
+    /// None for the configured prompt.
+    pub id: Option<i64>,
+    pub name: PromptName,
+    /// None for the configured prompt.
+    pub version: Option<i64>,
+    pub template: String,
+    pub description: Option<String>,
+    /// The variables the template uses.
+    pub variables: Vec<String>,
+    /// Stored with the results of the template, see `config::prompt_version`.
+    pub prompt_version: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl Prompt {
+    fn new(name: PromptName, template: String) -> Self {
+        // Stored and configured templates have been parsed before
+        let variables = Template::parse(name, &template)
+            .map(|parsed| parsed.variables().map(|v| v.to_string()).collect())
+            .unwrap_or_default();
+        Self {
+            id: None,
+            name,
+            version: None,
+            prompt_version: config::prompt_version(&template),
+            template,
+            description: None,
+            variables,
+            created_at: None,
+        }
+    }
+
+    fn configured(state: &AppState, name: PromptName) -> Self {
+        Self::new(name, name.configured(state).to_string())
+    }
+}
+
+impl From<StoredPrompt> for Prompt {
+    fn from(stored: StoredPrompt) -> Self {
+        Self {
+            id: Some(stored.id),
+            version: Some(stored.version),
+            description: stored.description,
+            created_at: Some(stored.created_at),
+            ..Self::new(stored.name, stored.template)
+        }
+    }
+}
+
+fn parse_name(name: &str) -> Result<PromptName, (StatusCode, String)> {
+    name.parse()
+        .map_err(|_| (StatusCode::NOT_FOUND, format!("Unknown prompt: {}", name)))
+}
+
+/// The prompts in use for every task.
+pub async fn list_prompts(State(state): State<AppState>) -> impl IntoResponse {
+    let mut stored = match sqlx::query_as::<_, StoredPrompt>(&format!(
+        "SELECT {} FROM prompts p
+         WHERE version = (SELECT MAX(version) FROM prompts WHERE name = p.name)",
+        PROMPT_COLUMNS
+    ))
+    .fetch_all(&*state.pool)
+    .await
+    {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Failed to fetch prompts: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch prompts: {}", e),
+            )
+                .into_response();
+        }
+    };
+
+    let prompts: Vec<Prompt> = PromptName::ALL
+        .into_iter()
+        .map(
+            |name| match stored.iter().position(|prompt| prompt.name == name) {
+                Some(index) => Prompt::from(stored.swap_remove(index)),
+                None => Prompt::configured(&state, name),
+            },
+        )
+        .collect();
+    (StatusCode::OK, Json(prompts)).into_response()
+}
+
+/// The prompt in use for task `name`.
+pub async fn get_prompt(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    match fetch_latest(&state.pool, name).await {
+        Ok(stored) => {
+            let prompt = stored
+                .map(Prompt::from)
+                .unwrap_or_else(|| Prompt::configured(&state, name));
+            (StatusCode::OK, Json(prompt)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to fetch prompt {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch prompt: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromptRequest {
+    pub template: String,
+    pub description: Option<String>,
+}
+
+/// Stores `template` as the next version of prompt `name`.
+pub async fn update_prompt(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<PromptRequest>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    if let Err(e) = Template::parse(name, &request.template) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid template: {}", e)).into_response();
+    }
+
+    match sqlx::query_as::<_, StoredPrompt>(&format!(
+        "INSERT INTO prompts (name, version, template, description, created_at)
+         SELECT ?, COALESCE(MAX(version), 0) + 1, ?, ?, ? FROM prompts WHERE name = ?
+         RETURNING {}",
+        PROMPT_COLUMNS
+    ))
+    .bind(name)
+    .bind(&request.template)
+    .bind(&request.description)
+    .bind(Utc::now())
+    .bind(name)
+    .fetch_one(&*state.pool)
+    .await
+    {
+        Ok(stored) => {
+            info!("Stored version {} of the {} prompt", stored.version, name);
+            (StatusCode::CREATED, Json(Prompt::from(stored))).into_response()
+        }
+        Err(e) => {
+            error!("Failed to store prompt {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store prompt: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Deletes every version of prompt `name`, the configured prompt is used
+/// again.
+pub async fn delete_prompt(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    match sqlx::query("DELETE FROM prompts WHERE name = ?")
+        .bind(name)
+        .execute(&*state.pool)
+        .await
+    {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("Failed to delete prompt {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to delete prompt: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The stored versions of prompt `name`, newest first.
+pub async fn list_versions(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    match sqlx::query_as::<_, StoredPrompt>(&format!(
+        "SELECT {} FROM prompts WHERE name = ? ORDER BY version DESC",
+        PROMPT_COLUMNS
+    ))
+    .bind(name)
+    .fetch_all(&*state.pool)
+    .await
+    {
+        Ok(stored) => {
+            let prompts: Vec<Prompt> = stored.into_iter().map(Prompt::from).collect();
+            (StatusCode::OK, Json(prompts)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to fetch prompts: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch prompts: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn get_version(
+    Path((name, version)): Path<(String, i64)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    match sqlx::query_as::<_, StoredPrompt>(&format!(
+        "SELECT {} FROM prompts WHERE name = ? AND version = ?",
+        PROMPT_COLUMNS
+    ))
+    .bind(name)
+    .bind(version)
+    .fetch_optional(&*state.pool)
+    .await
+    {
+        Ok(Some(stored)) => (StatusCode::OK, Json(Prompt::from(stored))).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Prompt version not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch prompt: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch prompt: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Deletes one version of prompt `name`. Deleting the latest version rolls
+/// back to the one before.
+pub async fn delete_version(
+    Path((name, version)): Path<(String, i64)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    match sqlx::query("DELETE FROM prompts WHERE name = ? AND version = ?")
+        .bind(name)
+        .bind(version)
+        .execute(&*state.pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => {
+            (StatusCode::NOT_FOUND, "Prompt version not found").into_response()
+        }
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("Failed to delete prompt: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to delete prompt: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenderRequest {
+    /// Required for the prompts about a single note.
+    pub note_id: Option<i64>,
+    /// Renders this template instead of the one in use, to try it before
+    /// storing it.
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RenderedPrompt {
+    pub name: PromptName,
+    pub prompt_version: String,
+    /// The prompt as it would be sent to the model.
+    pub prompt: String,
+    /// Estimated size of the prompt.
+    pub tokens: usize,
+}
+
+/// Fills in prompt `name` without calling the model, for a note if the
+/// prompt is about one. Variables given with each model call but the note
+/// content, like `{question}`, are kept as they are written.
+pub async fn render_prompt(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<RenderRequest>,
+) -> impl IntoResponse {
+    let name = match parse_name(&name) {
+        Ok(name) => name,
+        Err(response) => return response.into_response(),
+    };
+    let note_id = request.note_id.filter(|_| name.uses_note());
+    if name.uses_note() && note_id.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("The {} prompt needs a note_id", name),
+        )
+            .into_response();
+    }
+    let template = match &request.template {
+        Some(template) => template.clone(),
+        None => match template_in_use(&state, name).await {
+            Ok(template) => template,
+            Err(response) => return response.into_response(),
+        },
+    };
+    if let Err(e) = Template::parse(name, &template) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid template: {}", e)).into_response();
+    }
+    let prompt = match prepare(&state.pool, name, &template, note_id).await {
+        Ok(prompt) => prompt,
+        Err(response) => return response.into_response(),
+    };
+
+    let text = match note_id {
+        Some(note_id) => {
+            let content: String = match sqlx::query_scalar("SELECT content FROM notes WHERE id = ?")
+                .bind(note_id)
+                .fetch_one(&*state.pool)
+                .await
+            {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Failed to fetch note: {}", e);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to fetch note: {}", e),
+                    )
+                        .into_response();
+                }
+            };
+            prompt.render(&content)
+        }
+        None => prompt.render_with(&[]),
+    };
+    let rendered = RenderedPrompt {
+        name,
+        prompt_version: prompt.version,
+        tokens: chunking::estimate_tokens(&text),
+        prompt: text,
+    };
+    (StatusCode::OK, Json(rendered)).into_response()
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Profile {
+    pub content: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+async fn fetch_profile(pool: &SqlitePool) -> Result<Option<Profile>, sqlx::Error> {
+    sqlx::query_as::<_, Profile>("SELECT content, updated_at FROM user_profile WHERE id = 1")
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn get_profile(State(state): State<AppState>) -> impl IntoResponse {
+    match fetch_profile(&state.pool).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(profile)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Profile not set").into_response(),
+        Err(e) => {
+            error!("Failed to fetch profile: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch profile: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileRequest {
+    pub content: String,
+}
+
+pub async fn update_profile(
+    State(state): State<AppState>,
+    Json(request): Json<ProfileRequest>,
+) -> impl IntoResponse {
+    match sqlx::query_as::<_, Profile>(
+        "INSERT INTO user_profile (id, content, updated_at) VALUES (1, ?, ?)
+         ON CONFLICT(id) DO UPDATE SET
+            content = excluded.content,
+            updated_at = excluded.updated_at
+         RETURNING content, updated_at",
+    )
+    .bind(request.content.trim())
+    .bind(Utc::now())
+    .fetch_one(&*state.pool)
+    .await
+    {
+        Ok(profile) => (StatusCode::OK, Json(profile)).into_response(),
+        Err(e) => {
+            error!("Failed to store profile: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store profile: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::Duration;
+
+    fn parse_error(name: PromptName, template: &str) -> String {
+        Template::parse(name, template).unwrap_err()
+    }
+
+    /// Inserts a note written `days_ago` days ago.
+    async fn insert_note(pool: &SqlitePool, content: &str, days_ago: i64) -> i64 {
+        let id = testing::insert_note(pool, content, "personal").await;
+        sqlx::query("UPDATE notes SET created_at = ? WHERE id = ?")
+            .bind(Utc::now() - Duration::days(days_ago))
+            .bind(id)
+            .execute(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn fill_in(state: &AppState, name: PromptName, template: &str, id: i64) -> String {
+        prepare(&state.pool, name, template, Some(id))
+            .await
+            .unwrap()
+            .render("NOTE")
+    }
+
+    #[test]
+    fn configured_prompts_are_valid() {
+        let templates = [
+            (PromptName::Analysis, config::DETAILED_DIARY_ANALYSIS_PROMPT),
+            (
+                PromptName::Categorization,
+                config::DIARY_CATEGORIZATION_PROMPT,
+            ),
+            (PromptName::Mood, config::MOOD_PROMPT),
+            (PromptName::Entities, config::ENTITY_PROMPT),
+            (PromptName::Tasks, config::TASK_PROMPT),
+            (PromptName::ChunkAnalysis, config::CHUNK_ANALYSIS_PROMPT),
+            (
+                PromptName::AnalysisSynthesis,
+                config::ANALYSIS_SYNTHESIS_PROMPT,
+            ),
+            (PromptName::Ask, config::ASK_DIARY_PROMPT),
+            (PromptName::Digest, config::DIGEST_PROMPT),
+        ];
+        for (name, template) in templates {
+            assert!(Template::parse(name, template).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        assert_eq!(
+            parse_error(PromptName::Mood, "{note_content} {weather}"),
+            "unknown variable {weather}"
+        );
+        assert_eq!(
+            parse_error(PromptName::Mood, "{note_content} {category:work}"),
+            "{category} takes no argument"
+        );
+    }
+
+    #[test]
+    fn every_problem_is_listed() {
+        assert_eq!(
+            parse_error(PromptName::Analysis, "{weather} and {mood}"),
+            "unknown variable {weather}, unknown variable {mood}, \
+             the template must contain {note_content}"
+        );
+    }
+
+    #[test]
+    fn variables_of_other_tasks_are_rejected() {
+        assert_eq!(
+            parse_error(PromptName::Mood, "{note_content} {question}"),
+            "{question} cannot be used in the mood prompt"
+        );
+        assert_eq!(
+            parse_error(PromptName::Ask, "{note_date} {notes} {question}"),
+            "{note_date} cannot be used in the ask prompt"
+        );
+        assert_eq!(
+            parse_error(
+                PromptName::AnalysisSynthesis,
+                "{part_analyses} {note_content}"
+            ),
+            "{note_content} cannot be used in the analysis_synthesis prompt"
+        );
+    }
+
+    #[test]
+    fn required_variables_of_the_task() {
+        assert_eq!(
+            parse_error(PromptName::Ask, "{notes}"),
+            "the template must contain {question}"
+        );
+        assert_eq!(
+            parse_error(PromptName::Digest, "The {period} from {start} to {end}"),
+            "the template must contain {notes}"
+        );
+        assert!(Template::parse(PromptName::ChunkAnalysis, "{note_content}").is_ok());
+        assert!(Template::parse(PromptName::Ask, "{user_profile} {notes} {question}").is_ok());
+    }
+
+    #[test]
+    fn other_braces_are_text() {
+        let template = r#"Answer {"mood": 3} or {Note} {} {note_content}"#;
+        let parsed = Template::parse(PromptName::Mood, template).unwrap();
+        assert_eq!(
+            parsed.variables().collect::<Vec<_>>(),
+            [Variable::NoteContent]
+        );
+    }
+
+    #[test]
+    fn previous_notes_count() {
+        let parse = |template: &str| {
+            Template::parse(PromptName::Analysis, template)
+                .map(|parsed| parsed.variables().collect::<Vec<_>>())
+        };
+        assert_eq!(
+            parse("{previous_notes} {note_content}").unwrap(),
+            [Variable::PreviousNotes(3), Variable::NoteContent]
+        );
+        assert_eq!(
+            parse("{previous_notes:10} {note_content}").unwrap(),
+            [Variable::PreviousNotes(10), Variable::NoteContent]
+        );
+        for count in ["0", "11", "two"] {
+            assert_eq!(
+                parse(&format!("{{previous_notes:{}}} {{note_content}}", count)).unwrap_err(),
+                format!(
+                    "{{previous_notes:{}}} needs a number of notes from 1 to 10",
+                    count
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn values_are_inserted_as_written() {
+        let template = Template::parse(PromptName::Ask, "Q: {question}\n{notes}").unwrap();
+        let prompt = PreparedPrompt {
+            segments: template.segments,
+            version: String::new(),
+        };
+        assert_eq!(
+            prompt.render_with(&[(Variable::Notes, "About {question}")]),
+            "Q: {question}\nAbout {question}"
+        );
+    }
+
+    #[tokio::test]
+    async fn note_variables_are_filled_in() {
+        let state = testing::state(["unused"]).await;
+        let id = insert_note(&state.pool, "A day at home.", 0).await;
+        let prompt = fill_in(
+            &state,
+            PromptName::Mood,
+            "{category}|{note_date}|{note_content}",
+            id,
+        )
+        .await;
+        let date = Utc::now().format("%Y-%m-%d, %A").to_string();
+        assert_eq!(prompt, format!("Personal|{}|NOTE", date));
+    }
+
+    #[tokio::test]
+    async fn missing_note_fails() {
+        let state = testing::state(["unused"]).await;
+        let result = note_prompt(&state, PromptName::Mood, 999).await;
+        assert_eq!(
+            result.err(),
+            Some((StatusCode::NOT_FOUND, "Note not found".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_profile_fails_before_the_model_is_called() {
+        let state = testing::state(["unused"]).await;
+        let id = insert_note(&state.pool, "A day at home.", 0).await;
+        let template = "{user_profile}\n{note_content}";
+
+        let result = prepare(&state.pool, PromptName::Mood, template, Some(id)).await;
+        assert_eq!(
+            result.err(),
+            Some((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The mood prompt uses {user_profile} but no profile is set".to_string()
+            ))
+        );
+
+        sqlx::query("INSERT INTO user_profile (id, content, updated_at) VALUES (1, ?, ?)")
+            .bind("I am a nurse.")
+            .bind(Utc::now())
+            .execute(&*state.pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            fill_in(&state, PromptName::Mood, template, id).await,
+            "I am a nurse.\nNOTE"
+        );
+    }
+
+    #[tokio::test]
+    async fn previous_notes_are_oldest_first() {
+        let state = testing::state(["unused"]).await;
+        let mut ids = Vec::new();
+        for days_ago in (0..5).rev() {
+            let content = format!("Written {} days ago.", days_ago);
+            ids.push(insert_note(&state.pool, &content, days_ago).await);
+        }
+        let template = "{previous_notes:2}\n---\n{note_content}";
+
+        let prompt = fill_in(&state, PromptName::Analysis, template, ids[4]).await;
+        let previous = prompt.split("\n---\n").next().unwrap();
+        assert_eq!(previous.matches("### ").count(), 2);
+        let two = previous.find("Written 2 days ago.").unwrap();
+        let one = previous.find("Written 1 days ago.").unwrap();
+        assert!(two < one);
+        assert!(!previous.contains("Written 0 days ago."));
+        assert!(!previous.contains("Written 3 days ago."));
+
+        let prompt = fill_in(&state, PromptName::Analysis, template, ids[0]).await;
+        assert!(prompt.starts_with("(No earlier diary entries.)\n---\n"));
+    }
+}
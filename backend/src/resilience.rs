@@ -0,0 +1,377 @@
+//! Retries with exponential backoff and a circuit breaker around an LLM
+//! backend, so a model server that is restarting or overloaded does not fail
+//! every request, and one that is down fails them fast.
+
+use crate::config::ResilienceSettings;
+use crate::llm::{ChatRequest, GenerateRequest, Generation, LlmBackend, LlmError, TokenStream};
+use async_trait::async_trait;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Upper bound of a single backoff delay.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+pub struct ResilientBackend {
+    inner: Arc<dyn LlmBackend>,
+    settings: ResilienceSettings,
+    breaker: Mutex<Breaker>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// Calls fail fast until then. Afterwards calls are let through again,
+    /// a single failure reopens the circuit.
+    open_until: Option<Instant>,
+}
+
+impl ResilientBackend {
+    pub fn new(inner: Arc<dyn LlmBackend>, settings: ResilienceSettings) -> Self {
+        Self {
+            inner,
+            settings,
+            breaker: Mutex::default(),
+        }
+    }
+
+    /// Runs `call`, repeating it after transient failures.
+    async fn call<T, F, Fut>(&self, call: F) -> Result<T, LlmError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, LlmError>>,
+    {
+        let mut retry = 0;
+        loop {
+            self.check_circuit()?;
+            match call().await {
+                Ok(value) => {
+                    self.record_success();
+                    return Ok(value);
+                }
+                Err(err) if err.is_transient() => {
+                    self.record_failure();
+                    if retry >= self.settings.max_retries {
+                        return Err(err);
+                    }
+                    let delay = self.backoff(retry);
+                    retry += 1;
+                    warn!(
+                        %err,
+                        "LLM call failed, retry {} of {} in {:?}",
+                        retry,
+                        self.settings.max_retries,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Exponential backoff with jitter, so callers failing at the same time
+    /// do not retry in lockstep.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .settings
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_RETRY_DELAY);
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(millis / 2 + fastrand::u64(0..=millis / 2))
+    }
+
+    fn check_circuit(&self) -> Result<(), LlmError> {
+        let breaker = self.breaker.lock().unwrap();
+        match breaker.open_until {
+            Some(until) if until > Instant::now() => Err(LlmError::Unavailable(format!(
+                "{} consecutive failures, retrying in {}s",
+                breaker.consecutive_failures,
+                until.saturating_duration_since(Instant::now()).as_secs() + 1
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.open_until.is_some() {
+            info!(
+                "LLM backend {} recovered, closing circuit",
+                self.inner.name()
+            );
+        }
+        *breaker = Breaker::default();
+    }
+
+    fn record_failure(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= self.settings.breaker_threshold {
+            if breaker
+                .open_until
+                .is_none_or(|until| until <= Instant::now())
+            {
+                warn!(
+                    "LLM backend {} failed {} times in a row, failing fast for {:?}",
+                    self.inner.name(),
+                    breaker.consecutive_failures,
+                    self.settings.breaker_cooldown
+                );
+            }
+            breaker.open_until = Some(Instant::now() + self.settings.breaker_cooldown);
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for ResilientBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn default_model(&self) -> &str {
+        self.inner.default_model()
+    }
+
+    async fn generate(&self, request: GenerateRequest) -> Result<Generation, LlmError> {
+        self.call(|| self.inner.generate(request.clone())).await
+    }
+
+    /// Only establishing the stream is retried, tokens already sent to the
+    /// client cannot be taken back.
+    async fn stream(&self, request: GenerateRequest) -> Result<TokenStream, LlmError> {
+        self.call(|| self.inner.stream(request.clone())).await
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<Generation, LlmError> {
+        self.call(|| self.inner.chat(request.clone())).await
+    }
+
+    async fn embed(&self, model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        self.call(|| self.inner.embed(model, input.clone())).await
+    }
+
+    async fn health(&self) -> Result<(), LlmError> {
+        self.inner.health().await
+    }
+
+    fn circuit_open(&self) -> bool {
+        self.check_circuit().is_err()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{StreamEvent, Usage};
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Fails with the scripted errors in order, then succeeds.
+    struct FlakyBackend {
+        errors: Mutex<VecDeque<LlmError>>,
+        calls: AtomicUsize,
+    }
+
+    impl FlakyBackend {
+        fn failing(errors: impl IntoIterator<Item = LlmError>) -> Arc<Self> {
+            Arc::new(Self {
+                errors: Mutex::new(errors.into_iter().collect()),
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn next(&self) -> Result<(), LlmError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.errors.lock().unwrap().pop_front() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmBackend for FlakyBackend {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn default_model(&self) -> &str {
+            "flaky"
+        }
+
+        async fn generate(&self, _request: GenerateRequest) -> Result<Generation, LlmError> {
+            self.next().map(|()| Generation {
+                text: "ok".to_string(),
+                model: "flaky".to_string(),
+                usage: Usage::default(),
+            })
+        }
+
+        async fn stream(&self, _request: GenerateRequest) -> Result<TokenStream, LlmError> {
+            self.next()?;
+            let done = StreamEvent::Done {
+                model: "flaky".to_string(),
+                usage: Usage::default(),
+            };
+            Ok(Box::pin(futures_util::stream::iter([Ok(done)])))
+        }
+
+        async fn chat(&self, _request: ChatRequest) -> Result<Generation, LlmError> {
+            unimplemented!()
+        }
+
+        async fn embed(
+            &self,
+            _model: &str,
+            _input: Vec<String>,
+        ) -> Result<Vec<Vec<f32>>, LlmError> {
+            unimplemented!()
+        }
+
+        async fn health(&self) -> Result<(), LlmError> {
+            Ok(())
+        }
+    }
+
+    fn settings(max_retries: u32, breaker_threshold: u32) -> ResilienceSettings {
+        ResilienceSettings {
+            max_retries,
+            retry_delay: Duration::from_millis(1),
+            breaker_threshold,
+            breaker_cooldown: Duration::from_millis(50),
+        }
+    }
+
+    fn server_error() -> LlmError {
+        LlmError::Status {
+            status: 503,
+            body: "overloaded".to_string(),
+        }
+    }
+
+    fn request() -> GenerateRequest {
+        GenerateRequest::new("prompt")
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let inner =
+            FlakyBackend::failing([LlmError::Request("refused".to_string()), server_error()]);
+        let backend = ResilientBackend::new(inner.clone(), settings(3, 10));
+
+        let generation = backend.generate(request()).await.unwrap();
+        assert_eq!(generation.text, "ok");
+        assert_eq!(inner.calls(), 3);
+        assert!(!backend.circuit_open());
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let inner = FlakyBackend::failing((0..5).map(|_| server_error()));
+        let backend = ResilientBackend::new(inner.clone(), settings(2, 10));
+
+        let err = backend.generate(request()).await.unwrap_err();
+        assert!(matches!(err, LlmError::Status { status: 503, .. }));
+        assert_eq!(inner.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let inner = FlakyBackend::failing([LlmError::Status {
+            status: 404,
+            body: "model not found".to_string(),
+        }]);
+        let backend = ResilientBackend::new(inner.clone(), settings(3, 10));
+
+        assert!(backend.generate(request()).await.is_err());
+        assert_eq!(inner.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn streams_are_retried_until_established() {
+        let inner = FlakyBackend::failing([LlmError::Request("refused".to_string())]);
+        let backend = ResilientBackend::new(inner.clone(), settings(3, 10));
+
+        assert!(backend.stream(request()).await.is_ok());
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        let backend = ResilientBackend::new(
+            FlakyBackend::failing([]),
+            ResilienceSettings {
+                retry_delay: Duration::from_millis(100),
+                ..settings(3, 10)
+            },
+        );
+        for retry in 0..4 {
+            let full = Duration::from_millis(100 * 2u64.pow(retry));
+            let delay = backend.backoff(retry);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+        assert!(backend.backoff(20) <= MAX_RETRY_DELAY);
+        assert!(backend.backoff(20) >= MAX_RETRY_DELAY / 2);
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_consecutive_failures() {
+        let inner = FlakyBackend::failing((0..2).map(|_| server_error()));
+        let backend = ResilientBackend::new(inner.clone(), settings(0, 2));
+
+        assert!(backend.generate(request()).await.is_err());
+        assert!(!backend.circuit_open());
+        assert!(backend.generate(request()).await.is_err());
+        assert!(backend.circuit_open());
+
+        // Fails fast without calling the backend
+        let err = backend.generate(request()).await.unwrap_err();
+        assert!(matches!(err, LlmError::Unavailable(_)));
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn half_open_circuit_closes_on_success() {
+        let inner = FlakyBackend::failing((0..2).map(|_| server_error()));
+        let backend = ResilientBackend::new(inner.clone(), settings(0, 2));
+        for _ in 0..2 {
+            let _ = backend.generate(request()).await;
+        }
+        assert!(backend.circuit_open());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!backend.circuit_open());
+        assert!(backend.generate(request()).await.is_ok());
+        assert_eq!(inner.calls(), 3);
+        assert!(!backend.circuit_open());
+    }
+
+    #[tokio::test]
+    async fn half_open_circuit_reopens_on_a_single_failure() {
+        let inner = FlakyBackend::failing((0..3).map(|_| server_error()));
+        let backend = ResilientBackend::new(inner.clone(), settings(0, 2));
+        for _ in 0..2 {
+            let _ = backend.generate(request()).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(backend.generate(request()).await.is_err());
+        assert_eq!(inner.calls(), 3);
+        assert!(backend.circuit_open());
+        let err = backend.generate(request()).await.unwrap_err();
+        assert!(matches!(err, LlmError::Unavailable(_)));
+        assert_eq!(inner.calls(), 3);
+    }
+}
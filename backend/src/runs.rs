@@ -0,0 +1,267 @@
+//! Bookkeeping of every model call, so the time and tokens spent on the
+//! different tasks can be looked at later.
+
+use crate::llm::{Generation, LlmError, Usage};
+use crate::llm_json::RepairError;
+use crate::models::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, QueryBuilder, Sqlite};
+use tracing::error;
+
+static DEFAULT_LIMIT: i64 = 100;
+static MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RunKind {
+    Analyze,
+    Categorize,
+    Generate,
+    Ask,
+    Digest,
+    Mood,
+    Entities,
+    Tasks,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RunStatus {
+    Success,
+    Error,
+    /// The client went away before the model finished.
+    Cancelled,
+}
+
+/// A model call to be recorded.
+#[derive(Debug, Clone)]
+pub struct NewRun {
+    pub kind: RunKind,
+    pub note_id: Option<i64>,
+    pub model: String,
+    pub prompt_version: Option<String>,
+    pub usage: Usage,
+    pub status: RunStatus,
+    pub error: Option<String>,
+}
+
+impl NewRun {
+    pub fn success(kind: RunKind, note_id: Option<i64>, generation: &Generation) -> Self {
+        Self::completed(kind, note_id, &generation.model, &generation.usage)
+    }
+
+    pub fn completed(kind: RunKind, note_id: Option<i64>, model: &str, usage: &Usage) -> Self {
+        Self {
+            kind,
+            note_id,
+            model: model.to_string(),
+            prompt_version: None,
+            usage: usage.clone(),
+            status: RunStatus::Success,
+            error: None,
+        }
+    }
+
+    pub fn failure(kind: RunKind, note_id: Option<i64>, model: &str, err: &LlmError) -> Self {
+        Self {
+            kind,
+            note_id,
+            model: model.to_string(),
+            prompt_version: None,
+            usage: Usage::default(),
+            status: RunStatus::Error,
+            error: Some(err.to_string()),
+        }
+    }
+
+    /// The JSON repair call behind `err`, see `llm_json::extract_or_repair`.
+    pub fn repair_failure(
+        kind: RunKind,
+        note_id: Option<i64>,
+        model: &str,
+        err: &RepairError,
+    ) -> Self {
+        match err {
+            RepairError::Llm(err) => Self::failure(kind, note_id, model, err),
+            RepairError::Invalid(_, generation) => Self::success(kind, note_id, generation),
+        }
+    }
+
+    pub fn cancelled(kind: RunKind, note_id: Option<i64>, model: &str) -> Self {
+        Self {
+            kind,
+            note_id,
+            model: model.to_string(),
+            prompt_version: None,
+            usage: Usage::default(),
+            status: RunStatus::Cancelled,
+            error: None,
+        }
+    }
+
+    pub fn prompt_version(mut self, version: &str) -> Self {
+        self.prompt_version = Some(version.to_string());
+        self
+    }
+}
+
+/// Stores `run`. Failures are only logged, bookkeeping must never fail the
+/// request that made the model call.
+pub async fn record_run(pool: &SqlitePool, run: NewRun) {
+    let result = sqlx::query(
+        "INSERT INTO llm_runs (
+            note_id, kind, model, prompt_version,
+            prompt_tokens, completion_tokens,
+            total_duration_ms, load_duration_ms, prompt_eval_duration_ms, eval_duration_ms,
+            status, error, created_at
+         )
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(run.note_id)
+    .bind(run.kind)
+    .bind(&run.model)
+    .bind(&run.prompt_version)
+    .bind(run.usage.prompt_tokens as i64)
+    .bind(run.usage.completion_tokens as i64)
+    .bind(run.usage.total_duration.as_millis() as i64)
+    .bind(run.usage.load_duration.as_millis() as i64)
+    .bind(run.usage.prompt_eval_duration.as_millis() as i64)
+    .bind(run.usage.eval_duration.as_millis() as i64)
+    .bind(run.status)
+    .bind(&run.error)
+    .bind(Utc::now())
+    .execute(pool)
+    .await;
+
+    if let Err(e) = result {
+        error!("Failed to record {:?} run: {}", run.kind, e);
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct LlmRun {
+    pub id: i64,
+    pub note_id: Option<i64>,
+    pub kind: RunKind,
+    pub model: String,
+    pub prompt_version: Option<String>,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_duration_ms: i64,
+    pub load_duration_ms: i64,
+    pub prompt_eval_duration_ms: i64,
+    pub eval_duration_ms: i64,
+    pub status: RunStatus,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct RunSummary {
+    pub runs: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_duration_ms: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunList {
+    /// Totals over all runs matching the filters, not just the returned page.
+    pub summary: RunSummary,
+    pub runs: Vec<LlmRun>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunFilter {
+    pub note_id: Option<i64>,
+    pub kind: Option<RunKind>,
+    pub status: Option<RunStatus>,
+    pub model: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+impl RunFilter {
+    fn push_where(&self, builder: &mut QueryBuilder<'_, Sqlite>) {
+        builder.push(" WHERE 1 = 1");
+        if let Some(note_id) = self.note_id {
+            builder.push(" AND note_id = ").push_bind(note_id);
+        }
+        if let Some(kind) = self.kind {
+            builder.push(" AND kind = ").push_bind(kind);
+        }
+        if let Some(status) = self.status {
+            builder.push(" AND status = ").push_bind(status);
+        }
+        if let Some(model) = &self.model {
+            builder.push(" AND model = ").push_bind(model.clone());
+        }
+        if let Some(since) = self.since {
+            builder.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = self.until {
+            builder.push(" AND created_at < ").push_bind(until);
+        }
+    }
+}
+
+async fn fetch_runs(pool: &SqlitePool, filter: &RunFilter) -> Result<RunList, sqlx::Error> {
+    let mut summary_query = QueryBuilder::new(
+        "SELECT COUNT(*) AS runs,
+                COALESCE(SUM(prompt_tokens), 0) AS prompt_tokens,
+                COALESCE(SUM(completion_tokens), 0) AS completion_tokens,
+                COALESCE(SUM(total_duration_ms), 0) AS total_duration_ms
+         FROM llm_runs",
+    );
+    filter.push_where(&mut summary_query);
+    let summary = summary_query
+        .build_query_as::<RunSummary>()
+        .fetch_one(pool)
+        .await?;
+
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut runs_query = QueryBuilder::new(
+        "SELECT id, note_id, kind, model, prompt_version,
+                prompt_tokens, completion_tokens,
+                total_duration_ms, load_duration_ms, prompt_eval_duration_ms, eval_duration_ms,
+                status, error, created_at
+         FROM llm_runs",
+    );
+    filter.push_where(&mut runs_query);
+    runs_query
+        .push(" ORDER BY created_at DESC, id DESC LIMIT ")
+        .push_bind(limit);
+    let runs = runs_query
+        .build_query_as::<LlmRun>()
+        .fetch_all(pool)
+        .await?;
+
+    Ok(RunList { summary, runs })
+}
+
+pub async fn list_runs(
+    State(state): State<AppState>,
+    Query(filter): Query<RunFilter>,
+) -> impl IntoResponse {
+    match fetch_runs(&state.pool, &filter).await {
+        Ok(runs) => (StatusCode::OK, Json(runs)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch LLM runs: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to fetch LLM runs".to_string(),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Bounds the number of concurrent model calls. A single local model server
+//! serves one or two generations at a time, so the excess is queued, with
+//! interactive requests going ahead of batch work.
+
+use crate::models::AppState;
+use axum::{extract::State, response::IntoResponse, Json};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background jobs, run when no interactive request is waiting.
+    Batch,
+    /// A user is waiting for the answer, e.g. `/generate`.
+    Interactive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Turn {
+    /// Position in the queue, 1 is next.
+    Waiting(usize),
+    Running,
+}
+
+pub struct Scheduler {
+    limit: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: usize,
+    next_id: u64,
+    /// Ordered by priority, then arrival.
+    waiting: Vec<Waiter>,
+}
+
+struct Waiter {
+    id: u64,
+    priority: Priority,
+    turn: watch::Sender<Turn>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    pub limit: usize,
+    pub running: usize,
+    pub waiting_interactive: usize,
+    pub waiting_batch: usize,
+}
+
+impl Scheduler {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Queues a caller. The returned ticket holds a slot once its turn is
+    /// `Running` and frees it (or leaves the queue) when dropped.
+    pub fn join(self: &Arc<Self>, priority: Priority) -> Ticket {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let (tx, rx) = watch::channel(Turn::Waiting(0));
+        let index = inner
+            .waiting
+            .iter()
+            .position(|waiter| waiter.priority < priority)
+            .unwrap_or(inner.waiting.len());
+        inner.waiting.insert(
+            index,
+            Waiter {
+                id,
+                priority,
+                turn: tx,
+            },
+        );
+        self.dispatch(&mut inner);
+
+        Ticket {
+            id,
+            scheduler: self.clone(),
+            turn: rx,
+        }
+    }
+
+    /// Waits for a free slot.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> Ticket {
+        let mut ticket = self.join(priority);
+        ticket.ready().await;
+        ticket
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let inner = self.inner.lock().unwrap();
+        let waiting = |priority| {
+            inner
+                .waiting
+                .iter()
+                .filter(|waiter| waiter.priority == priority)
+                .count()
+        };
+        QueueStatus {
+            limit: self.limit,
+            running: inner.running,
+            waiting_interactive: waiting(Priority::Interactive),
+            waiting_batch: waiting(Priority::Batch),
+        }
+    }
+
+    /// Hands free slots to the front of the queue and tells everybody else
+    /// their new position.
+    fn dispatch(&self, inner: &mut Inner) {
+        while inner.running < self.limit && !inner.waiting.is_empty() {
+            let waiter = inner.waiting.remove(0);
+            inner.running += 1;
+            let _ = waiter.turn.send(Turn::Running);
+        }
+        for (index, waiter) in inner.waiting.iter().enumerate() {
+            waiter.turn.send_if_modified(|turn| {
+                let position = Turn::Waiting(index + 1);
+                let changed = *turn != position;
+                *turn = position;
+                changed
+            });
+        }
+    }
+}
+
+pub struct Ticket {
+    id: u64,
+    scheduler: Arc<Scheduler>,
+    turn: watch::Receiver<Turn>,
+}
+
+impl Ticket {
+    /// The current turn, `changed` then waits for the next update.
+    pub fn turn(&mut self) -> Turn {
+        *self.turn.borrow_and_update()
+    }
+
+    /// Waits until the turn changes, i.e. the position in the queue moved or
+    /// the ticket got a slot.
+    pub async fn changed(&mut self) {
+        // The sender lives as long as the ticket is queued
+        let _ = self.turn.changed().await;
+    }
+
+    /// Waits until the ticket got a slot.
+    pub async fn ready(&mut self) {
+        let _ = self.turn.wait_for(|turn| *turn == Turn::Running).await;
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut inner = self.scheduler.inner.lock().unwrap();
+        match inner.waiting.iter().position(|waiter| waiter.id == self.id) {
+            Some(index) => {
+                inner.waiting.remove(index);
+            }
+            None => inner.running -= 1,
+        }
+        self.scheduler.dispatch(&mut inner);
+    }
+}
+
+pub async fn queue_status(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.scheduler.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn interactive_goes_ahead_of_batch() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = scheduler.acquire(Priority::Batch).await;
+
+        let mut batch = scheduler.join(Priority::Batch);
+        let mut interactive = scheduler.join(Priority::Interactive);
+        assert_eq!(interactive.turn(), Turn::Waiting(1));
+        assert_eq!(batch.turn(), Turn::Waiting(2));
+        let status = scheduler.status();
+        assert_eq!((status.running, status.waiting_interactive), (1, 1));
+        assert_eq!(status.waiting_batch, 1);
+
+        drop(running);
+        assert_eq!(interactive.turn(), Turn::Running);
+        assert_eq!(batch.turn(), Turn::Waiting(1));
+
+        drop(interactive);
+        batch.ready().await;
+        assert_eq!(batch.turn(), Turn::Running);
+    }
+
+    #[tokio::test]
+    async fn same_priority_in_arrival_order() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = scheduler.acquire(Priority::Interactive).await;
+        let mut first = scheduler.join(Priority::Interactive);
+        let mut second = scheduler.join(Priority::Interactive);
+        assert_eq!(first.turn(), Turn::Waiting(1));
+        assert_eq!(second.turn(), Turn::Waiting(2));
+
+        drop(running);
+        assert_eq!(first.turn(), Turn::Running);
+        assert_eq!(second.turn(), Turn::Waiting(1));
+    }
+
+    #[tokio::test]
+    async fn dropped_ticket_frees_its_slot() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = scheduler.acquire(Priority::Interactive).await;
+        let waiting = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire(Priority::Batch).await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(scheduler.status().waiting_batch, 1);
+
+        drop(running);
+        let ticket = tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+            .await
+            .expect("the waiting ticket got the slot")
+            .unwrap();
+        assert_eq!(scheduler.status().running, 1);
+        drop(ticket);
+        assert_eq!(scheduler.status().running, 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_leaves_the_queue() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = scheduler.acquire(Priority::Interactive).await;
+        let first = scheduler.join(Priority::Batch);
+        let mut second = scheduler.join(Priority::Batch);
+        assert_eq!(second.turn(), Turn::Waiting(2));
+
+        drop(first);
+        assert_eq!(second.turn(), Turn::Waiting(1));
+        assert_eq!(scheduler.status().waiting_batch, 1);
+
+        drop(running);
+        assert_eq!(second.turn(), Turn::Running);
+        assert_eq!(scheduler.status().running, 1);
+    }
+}
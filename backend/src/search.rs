@@ -0,0 +1,705 @@
+//! Semantic search over the note embeddings. The vectors of the configured
+//! embedding model are kept in memory and scored by brute force, which takes
+//! a few milliseconds for tens of thousands of notes.
+
+use crate::embeddings::{self, decode};
+use crate::models::AppState;
+use crate::notes::{content_hash, NoteWithCategory, STALE_COLUMN};
+use crate::scheduler::Priority;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, QueryBuilder, Sqlite};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+static DEFAULT_LIMIT: usize = 10;
+static MAX_LIMIT: usize = 100;
+
+/// In-memory copy of the stored embeddings, normalized so the dot product
+/// is the cosine similarity.
+#[derive(Default)]
+pub struct EmbeddingIndex {
+    cache: RwLock<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    model: String,
+    /// Number of embeddings and the newest one when the cache was loaded.
+    /// Any change to the table, also by `mindfulnotes reindex`, changes it.
+    version: Option<(i64, Option<String>)>,
+    vectors: HashMap<i64, CachedVector>,
+}
+
+struct CachedVector {
+    /// Hash of the content the vector was computed from.
+    content_hash: String,
+    vector: Vec<f32>,
+}
+
+#[derive(FromRow)]
+struct StoredEmbedding {
+    note_id: i64,
+    embedding: Vec<u8>,
+    content_hash: String,
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+impl EmbeddingIndex {
+    /// Reloads the vectors if the table changed since they were loaded.
+    async fn refresh(&self, pool: &SqlitePool, model: &str) -> Result<(), sqlx::Error> {
+        let version: (i64, Option<String>) =
+            sqlx::query_as("SELECT COUNT(*), MAX(created_at) FROM note_embeddings WHERE model = ?")
+                .bind(model)
+                .fetch_one(pool)
+                .await?;
+        {
+            let cache = self.cache.read().await;
+            if cache.model == model && cache.version.as_ref() == Some(&version) {
+                return Ok(());
+            }
+        }
+
+        let embeddings = sqlx::query_as::<_, StoredEmbedding>(
+            "SELECT note_id, embedding, content_hash FROM note_embeddings WHERE model = ?",
+        )
+        .bind(model)
+        .fetch_all(pool)
+        .await?;
+        let vectors = embeddings
+            .into_iter()
+            .map(|stored| {
+                let vector = CachedVector {
+                    content_hash: stored.content_hash,
+                    vector: normalize(decode(&stored.embedding)),
+                };
+                (stored.note_id, vector)
+            })
+            .collect::<HashMap<_, _>>();
+        info!("Loaded {} note embeddings of {}", vectors.len(), model);
+
+        *self.cache.write().await = Cache {
+            model: model.to_string(),
+            version: Some(version),
+            vectors,
+        };
+        Ok(())
+    }
+
+    /// Makes the next `refresh` reload the vectors, after a note was
+    /// embedded again.
+    pub async fn invalidate(&self) {
+        self.cache.write().await.version = None;
+    }
+
+    /// The vector of the note, unless it was computed from other content
+    /// than the one with `content_hash`, e.g. while the note is embedded
+    /// again after an update.
+    async fn vector(&self, note_id: i64, content_hash: &str) -> Option<Vec<f32>> {
+        self.cache
+            .read()
+            .await
+            .vectors
+            .get(&note_id)
+            .filter(|cached| cached.content_hash == content_hash)
+            .map(|cached| cached.vector.clone())
+    }
+
+    /// The `limit` notes closest to `query`, restricted to `candidates` if
+    /// given, best first.
+    async fn nearest(
+        &self,
+        query: &[f32],
+        candidates: Option<&HashSet<i64>>,
+        exclude: Option<i64>,
+        limit: usize,
+    ) -> Vec<(i64, f32)> {
+        let query = normalize(query.to_vec());
+        let cache = self.cache.read().await;
+        let mut scored: Vec<(i64, f32)> = cache
+            .vectors
+            .iter()
+            .filter(|(id, cached)| {
+                cached.vector.len() == query.len()
+                    && Some(**id) != exclude
+                    && candidates.is_none_or(|candidates| candidates.contains(id))
+            })
+            .map(|(id, cached)| (*id, dot(&query, &cached.vector)))
+            .collect();
+        scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(limit);
+        scored
+    }
+}
+
+/// Restricts the search to notes of a category and/or a time range.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct NoteFilter {
+    pub category: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl NoteFilter {
+    fn is_empty(&self) -> bool {
+        self.category.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    /// Appends the filter as `AND` conditions on the notes `n` joined with
+    /// their category `cd`.
+    fn push_conditions(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        if let Some(category) = &self.category {
+            query
+                .push(" AND cd.category = ")
+                .push_bind(category.to_lowercase());
+        }
+        if let Some(from) = self.from {
+            query.push(" AND n.created_at >= ").push_bind(from);
+        }
+        if let Some(to) = self.to {
+            query.push(" AND n.created_at < ").push_bind(to);
+        }
+    }
+
+    /// Ids of the notes passing the filter, `None` if there is no filter.
+    async fn note_ids(&self, pool: &SqlitePool) -> Result<Option<HashSet<i64>>, sqlx::Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT n.id FROM notes n
+             JOIN category_descriptions cd ON n.category_id = cd.id
+             WHERE 1 = 1",
+        );
+        self.push_conditions(&mut query);
+        let ids: Vec<i64> = query.build_query_scalar().fetch_all(pool).await?;
+        Ok(Some(ids.into_iter().collect()))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScoredNote {
+    #[serde(flatten)]
+    pub note: NoteWithCategory,
+    /// Cosine similarity to the query, 1 is identical.
+    pub score: f32,
+}
+
+/// The notes with the given ids, in the same order.
+pub async fn fetch_notes(
+    pool: &SqlitePool,
+    ids: &[i64],
+) -> Result<Vec<NoteWithCategory>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(format!(
+        "SELECT n.id, n.content, n.analyzed, cd.category, n.created_at, n.updated_at, n.analysis, {}
+         FROM notes n
+         JOIN category_descriptions cd ON n.category_id = cd.id
+         WHERE n.id IN (",
+        STALE_COLUMN
+    ));
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    query.push(")");
+    let mut notes: HashMap<i64, NoteWithCategory> = query
+        .build_query_as::<NoteWithCategory>()
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|note| (note.id, note))
+        .collect();
+
+    Ok(ids.iter().filter_map(|id| notes.remove(id)).collect())
+}
+
+async fn fetch_scored_notes(
+    pool: &SqlitePool,
+    scored: Vec<(i64, f32)>,
+) -> Result<Vec<ScoredNote>, sqlx::Error> {
+    let ids: Vec<i64> = scored.iter().map(|(id, _)| *id).collect();
+    let scores: HashMap<i64, f32> = scored.into_iter().collect();
+    Ok(fetch_notes(pool, &ids)
+        .await?
+        .into_iter()
+        .map(|note| ScoredNote {
+            score: scores[&note.id],
+            note,
+        })
+        .collect())
+}
+
+/// Ids and cosine similarities of the notes most similar to `query` passing
+/// `filter`, best first.
+pub async fn semantic_ranking(
+    state: &AppState,
+    query: &str,
+    filter: &NoteFilter,
+    limit: usize,
+) -> Result<Vec<(i64, f32)>, (StatusCode, String)> {
+    let vector = {
+        let _ticket = state.scheduler.acquire(Priority::Interactive).await;
+        state
+            .llm
+            .embed(&state.embedding_model, vec![query.to_string()])
+            .await
+            .map_err(|e| {
+                error!("Failed to embed search query: {}", e);
+                (
+                    e.status_code(),
+                    format!("Failed to embed search query: {}", e),
+                )
+            })?
+            .pop()
+            .ok_or_else(|| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "Embedding model returned no vector".to_string(),
+                )
+            })?
+    };
+
+    let index = &state.embedding_index;
+    index
+        .refresh(&state.pool, &state.embedding_model)
+        .await
+        .map_err(|e| {
+            error!("Failed to load note embeddings: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to load note embeddings: {}", e),
+            )
+        })?;
+    let candidates = filter.note_ids(&state.pool).await.map_err(|e| {
+        error!("Failed to filter notes: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to filter notes: {}", e),
+        )
+    })?;
+    Ok(index
+        .nearest(&vector, candidates.as_ref(), None, limit)
+        .await)
+}
+
+/// The notes most similar to `query` passing `filter`, best first.
+pub async fn semantic_search(
+    state: &AppState,
+    query: &str,
+    filter: &NoteFilter,
+    limit: usize,
+) -> Result<Vec<ScoredNote>, (StatusCode, String)> {
+    let scored = semantic_ranking(state, query, filter, limit).await?;
+    fetch_scored_notes(&state.pool, scored).await.map_err(|e| {
+        error!("Failed to fetch notes: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch notes: {}", e),
+        )
+    })
+}
+
+/// Words too common to tell notes apart.
+const STOP_WORDS: &[&str] = &[
+    "about", "after", "again", "all", "and", "any", "are", "because", "been", "before", "but",
+    "can", "did", "does", "for", "from", "had", "has", "have", "how", "into", "last", "not", "now",
+    "that", "the", "their", "them", "then", "there", "they", "this", "was", "were", "what", "when",
+    "where", "which", "while", "who", "why", "will", "with", "would", "you", "your",
+];
+
+/// Distinct lowercase words of `text` worth searching for.
+fn keywords(text: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+    {
+        if word.chars().count() >= 3
+            && !STOP_WORDS.contains(&word.as_str())
+            && !keywords.contains(&word)
+        {
+            keywords.push(word);
+        }
+    }
+    keywords
+}
+
+/// Ids of the notes passing `filter` that contain the most keywords of
+/// `query`, with the number of keywords found, best first and newer notes
+/// first among equals.
+pub async fn keyword_ranking(
+    pool: &SqlitePool,
+    query: &str,
+    filter: &NoteFilter,
+    limit: usize,
+) -> Result<Vec<(i64, f32)>, sqlx::Error> {
+    let keywords = keywords(query);
+    if keywords.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sql = QueryBuilder::<Sqlite>::new("SELECT n.id, ");
+    for (i, keyword) in keywords.iter().enumerate() {
+        if i > 0 {
+            sql.push(" + ");
+        }
+        // Keywords are alphanumeric, nothing to escape
+        sql.push("(n.content LIKE ")
+            .push_bind(format!("%{}%", keyword))
+            .push(")");
+    }
+    sql.push(
+        " AS matches
+         FROM notes n
+         JOIN category_descriptions cd ON n.category_id = cd.id
+         WHERE 1 = 1",
+    );
+    filter.push_conditions(&mut sql);
+    sql.push(" AND matches > 0 ORDER BY matches DESC, n.created_at DESC LIMIT ")
+        .push_bind(limit as i64);
+
+    let ranking: Vec<(i64, i64)> = sql.build_query_as().fetch_all(pool).await?;
+    Ok(ranking
+        .into_iter()
+        .map(|(id, matches)| (id, matches as f32))
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub filter: NoteFilter,
+}
+
+pub async fn search_notes(
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    if params.q.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Query must not be empty").into_response();
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    match semantic_search(&state, &params.q, &params.filter, limit).await {
+        Ok(notes) => (StatusCode::OK, Json(notes)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarParams {
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub filter: NoteFilter,
+}
+
+/// Past entries related to note `id`, based on its stored embedding.
+pub async fn similar_notes(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Query(params): Query<SimilarParams>,
+) -> impl IntoResponse {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    match find_similar(&state, id, &params.filter, limit).await {
+        Ok(notes) => (StatusCode::OK, Json(notes)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+async fn find_similar(
+    state: &AppState,
+    id: i64,
+    filter: &NoteFilter,
+    limit: usize,
+) -> Result<Vec<ScoredNote>, (StatusCode, String)> {
+    let content: Option<String> = sqlx::query_scalar("SELECT content FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&*state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch note: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+        })?;
+    let Some(content) = content else {
+        return Err((StatusCode::NOT_FOUND, "Note not found".to_string()));
+    };
+
+    let index = &state.embedding_index;
+    index
+        .refresh(&state.pool, &state.embedding_model)
+        .await
+        .map_err(|e| {
+            error!("Failed to load note embeddings: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to load note embeddings: {}", e),
+            )
+        })?;
+
+    // Notes whose background embedding failed or is still pending are
+    // embedded on demand
+    let hash = content_hash(&content);
+    let vector = match index.vector(id, &hash).await {
+        Some(vector) => vector,
+        None => {
+            let _ticket = state.scheduler.acquire(Priority::Interactive).await;
+            embeddings::embed_note(
+                state.llm.as_ref(),
+                &state.pool,
+                &state.embedding_model,
+                id,
+                &content,
+            )
+            .await
+            .map_err(|e| {
+                error!("Failed to embed note {}: {:#}", id, e);
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Failed to embed note: {:#}", e),
+                )
+            })?;
+            index.invalidate().await;
+            index
+                .refresh(&state.pool, &state.embedding_model)
+                .await
+                .map_err(|e| {
+                    error!("Failed to load note embeddings: {}", e);
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to load note embeddings: {}", e),
+                    )
+                })?;
+            index.vector(id, &hash).await.ok_or_else(|| {
+                error!("No embedding for note {} after embedding it", id);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "Failed to load note embeddings: no embedding for note {}",
+                        id
+                    ),
+                )
+            })?
+        }
+    };
+    let candidates = filter.note_ids(&state.pool).await.map_err(|e| {
+        error!("Failed to filter notes: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to filter notes: {}", e),
+        )
+    })?;
+    let scored = index
+        .nearest(&vector, candidates.as_ref(), Some(id), limit)
+        .await;
+    fetch_scored_notes(&state.pool, scored).await.map_err(|e| {
+        error!("Failed to fetch notes: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch notes: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::Duration;
+
+    fn index(vectors: &[(i64, &[f32])]) -> EmbeddingIndex {
+        let vectors = vectors
+            .iter()
+            .map(|(id, vector)| {
+                let cached = CachedVector {
+                    content_hash: String::new(),
+                    vector: normalize(vector.to_vec()),
+                };
+                (*id, cached)
+            })
+            .collect();
+        EmbeddingIndex {
+            cache: RwLock::new(Cache {
+                vectors,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn ids(scored: &[(i64, f32)]) -> Vec<i64> {
+        scored.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Inserts a note written `days_ago` days ago.
+    async fn insert_note(pool: &SqlitePool, content: &str, category: &str, days_ago: i64) -> i64 {
+        let id = testing::insert_note(pool, content, category).await;
+        sqlx::query("UPDATE notes SET created_at = ? WHERE id = ?")
+            .bind(Utc::now() - Duration::days(days_ago))
+            .bind(id)
+            .execute(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn nearest_ranks_by_cosine_similarity() {
+        let index = index(&[
+            (1, &[1.0, 0.0]),
+            (2, &[0.0, 3.0]),
+            (3, &[2.0, 1.0]),
+            (4, &[1.0, 0.0, 0.0]),
+        ]);
+
+        let scored = index.nearest(&[5.0, 0.0], None, None, 10).await;
+        assert_eq!(ids(&scored), [1, 3, 2]);
+        assert!((scored[0].1 - 1.0).abs() < 1e-6);
+        assert!(scored[2].1.abs() < 1e-6);
+
+        assert_eq!(
+            ids(&index.nearest(&[1.0, 0.0], None, None, 2).await),
+            [1, 3]
+        );
+        assert_eq!(
+            ids(&index.nearest(&[1.0, 0.0], None, Some(1), 10).await),
+            [3, 2]
+        );
+        let candidates = HashSet::from([2, 4]);
+        assert_eq!(
+            ids(&index
+                .nearest(&[1.0, 0.0], Some(&candidates), None, 10)
+                .await),
+            [2]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_by_category_and_time() {
+        let pool = testing::pool().await;
+        let old_work = insert_note(&pool, "Old work", "work", 10).await;
+        let new_work = insert_note(&pool, "New work", "work", 1).await;
+        let personal = insert_note(&pool, "Personal", "personal", 1).await;
+
+        assert_eq!(NoteFilter::default().note_ids(&pool).await.unwrap(), None);
+        let filter = NoteFilter {
+            category: Some("Work".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.note_ids(&pool).await.unwrap(),
+            Some(HashSet::from([old_work, new_work]))
+        );
+        let filter = NoteFilter {
+            from: Some(Utc::now() - Duration::days(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.note_ids(&pool).await.unwrap(),
+            Some(HashSet::from([new_work, personal]))
+        );
+        let filter = NoteFilter {
+            category: Some("work".to_string()),
+            to: Some(Utc::now() - Duration::days(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.note_ids(&pool).await.unwrap(),
+            Some(HashSet::from([old_work]))
+        );
+    }
+
+    #[test]
+    fn keywords_skip_short_and_common_words() {
+        assert_eq!(
+            keywords("When did I last go hiking with Anna? Hiking, with ANNA!"),
+            ["hiking", "anna"]
+        );
+        assert!(keywords("What is it?").is_empty());
+    }
+
+    #[tokio::test]
+    async fn keyword_ranking_counts_matching_keywords() {
+        let pool = testing::pool().await;
+        let both_old = insert_note(&pool, "Went hiking with Anna.", "personal", 5).await;
+        let hiking = insert_note(&pool, "Hiking alone today.", "personal", 3).await;
+        let both_new = insert_note(&pool, "Anna loves hiking.", "work", 1).await;
+        insert_note(&pool, "Nothing in common.", "personal", 0).await;
+
+        let ranking = keyword_ranking(&pool, "hiking with Anna", &NoteFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(ranking, [(both_new, 2.0), (both_old, 2.0), (hiking, 1.0)]);
+
+        let filter = NoteFilter {
+            category: Some("personal".to_string()),
+            ..Default::default()
+        };
+        let ranking = keyword_ranking(&pool, "hiking with Anna", &filter, 1)
+            .await
+            .unwrap();
+        assert_eq!(ranking, [(both_old, 2.0)]);
+
+        let ranking = keyword_ranking(&pool, "what was it", &NoteFilter::default(), 10)
+            .await
+            .unwrap();
+        assert!(ranking.is_empty());
+    }
+
+    #[tokio::test]
+    async fn similar_notes_use_the_current_content() {
+        let state = testing::state(["unused"]).await;
+        let pool = &state.pool;
+        let note = insert_note(pool, "hiking in the mountains", "personal", 2).await;
+        let hiking = insert_note(pool, "hiking in the mountains again", "personal", 1).await;
+        let cooking = insert_note(pool, "cooking pasta for dinner", "personal", 0).await;
+        for (id, content) in [
+            (note, "hiking in the mountains"),
+            (hiking, "hiking in the mountains again"),
+            (cooking, "cooking pasta for dinner"),
+        ] {
+            embeddings::embed_note(state.llm.as_ref(), pool, "mock", id, content)
+                .await
+                .unwrap();
+        }
+
+        let similar = find_similar(&state, note, &NoteFilter::default(), 1)
+            .await
+            .unwrap();
+        assert_eq!(similar[0].note.id, hiking);
+
+        // Updated, but not embedded again yet
+        sqlx::query("UPDATE notes SET content = ? WHERE id = ?")
+            .bind("cooking pasta for dinner tonight")
+            .bind(note)
+            .execute(&**pool)
+            .await
+            .unwrap();
+        let similar = find_similar(&state, note, &NoteFilter::default(), 1)
+            .await
+            .unwrap();
+        assert_eq!(similar[0].note.id, cooking);
+    }
+}
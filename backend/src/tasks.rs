@@ -0,0 +1,715 @@
+//! Action items extracted from the notes, e.g. "I should push for that
+//! promotion", so reflection turns into follow-through. Notes in the `goal`
+//! and `work` categories are scanned in the background, any note on request.
+//! Tasks can be entered and edited by hand as well.
+
+use crate::llm_json;
+use crate::models::{AppState, LlmParams};
+use crate::notes::{content_hash, Category};
+use crate::prompts::{self, PreparedPrompt, PromptName};
+use crate::runs::{record_run, NewRun, RunKind};
+use crate::scheduler::Priority;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, QueryBuilder, Sqlite};
+use tracing::{error, info};
+
+const TASK_COLUMNS: &str = "id, note_id, title, details, status, due_date, source, created_at, \
+                            updated_at, completed_at";
+
+static DEFAULT_LIMIT: i64 = 100;
+static MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TaskStatus {
+    #[default]
+    Open,
+    Done,
+    /// Not going to be done, kept so it is not extracted again.
+    Dismissed,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TaskSource {
+    /// Extracted from the note by the model.
+    Llm,
+    Manual,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Task {
+    pub id: i64,
+    /// Note the task comes from.
+    pub note_id: Option<i64>,
+    pub title: String,
+    pub details: Option<String>,
+    pub status: TaskStatus,
+    pub due_date: Option<NaiveDate>,
+    pub source: TaskSource,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// When the task was marked as done.
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct TasksResponse {
+    tasks: Vec<TaskItem>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct TaskItem {
+    title: String,
+    #[serde(default)]
+    details: Option<String>,
+    /// YYYY-MM-DD
+    #[serde(default)]
+    due_date: Option<String>,
+}
+
+struct ExtractedTask {
+    title: String,
+    details: Option<String>,
+    due_date: Option<NaiveDate>,
+}
+
+impl ExtractedTask {
+    /// `None` if the title is empty. Due dates the model got wrong are
+    /// dropped rather than failing the extraction.
+    fn from_item(item: TaskItem) -> Option<Self> {
+        let title = item.title.trim().to_string();
+        if title.is_empty() {
+            return None;
+        }
+        Some(ExtractedTask {
+            title,
+            details: item
+                .details
+                .map(|details| details.trim().to_string())
+                .filter(|details| !details.is_empty()),
+            due_date: item
+                .due_date
+                .and_then(|date| NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()),
+        })
+    }
+}
+
+/// Whether notes of `category` are scanned for tasks when they are created
+/// or updated.
+pub fn extracts_tasks(category: &Category) -> bool {
+    matches!(category, Category::Goal | Category::Work)
+}
+
+/// Asks the model for the action items of the note. Returns them and the
+/// model that extracted them.
+async fn extract_with_model(
+    state: &AppState,
+    id: i64,
+    prompt: &PreparedPrompt,
+    content: &str,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<(Vec<ExtractedTask>, String), (StatusCode, String)> {
+    let request = state
+        .task_defaults
+        .request(prompt.render(content), params)
+        .with_format(llm_json::schema_for::<TasksResponse>());
+    let model = state.llm.resolve_model(request.model.as_deref());
+    let record = |run: NewRun| record_run(&state.pool, run.prompt_version(&prompt.version));
+
+    let _ticket = state.scheduler.acquire(priority).await;
+    let max_attempts = 2;
+    for attempt in 1..=max_attempts {
+        let generation = match state.llm.generate(request.clone()).await {
+            Ok(generation) => {
+                record(NewRun::success(RunKind::Tasks, Some(id), &generation)).await;
+                generation
+            }
+            Err(e) => {
+                error!("Failed to extract tasks of note {}: {}", id, e);
+                record(NewRun::failure(RunKind::Tasks, Some(id), &model, &e)).await;
+                return Err((e.status_code(), format!("Failed to extract tasks: {}", e)));
+            }
+        };
+
+        match llm_json::extract_or_repair::<TasksResponse>(
+            state.llm.as_ref(),
+            &request,
+            &generation.text,
+        )
+        .await
+        {
+            Ok((response, repair)) => {
+                if let Some(repair) = repair {
+                    info!("Repaired tasks JSON for note {}", id);
+                    record(NewRun::success(RunKind::Tasks, Some(id), &repair)).await;
+                }
+                let tasks = response
+                    .tasks
+                    .into_iter()
+                    .filter_map(ExtractedTask::from_item)
+                    .collect();
+                return Ok((tasks, generation.model));
+            }
+            Err(e) => {
+                record(NewRun::repair_failure(RunKind::Tasks, Some(id), &model, &e)).await;
+                error!(
+                    "Task extraction attempt {} for note {} returned invalid JSON: {}",
+                    attempt, id, e
+                );
+            }
+        }
+    }
+    Err((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to generate valid tasks JSON".to_string(),
+    ))
+}
+
+/// Replaces the extracted tasks of note `id` the writer did not touch yet by
+/// `tasks`. Tasks edited, done or dismissed are kept, and extracted tasks with
+/// the same title as one of them are skipped.
+async fn store_tasks(
+    pool: &SqlitePool,
+    id: i64,
+    tasks: &[ExtractedTask],
+    model: &str,
+    prompt_version: &str,
+    hash: &str,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "DELETE FROM tasks
+         WHERE note_id = ? AND source = ? AND status = ? AND updated_at = created_at",
+    )
+    .bind(id)
+    .bind(TaskSource::Llm)
+    .bind(TaskStatus::Open)
+    .execute(&mut *tx)
+    .await?;
+    let mut titles: Vec<String> = sqlx::query_scalar("SELECT title FROM tasks WHERE note_id = ?")
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|title: String| title.to_lowercase())
+        .collect();
+    for task in tasks {
+        if titles.contains(&task.title.to_lowercase()) {
+            continue;
+        }
+        titles.push(task.title.to_lowercase());
+        sqlx::query(
+            "INSERT INTO tasks (
+                note_id, title, details, status, due_date, source, created_at, updated_at
+             )
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(&task.title)
+        .bind(&task.details)
+        .bind(TaskStatus::Open)
+        .bind(task.due_date)
+        .bind(TaskSource::Llm)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query(
+        "INSERT INTO task_extractions (note_id, content_hash, model, prompt_version, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(note_id) DO UPDATE SET
+            content_hash = excluded.content_hash,
+            model = excluded.model,
+            prompt_version = excluded.prompt_version,
+            created_at = excluded.created_at",
+    )
+    .bind(id)
+    .bind(hash)
+    .bind(model)
+    .bind(prompt_version)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+async fn fetch_note_tasks(pool: &SqlitePool, note_id: i64) -> Result<Vec<Task>, sqlx::Error> {
+    sqlx::query_as::<_, Task>(&format!(
+        "SELECT {} FROM tasks WHERE note_id = ? ORDER BY id",
+        TASK_COLUMNS
+    ))
+    .bind(note_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Extracts the tasks of note `id` and stores them. Nothing is extracted if
+/// the note did not change since the last extraction, unless `reextract` is
+/// set. Returns all tasks of the note.
+pub async fn run_extraction(
+    state: &AppState,
+    id: i64,
+    reextract: bool,
+    params: Option<LlmParams>,
+    priority: Priority,
+) -> Result<Vec<Task>, (StatusCode, String)> {
+    let content: Option<String> = sqlx::query_scalar("SELECT content FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&*state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch note: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+        })?;
+    let Some(content) = content else {
+        return Err((StatusCode::NOT_FOUND, "Note not found".to_string()));
+    };
+    let hash = content_hash(&content);
+
+    let extracted_hash: Option<String> =
+        sqlx::query_scalar("SELECT content_hash FROM task_extractions WHERE note_id = ?")
+            .bind(id)
+            .fetch_optional(&*state.pool)
+            .await
+            .map_err(|e| {
+                error!("Failed to fetch tasks: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch tasks: {}", e),
+                )
+            })?;
+    if reextract || extracted_hash.as_deref() != Some(hash.as_str()) {
+        let prompt = prompts::note_prompt(state, PromptName::Tasks, id).await?;
+        let (tasks, model) =
+            extract_with_model(state, id, &prompt, &content, params, priority).await?;
+        store_tasks(&state.pool, id, &tasks, &model, &prompt.version, &hash)
+            .await
+            .map_err(|e| {
+                error!("Failed to store tasks: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to store tasks: {}", e),
+                )
+            })?;
+        info!("Extracted {} tasks of note {}", tasks.len(), id);
+    }
+
+    fetch_note_tasks(&state.pool, id).await.map_err(|e| {
+        error!("Failed to fetch tasks: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch tasks: {}", e),
+        )
+    })
+}
+
+/// Extracts the tasks of a created or updated note in the background if its
+/// category calls for it.
+pub fn spawn_extract_note(state: &AppState, note_id: i64, category: &Category) {
+    if !extracts_tasks(category) {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err((_, message)) =
+            run_extraction(&state, note_id, false, None, Priority::Batch).await
+        {
+            error!("Failed to extract tasks of note {}: {}", note_id, message);
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtractParams {
+    /// Ask the model again even if the note did not change.
+    pub reextract: Option<bool>,
+}
+
+pub async fn extract_note_tasks(
+    Path(id): Path<i64>,
+    Query(params): Query<ExtractParams>,
+    State(state): State<AppState>,
+    llm_params: Option<Json<LlmParams>>,
+) -> impl IntoResponse {
+    let reextract = params.reextract.unwrap_or(false);
+    let llm_params = llm_params.map(|Json(params)| params);
+    match run_extraction(&state, id, reextract, llm_params, Priority::Interactive).await {
+        Ok(tasks) => (StatusCode::OK, Json(tasks)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+pub async fn get_note_tasks(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let exists = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?)")
+        .bind(id)
+        .fetch_one(&*state.pool)
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "Note not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch note: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch note: {}", e),
+            )
+                .into_response();
+        }
+    }
+    match fetch_note_tasks(&state.pool, id).await {
+        Ok(tasks) => (StatusCode::OK, Json(tasks)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch tasks of note {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch tasks: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TaskFilter {
+    pub status: Option<TaskStatus>,
+    pub note_id: Option<i64>,
+    /// Only tasks due before this day.
+    pub due_before: Option<NaiveDate>,
+    pub limit: Option<i64>,
+}
+
+/// The tasks, those due first and the ones without due date newest first.
+pub async fn list_tasks(
+    State(state): State<AppState>,
+    Query(filter): Query<TaskFilter>,
+) -> impl IntoResponse {
+    let mut query =
+        QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM tasks WHERE 1 = 1", TASK_COLUMNS));
+    if let Some(status) = filter.status {
+        query.push(" AND status = ").push_bind(status);
+    }
+    if let Some(note_id) = filter.note_id {
+        query.push(" AND note_id = ").push_bind(note_id);
+    }
+    if let Some(due_before) = filter.due_before {
+        query.push(" AND due_date < ").push_bind(due_before);
+    }
+    query
+        .push(" ORDER BY due_date IS NULL, due_date, created_at DESC LIMIT ")
+        .push_bind(filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT));
+
+    match query.build_query_as::<Task>().fetch_all(&*state.pool).await {
+        Ok(tasks) => (StatusCode::OK, Json(tasks)).into_response(),
+        Err(e) => {
+            error!("Failed to fetch tasks: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch tasks: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn fetch_task(pool: &SqlitePool, id: i64) -> Result<Option<Task>, sqlx::Error> {
+    sqlx::query_as::<_, Task>(&format!("SELECT {} FROM tasks WHERE id = ?", TASK_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn get_task(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    match fetch_task(&state.pool, id).await {
+        Ok(Some(task)) => (StatusCode::OK, Json(task)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Task not found").into_response(),
+        Err(e) => {
+            error!("Failed to fetch task {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to fetch task: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TaskRequest {
+    pub title: String,
+    pub details: Option<String>,
+    /// Open if not given.
+    pub status: Option<TaskStatus>,
+    pub due_date: Option<NaiveDate>,
+    /// Note the task belongs to.
+    pub note_id: Option<i64>,
+}
+
+/// Checks the title and that the note exists.
+async fn validate(pool: &SqlitePool, task: &TaskRequest) -> Result<(), (StatusCode, String)> {
+    if task.title.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Title must not be empty".to_string(),
+        ));
+    }
+    if let Some(note_id) = task.note_id {
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?)")
+            .bind(note_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| {
+                error!("Failed to fetch note: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to fetch note: {}", e),
+                )
+            })?;
+        if !exists {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Note with id {} not found", note_id),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn create_task(
+    State(state): State<AppState>,
+    Json(task): Json<TaskRequest>,
+) -> impl IntoResponse {
+    if let Err(response) = validate(&state.pool, &task).await {
+        return response.into_response();
+    }
+    let now = Utc::now();
+    let status = task.status.unwrap_or_default();
+    let completed_at = (status == TaskStatus::Done).then_some(now);
+
+    match sqlx::query_as::<_, Task>(&format!(
+        "INSERT INTO tasks (
+            note_id, title, details, status, due_date, source, created_at, updated_at,
+            completed_at
+         )
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         RETURNING {}",
+        TASK_COLUMNS
+    ))
+    .bind(task.note_id)
+    .bind(task.title.trim())
+    .bind(&task.details)
+    .bind(status)
+    .bind(task.due_date)
+    .bind(TaskSource::Manual)
+    .bind(now)
+    .bind(now)
+    .bind(completed_at)
+    .fetch_one(&*state.pool)
+    .await
+    {
+        Ok(task) => (StatusCode::CREATED, Json(task)).into_response(),
+        Err(e) => {
+            error!("Failed to create task: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to create task: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Replaces the task. `completed_at` is set when the task is marked as done
+/// and cleared when it is reopened.
+pub async fn update_task(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Json(task): Json<TaskRequest>,
+) -> impl IntoResponse {
+    if let Err(response) = validate(&state.pool, &task).await {
+        return response.into_response();
+    }
+    let now = Utc::now();
+    let status = task.status.unwrap_or_default();
+
+    match sqlx::query_as::<_, Task>(&format!(
+        "UPDATE tasks
+         SET note_id = ?,
+             title = ?,
+             details = ?,
+             status = ?,
+             due_date = ?,
+             updated_at = ?,
+             completed_at = CASE
+                WHEN ? = 'done' THEN COALESCE(completed_at, ?)
+                ELSE NULL
+             END
+         WHERE id = ?
+         RETURNING {}",
+        TASK_COLUMNS
+    ))
+    .bind(task.note_id)
+    .bind(task.title.trim())
+    .bind(&task.details)
+    .bind(status)
+    .bind(task.due_date)
+    .bind(now)
+    .bind(status)
+    .bind(now)
+    .bind(id)
+    .fetch_optional(&*state.pool)
+    .await
+    {
+        Ok(Some(task)) => (StatusCode::OK, Json(task)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Task not found").into_response(),
+        Err(e) => {
+            error!("Failed to update task {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to update task: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn delete_task(Path(id): Path<i64>, State(state): State<AppState>) -> impl IntoResponse {
+    match sqlx::query("DELETE FROM tasks WHERE id = ?")
+        .bind(id)
+        .execute(&*state.pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => {
+            (StatusCode::NOT_FOUND, "Task not found").into_response()
+        }
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("Failed to delete task {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to delete task: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn item(title: &str, details: Option<&str>, due_date: Option<&str>) -> TaskItem {
+        TaskItem {
+            title: title.to_string(),
+            details: details.map(str::to_string),
+            due_date: due_date.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn extracted_tasks() {
+        let task =
+            ExtractedTask::from_item(item(" Call the bank ", Some(" "), Some(" 2024-05-01 ")))
+                .unwrap();
+        assert_eq!(task.title, "Call the bank");
+        assert_eq!(task.details, None);
+        assert_eq!(task.due_date, NaiveDate::from_ymd_opt(2024, 5, 1));
+
+        let task = ExtractedTask::from_item(item("Book flights", None, Some("next week"))).unwrap();
+        assert_eq!(task.due_date, None);
+        let task =
+            ExtractedTask::from_item(item("Book flights", None, Some("2024-02-30"))).unwrap();
+        assert_eq!(task.due_date, None);
+
+        assert!(ExtractedTask::from_item(item("  ", Some("Nothing"), None)).is_none());
+    }
+
+    async fn update(state: &AppState, task: &Task, details: &str, status: TaskStatus) {
+        let request = TaskRequest {
+            title: task.title.clone(),
+            details: Some(details.to_string()),
+            status: Some(status),
+            due_date: task.due_date,
+            note_id: task.note_id,
+        };
+        let response = update_task(Path(task.id), State(state.clone()), Json(request))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn reextraction_keeps_the_tasks_touched_by_the_writer() {
+        let state = testing::state([
+            r#"{"tasks": [
+                {"title": "Call the bank", "due_date": "2024-05-01"},
+                {"title": "Book flights", "due_date": "next week"},
+                {"title": "call the bank"},
+                {"title": "Water plants"}
+            ]}"#,
+            r#"{"tasks": [
+                {"title": "CALL THE BANK"},
+                {"title": "Book flights"},
+                {"title": "Pay rent"}
+            ]}"#,
+        ])
+        .await;
+        let id = testing::insert_note(&state.pool, "Things to do.", "work").await;
+
+        let tasks = run_extraction(&state, id, false, None, Priority::Batch)
+            .await
+            .unwrap();
+        let titles: Vec<&str> = tasks.iter().map(|task| task.title.as_str()).collect();
+        assert_eq!(titles, ["Call the bank", "Book flights", "Water plants"]);
+        assert_eq!(tasks[0].due_date, NaiveDate::from_ymd_opt(2024, 5, 1));
+        assert_eq!(tasks[1].due_date, None);
+        assert!(tasks.iter().all(|task| task.source == TaskSource::Llm));
+
+        update(&state, &tasks[0], "Ask about the fee", TaskStatus::Open).await;
+        update(&state, &tasks[1], "Done", TaskStatus::Done).await;
+
+        // The note did not change, nothing is extracted
+        let unchanged = run_extraction(&state, id, false, None, Priority::Batch)
+            .await
+            .unwrap();
+        assert_eq!(unchanged.len(), 3);
+
+        let reextracted = run_extraction(&state, id, true, None, Priority::Batch)
+            .await
+            .unwrap();
+        let titles: Vec<(i64, &str, TaskStatus)> = reextracted
+            .iter()
+            .map(|task| (task.id, task.title.as_str(), task.status))
+            .collect();
+        assert_eq!(
+            titles[..2],
+            [
+                (tasks[0].id, "Call the bank", TaskStatus::Open),
+                (tasks[1].id, "Book flights", TaskStatus::Done),
+            ]
+        );
+        assert_eq!(titles.len(), 3);
+        assert_eq!((titles[2].1, titles[2].2), ("Pay rent", TaskStatus::Open));
+        assert_eq!(reextracted[0].details.as_deref(), Some("Ask about the fee"));
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers for the tests: an in-memory database with the schema and an
+//! `AppState` around the mock backend.
+
+use crate::config::{self, TaskDefaults};
+use crate::llm_models::ModelRegistry;
+use crate::mock_llm::MockBackend;
+use crate::models::AppState;
+use crate::scheduler::Scheduler;
+use chrono::Utc;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::sync::Arc;
+
+/// A fresh in-memory database. A single connection that is never closed, as
+/// every connection to `sqlite::memory:` opens a database of its own.
+pub async fn pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    crate::initialize_database(&pool).await.unwrap();
+    pool
+}
+
+/// State with the default prompts whose model plays back `replies`.
+pub async fn state<I, S>(replies: I) -> AppState
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    AppState {
+        llm: Arc::new(MockBackend::with_replies(replies)),
+        model_registry: Arc::new(ModelRegistry::new(
+            reqwest::Client::new(),
+            "http://localhost:11434",
+        )),
+        jobs: Arc::default(),
+        scheduler: Arc::new(Scheduler::new(1)),
+        pool: Arc::new(pool().await),
+        detailed_diary_analysis_prompt: config::DETAILED_DIARY_ANALYSIS_PROMPT.to_string(),
+        diary_categorization_prompt: config::DIARY_CATEGORIZATION_PROMPT.to_string(),
+        ask_diary_prompt: config::ASK_DIARY_PROMPT.to_string(),
+        chunk_analysis_prompt: config::CHUNK_ANALYSIS_PROMPT.to_string(),
+        analysis_synthesis_prompt: config::ANALYSIS_SYNTHESIS_PROMPT.to_string(),
+        digest_prompt: config::DIGEST_PROMPT.to_string(),
+        mood_prompt: config::MOOD_PROMPT.to_string(),
+        entity_prompt: config::ENTITY_PROMPT.to_string(),
+        task_prompt: config::TASK_PROMPT.to_string(),
+        analysis_defaults: TaskDefaults::default(),
+        categorization_defaults: TaskDefaults::default(),
+        ask_defaults: TaskDefaults::default(),
+        digest_defaults: TaskDefaults::default(),
+        mood_defaults: TaskDefaults::default(),
+        entity_defaults: TaskDefaults::default(),
+        task_defaults: TaskDefaults::default(),
+        embedding_model: "mock".to_string(),
+        embedding_index: Arc::default(),
+        context_window: 4096,
+    }
+}
+
+/// Inserts a note directly, without the background work `create_note`
+/// starts. Returns its id.
+pub async fn insert_note(pool: &SqlitePool, content: &str, category: &str) -> i64 {
+    sqlx::query_scalar(
+        "INSERT INTO notes (content, analyzed, category_id, created_at, updated_at, content_hash)
+         SELECT ?, 0, id, ?, ?, ?
+         FROM category_descriptions WHERE category = ?
+         RETURNING id",
+    )
+    .bind(content)
+    .bind(Utc::now())
+    .bind(Utc::now())
+    .bind(crate::notes::content_hash(content))
+    .bind(category)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
@@ -6,9 +6,15 @@ const AIGenerator: React.FC = () => {
   const [generatedText, setGeneratedText] = useState('');
 
   const handleGenerate = async () => {
+    setGeneratedText('');
     try {
-      const data = await api.post('/generate', { prompt });
-      setGeneratedText(data.generated_text);
+      await api.stream('/generate', { prompt }, (event, data) => {
+        if (event === 'message') {
+          setGeneratedText(text => text + data);
+        } else if (event === 'error') {
+          console.error('Error generating text:', data);
+        }
+      });
     } catch (error) {
       console.error('Error generating text:', error);
     }
@@ -37,4 +37,51 @@ export const api = {
         return text ? JSON.parse(text) : null;
       });
     }),
-};
\ No newline at end of file
+
+  // POSTs `data` and parses the Server-Sent Events of the response, calling
+  // `onEvent` with the event name ("message" for plain data frames) and data.
+  stream: async (
+    endpoint: string,
+    data: any,
+    onEvent: (event: string, data: string) => void,
+  ) => {
+    const response = await fetch(`${API_BASE_URL}${endpoint}`, {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify(data),
+    });
+    if (!response.ok || !response.body) {
+      throw new Error(`HTTP error! status: ${response.status}`);
+    }
+
+    const reader = response.body.getReader();
+    const decoder = new TextDecoder();
+    let buffer = '';
+    for (;;) {
+      const { done, value } = await reader.read();
+      if (done) break;
+      buffer += decoder.decode(value, { stream: true });
+
+      let boundary;
+      while ((boundary = buffer.indexOf('\n\n')) !== -1) {
+        const frame = buffer.slice(0, boundary);
+        buffer = buffer.slice(boundary + 2);
+
+        let event = 'message';
+        const lines: string[] = [];
+        for (const line of frame.split('\n')) {
+          if (line.startsWith('event:')) {
+            event = line.slice(6).trim();
+          } else if (line.startsWith('data:')) {
+            lines.push(line.slice(5).replace(/^ /, ''));
+          }
+        }
+        if (lines.length > 0) {
+          onEvent(event, lines.join('\n'));
+        }
+      }
+    }
+  },
+};